@@ -0,0 +1,220 @@
+//! Automatic batching of single calls issued within a short time window.
+//!
+//! The first call to find the queue empty becomes the batch's leader: it waits for the
+//! window to elapse (or the batch to fill, or `flush_batch`) and then sends every queued
+//! call as one batch request. Calls hold no concurrency slot while queued; only the
+//! thread dispatching a batch goes through the rate limiter and concurrency gate.
+
+use crate::response;
+use crate::{CallOptions, HttpTransport, RpcError};
+use serde_json::Value;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Queue of single calls waiting to be sent together
+pub(crate) struct AutoBatcher {
+    window: Duration,
+    max_size: usize,
+    state: Mutex<Queue>,
+    taken: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+    entries: Vec<Entry>,
+    /// Incremented every time the queue is taken for dispatch
+    generation: u64,
+}
+
+struct Entry {
+    id: Value,
+    request: Value,
+    slot: Arc<Slot>,
+}
+
+/// Where a queued call receives its response envelope
+#[derive(Default)]
+struct Slot {
+    result: Mutex<Option<Result<String, RpcError>>>,
+    ready: Condvar,
+}
+
+impl Slot {
+    fn fill(&self, result: Result<String, RpcError>) {
+        let mut lock = self.result.lock().unwrap_or_else(PoisonError::into_inner);
+        if lock.is_none() {
+            *lock = Some(result);
+            self.ready.notify_all();
+        }
+    }
+
+    fn wait(&self) -> Result<String, RpcError> {
+        let mut lock = self.result.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if let Some(result) = lock.take() {
+                return result;
+            }
+            lock = self
+                .ready
+                .wait(lock)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// Entries taken from the queue; any left unanswered when dropped (including while
+/// unwinding from a panic) fail with `RpcError::MissingResponse`
+struct Batch(Vec<Entry>);
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        for entry in &self.0 {
+            entry.slot.fill(Err(RpcError::MissingResponse));
+        }
+    }
+}
+
+impl AutoBatcher {
+    pub(crate) fn new(window: Duration, max_size: usize) -> Self {
+        AutoBatcher {
+            window,
+            max_size,
+            state: Mutex::new(Queue::default()),
+            taken: Condvar::new(),
+        }
+    }
+
+    /// Queue `request` (whose id is `id`) and block until its batch has been answered.
+    ///
+    /// Returns the text of this call's own response envelope.
+    pub(crate) fn submit(
+        &self,
+        transport: &HttpTransport,
+        id: Value,
+        request: Value,
+    ) -> Result<String, RpcError> {
+        let slot = Arc::new(Slot::default());
+        let mut state = self.lock();
+        state.entries.push(Entry {
+            id,
+            request,
+            slot: slot.clone(),
+        });
+        if self.max_size > 0 && state.entries.len() >= self.max_size {
+            let batch = self.take(&mut state);
+            drop(state);
+            self.dispatch(transport, batch)?;
+        } else if state.entries.len() == 1 {
+            self.lead(transport, state)?;
+        } else {
+            drop(state);
+        }
+        slot.wait()
+    }
+
+    /// Number of calls waiting for the next batch
+    pub(crate) fn pending_len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Methods of the calls waiting for the next batch, in queue order
+    pub(crate) fn pending_methods(&self) -> Vec<String> {
+        self.lock()
+            .entries
+            .iter()
+            .map(|e| match e.request.get("method") {
+                Some(Value::String(method)) => method.clone(),
+                _ => String::new(),
+            })
+            .collect()
+    }
+
+    /// Send everything queued right now, returning once every queued call has its
+    /// response
+    pub(crate) fn flush(&self, transport: &HttpTransport) -> Result<(), RpcError> {
+        let mut state = self.lock();
+        if state.entries.is_empty() {
+            return Ok(());
+        }
+        let batch = self.take(&mut state);
+        drop(state);
+        self.dispatch(transport, batch)
+    }
+
+    /// Wait out the window as the batch's leader, then send the batch unless it was
+    /// already taken by a full batch or a flush
+    fn lead(
+        &self,
+        transport: &HttpTransport,
+        mut state: MutexGuard<'_, Queue>,
+    ) -> Result<(), RpcError> {
+        let generation = state.generation;
+        let deadline = Instant::now() + self.window;
+        loop {
+            if state.generation != generation {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                let batch = self.take(&mut state);
+                drop(state);
+                return self.dispatch(transport, batch);
+            }
+            state = self
+                .taken
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+
+    fn take(&self, state: &mut Queue) -> Batch {
+        state.generation += 1;
+        self.taken.notify_all();
+        Batch(std::mem::take(&mut state.entries))
+    }
+
+    /// Send `batch` and hand each queued call its own response envelope.
+    ///
+    /// A failure of the batch request as a whole (the request failing, an error status
+    /// without a JSON-RPC body, a body that is not JSON, or a single error object
+    /// rejecting the whole batch) is returned to the dispatching caller and reported to
+    /// every queued call as `RpcError::BatchFailed`.
+    /// An error entry in the response fails only the call it answers.
+    fn dispatch(&self, transport: &HttpTransport, mut batch: Batch) -> Result<(), RpcError> {
+        let requests: Vec<&Value> = batch.0.iter().map(|entry| &entry.request).collect();
+        let envelopes = transport
+            .call_batch(&CallOptions::default(), &requests)
+            .and_then(|text| response::batch_replies::<Value>(transport.config(), &text));
+        let envelopes = match envelopes {
+            Ok(envelopes) => envelopes,
+            Err(e) => {
+                let e = Arc::new(e);
+                for entry in batch.0.drain(..) {
+                    entry.slot.fill(Err(RpcError::BatchFailed(e.clone())));
+                }
+                return Err(RpcError::BatchFailed(e));
+            }
+        };
+        let mut unattributed = Vec::new();
+        for envelope in envelopes {
+            let id = envelope.get("id").unwrap_or(&Value::Null);
+            if let Some(pos) = batch.0.iter().position(|entry| entry.id == *id) {
+                let entry = batch.0.swap_remove(pos);
+                entry.slot.fill(Ok(envelope.to_string()));
+            } else if id.is_null() && envelope.get("error").is_some_and(|e| !e.is_null()) {
+                unattributed.push(envelope);
+            }
+        }
+        // An error with a null id answers a request the server could not parse; it is
+        // only attributed when a single call is left unanswered
+        if let ([entry], [envelope]) = (&batch.0[..], &unattributed[..]) {
+            entry.slot.fill(Ok(envelope.to_string()));
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
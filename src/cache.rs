@@ -0,0 +1,275 @@
+//! Time-limited caching of successful single-call responses, for methods opted in with
+//! `ClientBuilder::cache_method`.
+//!
+//! Responses that came with an `ETag` outlive their TTL for a while: once expired they
+//! are revalidated with `If-None-Match`, and a `304 Not Modified` renews them.
+
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::{Duration, Instant};
+
+/// Number of TTLs an expired entry with an `ETag` is kept for revalidation
+const REVALIDATE_TTLS: u32 = 10;
+
+thread_local! {
+    /// Whether this thread's last single call through each live cache was a hit,
+    /// dropped with the thread
+    static LAST_HIT: RefCell<Vec<(Weak<()>, bool)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Cached responses keyed by method and params
+pub(crate) struct ResponseCache {
+    /// Time to live per wire method name
+    ttls: HashMap<String, Duration>,
+    entries: Mutex<HashMap<String, Entry>>,
+    /// Identity of this cache in the threads' `LAST_HIT`, whose entries for dropped
+    /// caches are pruned
+    id: Arc<()>,
+}
+
+struct Entry {
+    stored: Instant,
+    /// Time to live of the entry's method
+    ttl: Duration,
+    reply: Value,
+    etag: Option<String>,
+}
+
+impl Entry {
+    fn keep(&self, now: Instant) -> bool {
+        let age = now.duration_since(self.stored);
+        age < self.ttl || (self.etag.is_some() && age < self.ttl * REVALIDATE_TTLS)
+    }
+
+    /// The stored response with its id replaced by `id`
+    fn reply(&self, id: &Value) -> String {
+        let mut reply = self.reply.clone();
+        reply["id"] = id.clone();
+        reply.to_string()
+    }
+}
+
+/// Outcome of looking up a request in the cache
+#[derive(Debug, PartialEq)]
+pub(crate) enum Lookup {
+    /// Unexpired response, with the request's id
+    Fresh(String),
+    /// Expired response that may be revalidated with this `ETag`
+    Stale {
+        etag: String,
+    },
+    Miss,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(ttls: HashMap<String, Duration>) -> Self {
+        ResponseCache {
+            ttls,
+            entries: Mutex::default(),
+            id: Arc::new(()),
+        }
+    }
+
+    /// Cache key of `request` if its method is cached; the id is not part of the key
+    pub(crate) fn key(&self, method: &str, request: &Value) -> Option<String> {
+        self.ttls.get(method)?;
+        Some(serde_json::json!([method, request.get("params")]).to_string())
+    }
+
+    /// Response stored under `key`, with its id replaced by `id` if still fresh
+    pub(crate) fn get(&self, method: &str, key: &str, id: &Value) -> Lookup {
+        if !self.ttls.contains_key(method) {
+            return Lookup::Miss;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(entry) = entries.get(key) else {
+            return Lookup::Miss;
+        };
+        if entry.stored.elapsed() < entry.ttl {
+            return Lookup::Fresh(entry.reply(id));
+        }
+        match &entry.etag {
+            Some(etag) if entry.keep(Instant::now()) => Lookup::Stale { etag: etag.clone() },
+            _ => {
+                entries.remove(key);
+                Lookup::Miss
+            }
+        }
+    }
+
+    /// Renew the entry under `key` after the server answered `304 Not Modified`,
+    /// returning its response with the id replaced by `id`
+    pub(crate) fn revalidated(&self, key: &str, id: &Value) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = entries.get_mut(key)?;
+        entry.stored = Instant::now();
+        Some(entry.reply(id))
+    }
+
+    /// Store `text` and its `ETag` under `key` if it is a response without an error
+    pub(crate) fn insert(&self, method: &str, key: String, text: &str, etag: Option<String>) {
+        let Some(&ttl) = self.ttls.get(method) else {
+            return;
+        };
+        let Ok(reply @ Value::Object(_)) = serde_json::from_str::<Value>(text) else {
+            return;
+        };
+        if reply.get("error").is_some_and(|e| !e.is_null()) {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        entries.retain(|_, entry| entry.keep(now));
+        entries.insert(
+            key,
+            Entry {
+                stored: now,
+                ttl,
+                reply,
+                etag,
+            },
+        );
+    }
+
+    pub(crate) fn record_hit(&self, hit: bool) {
+        LAST_HIT.with(|last_hit| {
+            let mut last_hit = last_hit.borrow_mut();
+            last_hit.retain(|(id, _)| id.strong_count() > 0);
+            match last_hit
+                .iter_mut()
+                .find(|(id, _)| id.as_ptr() == Arc::as_ptr(&self.id))
+            {
+                Some((_, last)) => *last = hit,
+                None => last_hit.push((Arc::downgrade(&self.id), hit)),
+            }
+        });
+    }
+
+    pub(crate) fn last_was_hit(&self) -> bool {
+        LAST_HIT.with(|last_hit| {
+            last_hit
+                .borrow()
+                .iter()
+                .find(|(id, _)| id.as_ptr() == Arc::as_ptr(&self.id))
+                .is_some_and(|(_, hit)| *hit)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_cache_entries() {
+        let cache = ResponseCache::new(HashMap::from([(
+            "getblockhash".to_string(),
+            Duration::from_millis(100),
+        )]));
+        let request = json!({"method": "getblockhash", "params": [1], "id": 0});
+        assert_eq!(cache.key("getblockcount", &request), None);
+        let key = cache.key("getblockhash", &request).unwrap();
+        assert_eq!(cache.get("getblockhash", &key, &json!(1)), Lookup::Miss);
+
+        cache.insert(
+            "getblockhash",
+            key.clone(),
+            r#"{"result":"ab","id":0}"#,
+            None,
+        );
+        let Lookup::Fresh(hit) = cache.get("getblockhash", &key, &json!(7)) else {
+            panic!("expected a fresh entry");
+        };
+        assert_eq!(
+            serde_json::from_str::<Value>(&hit).unwrap(),
+            json!({"result": "ab", "id": 7})
+        );
+
+        let other = json!({"method": "getblockhash", "params": [2], "id": 0});
+        let other_key = cache.key("getblockhash", &other).unwrap();
+        assert_eq!(
+            cache.get("getblockhash", &other_key, &json!(1)),
+            Lookup::Miss
+        );
+
+        // Errors are not cached, and entries expire
+        cache.insert(
+            "getblockhash",
+            other_key.clone(),
+            r#"{"error":{"code":-8},"id":0}"#,
+            None,
+        );
+        assert_eq!(
+            cache.get("getblockhash", &other_key, &json!(1)),
+            Lookup::Miss
+        );
+        std::thread::sleep(Duration::from_millis(110));
+        assert_eq!(cache.get("getblockhash", &key, &json!(1)), Lookup::Miss);
+    }
+
+    #[test]
+    fn test_etag_revalidation() {
+        let cache = ResponseCache::new(HashMap::from([(
+            "getblock".to_string(),
+            Duration::from_millis(50),
+        )]));
+        let request = json!({"method": "getblock", "params": ["00"], "id": 0});
+        let key = cache.key("getblock", &request).unwrap();
+        cache.insert(
+            "getblock",
+            key.clone(),
+            r#"{"result":{"height":1},"id":0}"#,
+            Some("\"v1\"".to_string()),
+        );
+        assert!(matches!(
+            cache.get("getblock", &key, &json!(1)),
+            Lookup::Fresh(_)
+        ));
+
+        // Expired entries with an ETag wait for revalidation instead of being dropped
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(
+            cache.get("getblock", &key, &json!(1)),
+            Lookup::Stale {
+                etag: "\"v1\"".to_string()
+            }
+        );
+        let renewed = cache.revalidated(&key, &json!(3)).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&renewed).unwrap(),
+            json!({"result": {"height": 1}, "id": 3})
+        );
+        assert!(matches!(
+            cache.get("getblock", &key, &json!(1)),
+            Lookup::Fresh(_)
+        ));
+    }
+
+    #[test]
+    fn test_eviction_per_method_ttl() {
+        let cache = ResponseCache::new(HashMap::from([
+            ("getblock".to_string(), Duration::from_secs(3600)),
+            ("getmempoolinfo".to_string(), Duration::from_millis(20)),
+        ]));
+        let insert = |method: &str, params: Value| {
+            let key = cache.key(method, &json!({"params": params})).unwrap();
+            cache.insert(method, key.clone(), r#"{"result":1,"id":0}"#, None);
+            key
+        };
+        let block = insert("getblock", json!(["00"]));
+        insert("getmempoolinfo", json!([]));
+
+        // Inserting evicts each entry by its own method's TTL: the short-lived entry
+        // goes, the hour-long one stays
+        std::thread::sleep(Duration::from_millis(30));
+        insert("getmempoolinfo", json!([true]));
+        assert_eq!(cache.entries.lock().unwrap().len(), 2);
+        assert!(matches!(
+            cache.get("getblock", &block, &json!(1)),
+            Lookup::Fresh(_)
+        ));
+    }
+}
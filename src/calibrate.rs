@@ -0,0 +1,94 @@
+//! Empirical throughput check against a server, from `client.calibrate(..)`.
+
+use crate::{read_body, CallOptions, HttpTransport};
+use reqwest::StatusCode;
+use std::time::{Duration, Instant};
+
+/// Upper bound on the number of requests a single calibration run sends
+pub const MAX_CALIBRATION_SAMPLES: usize = 1000;
+
+/// Consecutive failures after which a calibration run stops early
+pub const CALIBRATION_ERROR_LIMIT: usize = 5;
+
+/// Outcome of a calibration run
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationReport {
+    /// Requests actually sent
+    pub requests: usize,
+    /// Requests that failed: transport errors, non-success statuses, unreadable bodies
+    /// and JSON-RPC errors
+    pub errors: usize,
+    /// Responses with status `429 Too Many Requests`
+    pub rate_limited: usize,
+    /// Wall-clock time of the run, including throttling
+    pub elapsed: Duration,
+    /// Whether the run stopped after [`CALIBRATION_ERROR_LIMIT`] consecutive failures
+    pub stopped_early: bool,
+}
+
+impl CalibrationReport {
+    /// Requests per second achieved over the run
+    pub fn achieved_rps(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.requests as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// Fraction of requests that failed
+    pub fn error_rate(&self) -> f64 {
+        match self.requests {
+            0 => 0.0,
+            n => self.errors as f64 / n as f64,
+        }
+    }
+}
+
+impl HttpTransport {
+    /// Send up to `samples` parameterless calls to `method` through the throttle and
+    /// report the achieved throughput.
+    ///
+    /// `samples` is capped at [`MAX_CALIBRATION_SAMPLES`].
+    pub fn calibrate(&self, method: &str, samples: usize) -> CalibrationReport {
+        let mut report = CalibrationReport {
+            requests: 0,
+            errors: 0,
+            rate_limited: 0,
+            elapsed: Duration::ZERO,
+            stopped_early: false,
+        };
+        let started = Instant::now();
+        let mut consecutive_errors = 0;
+        for _ in 0..samples.min(MAX_CALIBRATION_SAMPLES) {
+            report.requests += 1;
+            let body = serde_json::json!({
+                "method": self.config().wire_method(method),
+                "params": [],
+                "id": self.reserve_ids(1).id(0),
+            });
+            let ok = match self.send_in_slot(&CallOptions::default(), &body) {
+                Ok((res, _)) if res.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    report.rate_limited += 1;
+                    false
+                }
+                Ok((res, _slot)) if res.status().is_success() => read_body(res)
+                    .ok()
+                    .and_then(|text| serde_json::from_str::<serde_json::Value>(&text).ok())
+                    .is_some_and(|reply| reply.get("error").map_or(true, |e| e.is_null())),
+                _ => false,
+            };
+            if ok {
+                consecutive_errors = 0;
+            } else {
+                report.errors += 1;
+                consecutive_errors += 1;
+                if consecutive_errors >= CALIBRATION_ERROR_LIMIT {
+                    report.stopped_early = true;
+                    break;
+                }
+            }
+        }
+        report.elapsed = started.elapsed();
+        report
+    }
+}
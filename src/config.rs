@@ -0,0 +1,994 @@
+//! Client configuration and the builder used by generated clients.
+
+use crate::{
+    Auth, AuthSigner, BatchCost, DeserializeErrorHook, Endpoint, RequestEvent, RequestHook,
+    RequestParts, RpcError, Throttle, ThrottleScope, WhenThrottled,
+};
+use reqwest::header::{HeaderName, HeaderValue};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::fmt;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Default `Content-Type` sent with every request
+pub const DEFAULT_CONTENT_TYPE: &str = "application/json";
+
+/// Default `Accept` header sent with every request
+pub const DEFAULT_ACCEPT: &str = "application/json";
+
+/// Default method called by `health_check()`
+pub const DEFAULT_HEALTH_CHECK_METHOD: &str = "getblockcount";
+
+/// Default minimum body size, in bytes, for request compression
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 32 * 1024;
+
+/// Default maximum length, in bytes, of response bodies kept in errors
+pub const DEFAULT_MAX_ERROR_BODY: usize = 8 * 1024;
+
+/// Default delay before the first retry, doubled for each further retry
+pub const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Transform applied to every method name before it is sent
+#[derive(Clone)]
+pub struct MethodMapper(Arc<dyn Fn(&str) -> String + Send + Sync>);
+
+impl MethodMapper {
+    pub fn new(f: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        MethodMapper(Arc::new(f))
+    }
+
+    pub fn map(&self, method: &str) -> String {
+        (self.0)(method)
+    }
+}
+
+impl fmt::Debug for MethodMapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MethodMapper(..)")
+    }
+}
+
+/// Rewrite applied to every `result` value before it is deserialized
+#[derive(Clone)]
+pub struct ResponseTransform(Arc<dyn Fn(&mut Value) + Send + Sync>);
+
+impl ResponseTransform {
+    pub fn new(f: impl Fn(&mut Value) + Send + Sync + 'static) -> Self {
+        ResponseTransform(Arc::new(f))
+    }
+
+    pub fn apply(&self, value: &mut Value) {
+        (self.0)(value)
+    }
+}
+
+impl fmt::Debug for ResponseTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ResponseTransform(..)")
+    }
+}
+
+/// Decides whether a failed attempt is retried, given the error and the number of
+/// attempts made so far
+#[derive(Clone)]
+pub struct RetryPredicate(Arc<RetryFn>);
+
+type RetryFn = dyn Fn(&RpcError, u32) -> bool + Send + Sync;
+
+impl RetryPredicate {
+    pub fn new(f: impl Fn(&RpcError, u32) -> bool + Send + Sync + 'static) -> Self {
+        RetryPredicate(Arc::new(f))
+    }
+
+    pub fn call(&self, error: &RpcError, attempt: u32) -> bool {
+        (self.0)(error, attempt)
+    }
+}
+
+impl fmt::Debug for RetryPredicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RetryPredicate(..)")
+    }
+}
+
+/// Fetches a fresh bearer token after a request is rejected with `401 Unauthorized`
+#[derive(Clone)]
+pub struct TokenRefresh(Arc<TokenRefreshFn>);
+
+type TokenRefreshFn = dyn Fn() -> Result<String, RpcError> + Send + Sync;
+
+impl TokenRefresh {
+    pub fn new(f: impl Fn() -> Result<String, RpcError> + Send + Sync + 'static) -> Self {
+        TokenRefresh(Arc::new(f))
+    }
+
+    pub fn call(&self) -> Result<String, RpcError> {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for TokenRefresh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TokenRefresh(..)")
+    }
+}
+
+/// What to do with a response carrying both a `result` and an `error`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AmbiguousResponsePolicy {
+    /// Treat the response as failed with the server's error (safest)
+    #[default]
+    PreferError,
+    /// Ignore the error and return the result
+    PreferResult,
+    /// Fail with `RpcError::AmbiguousResponse`
+    Reject,
+}
+
+/// HTTP protocol version used to talk to endpoints
+///
+/// HTTP/2 multiplexes concurrent requests over one connection, which helps with many
+/// small requests in flight at once (high `max_concurrency`, or many threads). With
+/// few concurrent requests, or large responses, HTTP/1.1's connection pool performs
+/// just as well and works with every server and proxy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// HTTP/1.1, or HTTP/2 when a TLS server offers it during ALPN negotiation
+    #[default]
+    Negotiate,
+    /// Always HTTP/1.1
+    Http1Only,
+    /// HTTP/2 from the first byte, for servers known to support it (including over
+    /// plain `http://`); requires the `http2` feature
+    #[cfg(feature = "http2")]
+    Http2PriorKnowledge,
+}
+
+/// How call arguments are sent in a request's `params`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParamStyle {
+    /// An array in declaration order; trailing `null`s are dropped
+    #[default]
+    Positional,
+    /// An object keyed by the argument names used in `jsonrpc_client!`
+    Named,
+}
+
+/// Throttling, batching and timeout settings, reusable across clients
+///
+/// ```
+/// use throttled_json_rpc::ThrottleConfig;
+///
+/// let throttle = ThrottleConfig {
+///     max_concurrency: 5,
+///     rps: 10,
+///     ..ThrottleConfig::default()
+/// };
+/// # let _ = throttle;
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThrottleConfig {
+    /// Maximum simultaneous in-flight requests (`0` = unlimited)
+    pub max_concurrency: usize,
+    /// Maximum requests per second (`0` = unlimited)
+    ///
+    /// Values of a billion or more are also treated as unlimited.
+    pub rps: usize,
+    /// Maximum number of requests per batch (`0` = unlimited)
+    pub max_batch_size: usize,
+    /// Queue single calls for up to this long and send them as one batch
+    pub batch_window: Option<Duration>,
+    /// Timeout of each HTTP request (`None` = reqwest's default of 30 seconds)
+    pub timeout: Option<Duration>,
+    /// Whether the rate limit is shared by all threads or applies to each thread
+    pub scope: ThrottleScope,
+    /// Whether a request sleeps or fails when the rate limit does not allow it yet
+    pub when_throttled: WhenThrottled,
+    /// How many requests a batch is charged as against the rate limit
+    pub batch_cost: BatchCost,
+    /// Final part of each rate-limit wait spent spinning instead of sleeping
+    /// (`Duration::ZERO` = sleep through the whole wait)
+    pub spin_threshold: Duration,
+}
+
+/// Complete configuration of a generated client
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Endpoints in failover order; the first one is the primary
+    pub endpoints: Vec<Endpoint>,
+    /// Rate, concurrency and batching limits
+    pub throttle: ThrottleConfig,
+    /// Throttle shared with other clients, used instead of one built from `throttle`
+    pub shared_throttle: Option<Arc<Throttle>>,
+    /// HTTP client (and connection pool) shared with other clients, used instead of one
+    /// built from this configuration's connection settings
+    pub http_client: Option<reqwest::blocking::Client>,
+    /// HTTP protocol version
+    pub http_version: HttpVersion,
+    /// Set `TCP_NODELAY` on connections, disabling Nagle's algorithm
+    pub tcp_nodelay: bool,
+    /// `SO_KEEPALIVE` idle time of connections (`None` = leave keepalive off)
+    pub tcp_keepalive: Option<Duration>,
+    /// Host names resolved to fixed addresses instead of through DNS
+    pub resolve: Vec<(String, SocketAddr)>,
+    /// Array or object `params`
+    pub param_style: ParamStyle,
+    /// Leave `null` arguments (e.g. `None`) out of named params instead of sending `null`
+    pub omit_null_params: bool,
+    /// `Content-Type` header sent with every request
+    pub content_type: String,
+    /// `Accept` header sent with every request
+    pub accept: String,
+    /// Accept `0x`-prefixed hex strings wherever an integer result is expected
+    pub hex_numbers: bool,
+    /// Fail single calls whose response `id` differs from the request's
+    pub verify_id: bool,
+    /// Resend the calls of a batch one by one when the server answers it with a single
+    /// object
+    pub batch_fallback: bool,
+    /// Cheap method called by `health_check()`
+    pub health_check_method: String,
+    /// Transform applied to every method name before it is sent
+    pub method_mapper: Option<MethodMapper>,
+    /// Keys to descend into from `result` to reach the actual result
+    pub result_path: Vec<String>,
+    /// Rewrite of every `result` value before it is deserialized
+    pub on_response_json: Option<ResponseTransform>,
+    /// Handling of responses carrying both a `result` and an `error`
+    pub ambiguous_response: AmbiguousResponsePolicy,
+    /// First request id used by this client
+    pub id_offset: u64,
+    /// Request id sequence shared with other clients, used instead of one starting at
+    /// `id_offset`
+    pub shared_id_counter: Option<Arc<AtomicU64>>,
+    /// Prefix turning request ids into strings such as `crawler-17`
+    pub id_prefix: Option<String>,
+    /// Gzip request bodies of at least `compression_threshold` bytes
+    pub compress_requests: bool,
+    /// Minimum body size, in bytes, compressed when `compress_requests` is set
+    pub compression_threshold: usize,
+    /// Longest response body, in bytes, kept in `JsonError` and `CannotDeserialize`
+    pub max_error_body: usize,
+    /// Callback adding request-specific auth headers just before each request is sent
+    pub auth_signer: Option<AuthSigner>,
+    /// Methods (wire names) whose successful responses are cached, with their time to live
+    pub cached_methods: Vec<(String, Duration)>,
+    /// Methods (wire names) whose responses may be the bare result, without an envelope
+    pub bare_result_methods: Vec<String>,
+    /// Methods (wire names) sent as HTTP GET requests
+    pub http_get_methods: Vec<String>,
+    /// Methods (wire names) whose responses are requested gzip-compressed
+    pub gzip_response_methods: Vec<String>,
+    /// Methods (wire names) with their own limit of simultaneous calls
+    pub method_concurrency: Vec<(String, usize)>,
+    /// Error codes answered as success, for one method (wire name) or all if `None`
+    pub accepted_error_codes: Vec<(Option<String>, i64)>,
+    /// Retries of a failed request after the first attempt (`0` = never retry)
+    pub max_retries: u32,
+    /// Errors retried in addition to those for which `RpcError::is_transient` holds
+    pub retry_if: Option<RetryPredicate>,
+    /// Delay before the first retry, doubled for each further retry
+    pub retry_backoff: Duration,
+    /// Callback invoked after every request
+    pub on_request: Option<RequestHook>,
+    /// Callback fetching a new bearer token when a request is answered with a 401
+    pub on_unauthorized: Option<TokenRefresh>,
+    /// Callback invoked when a result cannot be deserialized
+    pub on_deserialize_error: Option<DeserializeErrorHook>,
+}
+
+impl ClientConfig {
+    pub fn new(uri: impl Into<String>) -> Self {
+        ClientConfig {
+            endpoints: vec![Endpoint::new(uri)],
+            throttle: ThrottleConfig::default(),
+            shared_throttle: None,
+            http_client: None,
+            http_version: HttpVersion::Negotiate,
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+            resolve: Vec::new(),
+            param_style: ParamStyle::Positional,
+            omit_null_params: true,
+            content_type: DEFAULT_CONTENT_TYPE.to_string(),
+            accept: DEFAULT_ACCEPT.to_string(),
+            hex_numbers: false,
+            verify_id: false,
+            batch_fallback: false,
+            health_check_method: DEFAULT_HEALTH_CHECK_METHOD.to_string(),
+            method_mapper: None,
+            result_path: Vec::new(),
+            on_response_json: None,
+            ambiguous_response: AmbiguousResponsePolicy::PreferError,
+            id_offset: 0,
+            shared_id_counter: None,
+            id_prefix: None,
+            compress_requests: false,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            max_error_body: DEFAULT_MAX_ERROR_BODY,
+            auth_signer: None,
+            cached_methods: Vec::new(),
+            bare_result_methods: Vec::new(),
+            http_get_methods: Vec::new(),
+            gzip_response_methods: Vec::new(),
+            method_concurrency: Vec::new(),
+            accepted_error_codes: Vec::new(),
+            max_retries: 0,
+            retry_if: None,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            on_request: None,
+            on_unauthorized: None,
+            on_deserialize_error: None,
+        }
+    }
+
+    /// Name sent on the wire for `method`
+    pub fn wire_method<'a>(&self, method: &'a str) -> Cow<'a, str> {
+        match &self.method_mapper {
+            Some(mapper) => Cow::Owned(mapper.map(method)),
+            None => Cow::Borrowed(method),
+        }
+    }
+
+    /// Whether an `error` answering a call of `method` (its wire name) counts as success
+    ///
+    /// Only a numeric `code` sent by the server matches: string errors and error
+    /// objects without a code always stay errors.
+    pub fn accepts_error(&self, method: &str, error: &Value) -> bool {
+        let Some(code) = error.get("code").and_then(Value::as_i64) else {
+            return false;
+        };
+        self.accepted_error_codes
+            .iter()
+            .any(|(m, c)| *c == code && m.as_deref().map_or(true, |m| m == method))
+    }
+
+    /// `body` as kept in an error: cut to at most `max_error_body` bytes, noting the
+    /// original length
+    pub fn error_body(&self, body: &str) -> String {
+        if body.len() <= self.max_error_body {
+            return body.to_string();
+        }
+        let mut end = self.max_error_body;
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}... ({} bytes total)", &body[..end], body.len())
+    }
+
+    /// Named `params` object for serialized positional arguments `params`
+    pub fn named_params(&self, names: &[&str], params: Value) -> Value {
+        let Value::Array(values) = params else {
+            return params;
+        };
+        Value::Object(
+            names
+                .iter()
+                .zip(values)
+                .filter(|(_, value)| !(self.omit_null_params && value.is_null()))
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// The primary endpoint
+    pub fn primary(&self) -> &Endpoint {
+        &self.endpoints[0]
+    }
+
+    pub(crate) fn primary_mut(&mut self) -> &mut Endpoint {
+        &mut self.endpoints[0]
+    }
+}
+
+/// Implemented by clients generated with [`jsonrpc_client!`](crate::jsonrpc_client)
+pub trait FromConfig {
+    fn from_config(config: ClientConfig) -> Arc<Self>;
+}
+
+/// Builder for generated clients, obtained from `MyClient::builder(uri)`
+///
+/// Options for a single method (`cache_method`, `http_get`, `method_concurrency` and
+/// so on) take its wire name: the name sent in requests, after any `as "name"` in
+/// `jsonrpc_client!` and any [`method_mapper`](Self::method_mapper) or
+/// [`method_prefix`](Self::method_prefix).
+pub struct ClientBuilder<C> {
+    config: ClientConfig,
+    client: PhantomData<fn() -> C>,
+}
+
+impl<C: FromConfig> ClientBuilder<C> {
+    pub fn new(uri: impl Into<String>) -> Self {
+        Self::with_config(ClientConfig::new(uri))
+    }
+
+    pub(crate) fn with_config(config: ClientConfig) -> Self {
+        ClientBuilder {
+            config,
+            client: PhantomData,
+        }
+    }
+
+    /// Use HTTP basic auth for the primary endpoint
+    pub fn auth(mut self, user: impl Into<String>, pass: Option<String>) -> Self {
+        self.config.primary_mut().auth = Auth::Basic {
+            user: user.into(),
+            pass,
+        };
+        self
+    }
+
+    /// Use a bearer token for the primary endpoint
+    pub fn bearer_auth(mut self, token: impl Into<String>) -> Self {
+        self.config.primary_mut().auth = Auth::Bearer(token.into());
+        self
+    }
+
+    /// Send an extra header with every request to the primary endpoint
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.config
+            .primary_mut()
+            .headers
+            .push((name.into(), value.into()));
+        self
+    }
+
+    /// Send an extra header with every request to the primary endpoint, given as
+    /// `reqwest` header types, e.g. for tokens that are not valid UTF-8
+    ///
+    /// See `Endpoint::raw_header`.
+    pub fn raw_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.config.primary_mut().raw_headers.push((name, value));
+        self
+    }
+
+    /// Append `name=value` to the query string of every request to the primary
+    /// endpoint, e.g. an API key for providers expecting `?apikey=..`
+    pub fn query_param(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.config
+            .primary_mut()
+            .query
+            .push((name.into(), value.into()));
+        self
+    }
+
+    /// Call `f` just before every HTTP request is sent, to attach headers computed from
+    /// the body, e.g. an HMAC signature and timestamp for signed-RPC providers
+    ///
+    /// Runs after each endpoint's own auth and headers are applied, and again for every
+    /// retry or failover attempt, so signatures and timestamps are always fresh.
+    pub fn auth_signer(
+        mut self,
+        f: impl Fn(&mut RequestParts<'_>) + Send + Sync + 'static,
+    ) -> Self {
+        self.config.auth_signer = Some(AuthSigner::new(f));
+        self
+    }
+
+    /// Override the `Host` header sent to the primary endpoint
+    pub fn host_header(self, host: impl Into<String>) -> Self {
+        self.header("Host", host)
+    }
+
+    /// Connect to the primary endpoint's IP address but present `name` for TLS
+    /// (SNI and certificate verification) and as `Host`
+    ///
+    /// See [`Endpoint::tls_server_name`].
+    pub fn tls_server_name(mut self, name: impl Into<String>) -> Self {
+        self.config.primary_mut().tls_server_name = Some(name.into());
+        self
+    }
+
+    /// Resolve `host` to `addr` instead of looking it up in DNS, for every endpoint
+    ///
+    /// A port in the endpoint URL takes precedence over the port of `addr`; a port of
+    /// `0` means the scheme's default, as with reqwest's own `resolve`.
+    pub fn resolve(mut self, host: impl Into<String>, addr: SocketAddr) -> Self {
+        self.config.resolve.push((host.into(), addr));
+        self
+    }
+
+    /// Add a fallback endpoint, tried in order when the current one is unreachable
+    ///
+    /// Each endpoint carries its own credentials and headers, which are applied to
+    /// every request sent to it.
+    pub fn fallback(mut self, endpoint: Endpoint) -> Self {
+        self.config.endpoints.push(endpoint);
+        self
+    }
+
+    /// Replace all throttling, batching and timeout settings at once
+    pub fn throttle(mut self, throttle: ThrottleConfig) -> Self {
+        self.config.throttle = throttle;
+        self
+    }
+
+    /// HTTP protocol version (default: HTTP/1.1, upgraded to HTTP/2 only if a TLS server
+    /// offers it)
+    ///
+    /// See [`HttpVersion`] for when HTTP/2 helps.
+    pub fn http_version(mut self, version: HttpVersion) -> Self {
+        self.config.http_version = version;
+        self
+    }
+
+    /// Set `TCP_NODELAY` on connections (default: `true`)
+    ///
+    /// Small requests are sent as soon as they are written instead of being held back
+    /// by Nagle's algorithm to coalesce with later writes, which matters for
+    /// latency-sensitive calls. Disable only to trade latency for fewer packets.
+    pub fn tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.config.tcp_nodelay = enabled;
+        self
+    }
+
+    /// Send TCP keepalive probes on idle connections after `idle` (default: `None`,
+    /// keepalive off)
+    ///
+    /// Keeps pooled connections alive through NATs and firewalls that drop idle flows,
+    /// and detects dead peers before a request is sent on the connection.
+    pub fn tcp_keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.config.tcp_keepalive = idle;
+        self
+    }
+
+    /// Speak HTTP/2 from the start, without negotiation, or go back to the default
+    #[cfg(feature = "http2")]
+    pub fn http2_prior_knowledge(self, enabled: bool) -> Self {
+        self.http_version(match enabled {
+            true => HttpVersion::Http2PriorKnowledge,
+            false => HttpVersion::Negotiate,
+        })
+    }
+
+    /// Timeout of each HTTP request (default: 30 seconds)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.throttle.timeout = Some(timeout);
+        self
+    }
+
+    /// Maximum simultaneous in-flight requests (`0` = unlimited)
+    pub fn max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.config.throttle.max_concurrency = max_concurrency;
+        self
+    }
+
+    /// Allow at most `max` calls of `method` in flight at once, e.g. for a memory-heavy
+    /// method, on top of [`max_concurrency`](Self::max_concurrency)
+    ///
+    /// A call first takes a slot of the method's own limit and only then waits for the
+    /// rate limiter and a slot of the client-wide limit, releasing both when it
+    /// completes. Calls holding a client-wide slot therefore never wait for a method
+    /// slot, so the two limits cannot deadlock. Auto-batched calls hold their method
+    /// slot until their batch is answered; explicit batches are not limited per method.
+    pub fn method_concurrency(mut self, method: impl Into<String>, max: usize) -> Self {
+        self.config.method_concurrency.push((method.into(), max));
+        self
+    }
+
+    /// Maximum requests per second (`0` = unlimited)
+    ///
+    /// Requests are spaced `1/rps` apart with nanosecond precision, so any value of a
+    /// billion or more is treated as unlimited as well.
+    pub fn rps(mut self, rps: usize) -> Self {
+        self.config.throttle.rps = rps;
+        self
+    }
+
+    /// Count this client's requests against `throttle`, shared with other clients
+    ///
+    /// The rate and concurrency limits of the shared throttle replace this client's own
+    /// `rps`, `max_concurrency` and scope; batching, timeouts and `when_throttled` still
+    /// come from this builder. Get a client's throttle with `client.throttle()`.
+    pub fn shared_throttle(mut self, throttle: Arc<Throttle>) -> Self {
+        self.config.shared_throttle = Some(throttle);
+        self
+    }
+
+    /// Send requests with `client`, sharing its connection pool with other clients
+    ///
+    /// The client's own timeout, TLS and connection settings apply instead of this
+    /// builder's `timeout`, `tcp_nodelay`, `tcp_keepalive`, `http_version` and `resolve`
+    /// settings and endpoint TLS server names. Get a generated client's HTTP client with
+    /// `client.http_client()`, or use `client.with_independent_throttle(..)`.
+    pub fn http_client(mut self, client: reqwest::blocking::Client) -> Self {
+        self.config.http_client = Some(client);
+        self
+    }
+
+    /// Share the rate limit across threads (default) or give each thread its own
+    ///
+    /// See [`ThrottleScope::PerThread`] for the implications.
+    pub fn throttle_scope(mut self, scope: ThrottleScope) -> Self {
+        self.config.throttle.scope = scope;
+        self
+    }
+
+    /// Spin through the last `threshold` of each rate-limit wait instead of sleeping,
+    /// for accurate rates when the interval is close to the platform's sleep
+    /// granularity (e.g. about 15.6ms on Windows)
+    ///
+    /// See [`SpinClock`](crate::SpinClock) for the platform caveats. Spinning keeps a
+    /// core busy for up to `threshold` per request, so keep it small, e.g. 1 to 2ms on
+    /// Linux and 16ms on Windows. Does not apply to a `shared_throttle`, whose clock is
+    /// set when it is created.
+    pub fn spin_threshold(mut self, threshold: Duration) -> Self {
+        self.config.throttle.spin_threshold = threshold;
+        self
+    }
+
+    /// Sleep until the rate limit allows a request (default) or fail fast with
+    /// `RpcError::RateLimited`
+    ///
+    /// Individual calls can override this with `CallOptions::when_throttled`.
+    pub fn when_throttled(mut self, mode: WhenThrottled) -> Self {
+        self.config.throttle.when_throttled = mode;
+        self
+    }
+
+    /// Maximum number of requests per batch (`0` = unlimited)
+    ///
+    /// Only limits batches; it never delays a single call. Without a
+    /// [`batch_window`](Self::batch_window), a method called directly on the client is
+    /// sent on its own right away. With one, it is sent once the window elapses, whether
+    /// or not the batch has filled up.
+    pub fn max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.config.throttle.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// Batch single calls automatically: a call waits up to `window` for other calls,
+    /// then all of them are sent as one batch request
+    ///
+    /// A batch is sent early once it reaches `max_batch_size` or when
+    /// `flush_batch()` is called. Queued calls hold no concurrency slot and do not wait
+    /// on the rate limiter; the batch passes the rate limiter and concurrency gate once,
+    /// when it is sent, so batching cannot deadlock against `max_concurrency`. It is
+    /// charged as set by [`batch_cost`](Self::batch_cost).
+    pub fn batch_window(mut self, window: Duration) -> Self {
+        self.config.throttle.batch_window = Some(window);
+        self
+    }
+
+    /// Charge each batch against the rate limit as one request per call (default) or
+    /// as a single request
+    ///
+    /// Applies to automatic batches, `batcher()`, notification batches and
+    /// `send_raw_batch`. Calls waiting for a batch never block on the rate limiter;
+    /// only the dispatch of the batch does.
+    pub fn batch_cost(mut self, cost: BatchCost) -> Self {
+        self.config.throttle.batch_cost = cost;
+        self
+    }
+
+    /// Resend the calls of a batch one by one when the server does not support batches
+    /// (default: `false`)
+    ///
+    /// A server without batch support answers a batch with a single object, typically
+    /// an error, instead of an array. With the fallback on, each call of such a batch is
+    /// then sent as its own request and the batch is answered from their responses, so
+    /// one client works with servers with and without batch support. Applies to
+    /// explicit and automatic batches, not to `send_raw_batch`.
+    pub fn batch_fallback(mut self, enabled: bool) -> Self {
+        self.config.batch_fallback = enabled;
+        self
+    }
+
+    /// Send arguments as an array (default) or as an object keyed by argument name
+    pub fn param_style(mut self, style: ParamStyle) -> Self {
+        self.config.param_style = style;
+        self
+    }
+
+    /// With named params, leave out `None` arguments (default) or send them as `null`
+    ///
+    /// Some servers treat an explicit `null` differently from a missing key.
+    pub fn omit_null_params(mut self, omit: bool) -> Self {
+        self.config.omit_null_params = omit;
+        self
+    }
+
+    /// Override the request `Content-Type` (default: `application/json`)
+    ///
+    /// Useful for servers that insist on `application/json-rpc` or `text/plain`.
+    pub fn content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.config.content_type = content_type.into();
+        self
+    }
+
+    /// Override the request `Accept` header (default: `application/json`)
+    ///
+    /// Some servers pick the response format from this header and answer with HTML or
+    /// another encoding when it is missing or too broad.
+    pub fn accept(mut self, accept: impl Into<String>) -> Self {
+        self.config.accept = accept.into();
+        self
+    }
+
+    /// Accept both JSON numbers and `0x` hex strings for integer fields of results
+    ///
+    /// Applies to every integer anywhere in a result, which suits EVM-style servers
+    /// that encode quantities as hex strings.
+    pub fn hex_numbers(mut self, hex_numbers: bool) -> Self {
+        self.config.hex_numbers = hex_numbers;
+        self
+    }
+
+    /// Check that the response to each single call carries the `id` it was sent with,
+    /// failing with `RpcError::IdMismatch` otherwise (default: `false`)
+    ///
+    /// Catches proxies returning a stale response meant for another request. Off by
+    /// default since some servers do not echo ids faithfully. An error response with
+    /// a `null` id, which servers send when they could not read the request, is let
+    /// through.
+    pub fn verify_id(mut self, verify_id: bool) -> Self {
+        self.config.verify_id = verify_id;
+        self
+    }
+
+    /// Method called by `health_check()` (default: `getblockcount`)
+    ///
+    /// Pick something cheap that takes no parameters, e.g. `web3_clientVersion`.
+    pub fn health_check_method(mut self, method: impl Into<String>) -> Self {
+        self.config.health_check_method = method.into();
+        self
+    }
+
+    /// Prefix every method name, e.g. `daemon.` turns `getinfo` into `daemon.getinfo`
+    pub fn method_prefix(self, prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        self.method_mapper(move |method| format!("{}{}", prefix, method))
+    }
+
+    /// Rewrite every method name before it is sent
+    ///
+    /// Applies to single calls, batches, notifications and `health_check()`. Replaces
+    /// any previously set prefix or mapper.
+    pub fn method_mapper(mut self, f: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        self.config.method_mapper = Some(MethodMapper::new(f));
+        self
+    }
+
+    /// Find the actual result under these nested keys of `result`, for providers that
+    /// answer e.g. `{"result":{"data":<actual>},"id":1}`
+    ///
+    /// `.result_path(&["data"])` makes every typed call deserialize `result.data`; a
+    /// result without the key fails with `RpcError::JsonError`. Applies to each entry
+    /// of a batch, before `on_response_json`. Results of `stream_array` and
+    /// `with_response` are taken from `result` as is.
+    pub fn result_path(mut self, path: &[&str]) -> Self {
+        self.config.result_path = path.iter().map(|key| key.to_string()).collect();
+        self
+    }
+
+    /// Rewrite every `result` value with `f` before it is deserialized, e.g. to turn
+    /// numbers sent as strings back into numbers for a server that cannot be fixed
+    ///
+    /// Runs once per call after the result is taken out of its envelope (for each entry
+    /// of a batch), and before `hex_numbers` decoding. Results of `stream_array` are not
+    /// rewritten.
+    pub fn on_response_json(mut self, f: impl Fn(&mut Value) + Send + Sync + 'static) -> Self {
+        self.config.on_response_json = Some(ResponseTransform::new(f));
+        self
+    }
+
+    /// Handling of non-conformant responses carrying both a non-null `result` and a
+    /// non-null `error` (default: [`AmbiguousResponsePolicy::PreferError`])
+    pub fn ambiguous_response(mut self, policy: AmbiguousResponsePolicy) -> Self {
+        self.config.ambiguous_response = policy;
+        self
+    }
+
+    /// Start this client's request id sequence at `offset` (default: `0`)
+    pub fn id_offset(mut self, offset: u64) -> Self {
+        self.config.id_offset = offset;
+        self
+    }
+
+    /// Take request ids from `counter`, shared with other clients, so that ids are
+    /// unique across all of them, e.g. for log correlation behind a shared proxy
+    ///
+    /// Each request takes the counter's current value and increments it (a batch
+    /// takes one id per request). Replaces [`id_offset`](Self::id_offset); set the
+    /// counter's initial value instead.
+    pub fn shared_id_counter(mut self, counter: Arc<AtomicU64>) -> Self {
+        self.config.shared_id_counter = Some(counter);
+        self
+    }
+
+    /// Send request ids as strings with this prefix, e.g. `crawler-17`
+    ///
+    /// Makes ids of several clients behind a shared proxy distinguishable in its logs.
+    /// Applies to single calls and to every sub-request of a batch.
+    pub fn id_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.config.id_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Gzip large request bodies and send them with `Content-Encoding: gzip`
+    ///
+    /// Off by default, since not every server accepts compressed requests. Only bodies
+    /// of at least [`compression_threshold`](Self::compression_threshold) bytes are
+    /// compressed, which in practice means large batches.
+    pub fn compress_requests(mut self, compress: bool) -> Self {
+        self.config.compress_requests = compress;
+        self
+    }
+
+    /// Minimum body size, in bytes, compressed by
+    /// [`compress_requests`](Self::compress_requests) (default: 32 KiB; `0` compresses
+    /// every request)
+    pub fn compression_threshold(mut self, bytes: usize) -> Self {
+        self.config.compression_threshold = bytes;
+        self
+    }
+
+    /// Longest response body, in bytes, kept in `RpcError::JsonError` and
+    /// `RpcError::CannotDeserialize` (default: 8 KiB)
+    ///
+    /// Longer bodies are cut, ending with `...` and their original length, so huge
+    /// responses neither flood logs nor linger in memory. Pass `usize::MAX` to keep
+    /// whole bodies; the `on_deserialize_error` hook always receives the whole body.
+    pub fn max_error_body(mut self, bytes: usize) -> Self {
+        self.config.max_error_body = bytes;
+        self
+    }
+
+    /// Cache successful responses to `method` for `ttl`
+    ///
+    /// Calls with the same arguments within `ttl` are answered from the cache without
+    /// a request; error responses are never cached. Only direct calls are cached, not
+    /// batches. `last_was_cache_hit()` tells whether the calling thread's last call was
+    /// answered from the cache.
+    ///
+    /// If the server sent an `ETag` with the response, an expired entry is revalidated
+    /// with `If-None-Match` instead of dropped (for up to ten times `ttl`), and a
+    /// `304 Not Modified` answer renews it and counts as a cache hit. Calls queued for an
+    /// automatic batch cannot use `ETag`s and fall back to plain expiry.
+    pub fn cache_method(mut self, method: impl Into<String>, ttl: Duration) -> Self {
+        self.config.cached_methods.push((method.into(), ttl));
+        self
+    }
+
+    /// Accept the whole response body as the result of `method` when it is not a
+    /// JSON-RPC envelope, for non-standard endpoints that answer with the bare value
+    ///
+    /// A body that is a JSON object with a `result` or `error` member is still parsed
+    /// as an envelope. Has no effect on batched calls.
+    pub fn bare_result(mut self, method: impl Into<String>) -> Self {
+        self.config.bare_result_methods.push(method.into());
+        self
+    }
+
+    /// Send calls to `method` as HTTP GET requests instead of POSTs, for servers that
+    /// accept read-only calls that way, such as some embedded devices
+    ///
+    /// The request object goes in the query string, URL-encoded after any query of the
+    /// endpoint URL: `jsonrpc` (if set) and `method` as plain strings, `params` and `id`
+    /// as JSON text, e.g. `?method=getblockhash&params=%5B100%5D&id=0`. Such calls are
+    /// sent on their own rather than in an automatic batch, and batches are always
+    /// POSTed. `CallOptions::http_get` does the same for a single call.
+    pub fn http_get(mut self, method: impl Into<String>) -> Self {
+        self.config.http_get_methods.push(method.into());
+        self
+    }
+
+    /// Retry a failed request up to `retries` more times
+    ///
+    /// Transient errors (see `RpcError::is_transient`) are retried, as are errors
+    /// accepted by [`retry_if`](Self::retry_if). Retries wait out
+    /// [`retry_backoff`](Self::retry_backoff) and go through the throttle like any
+    /// other request.
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.config.max_retries = retries;
+        self
+    }
+
+    /// Wait `delay` before the first retry and twice as long before each further one
+    /// (default: [`DEFAULT_RETRY_BACKOFF`]), giving a busy node time to recover
+    ///
+    /// A call's `CancelToken` is checked while it waits, ending the call with
+    /// `RpcError::Cancelled`.
+    pub fn retry_backoff(mut self, delay: Duration) -> Self {
+        self.config.retry_backoff = delay;
+        self
+    }
+
+    /// Also retry errors for which `f(error, attempt)` returns `true`, where `attempt`
+    /// counts the attempts made so far (starting at 1)
+    ///
+    /// The server's error object is seen as `RpcError::RpcError`, so e.g. a "node busy"
+    /// error can be retried with
+    /// `.retry_if(|e, _| matches!(e, RpcError::RpcError { error } if error["code"] == -32000))`.
+    /// Takes effect together with [`max_retries`](Self::max_retries), which still bounds
+    /// the number of retries.
+    pub fn retry_if(mut self, f: impl Fn(&RpcError, u32) -> bool + Send + Sync + 'static) -> Self {
+        self.config.retry_if = Some(RetryPredicate::new(f));
+        self
+    }
+
+    /// Treat error objects with this `code` as success, e.g. "already in mempool" when
+    /// resubmitting a transaction (default: every error object is an error)
+    ///
+    /// Only errors carrying this numeric `code` match, never 1.0-style string errors or
+    /// error objects without a code, even with the code they are given in
+    /// [`JsonRpcErrorObject`](crate::JsonRpcErrorObject).
+    ///
+    /// The call's result is then deserialized from `null`, so the method should return a
+    /// type accepting it, such as `Option<T>`, `()` or `Value`; for other types the
+    /// call still fails with the server's error. Applies to single calls, including
+    /// auto-batched ones, but not to explicit batches.
+    pub fn accept_error_code(mut self, code: i64) -> Self {
+        self.config.accepted_error_codes.push((None, code));
+        self
+    }
+
+    /// Like [`accept_error_code`](Self::accept_error_code), for calls of `method` only
+    pub fn accept_method_error_code(mut self, method: impl Into<String>, code: i64) -> Self {
+        self.config
+            .accepted_error_codes
+            .push((Some(method.into()), code));
+        self
+    }
+
+    /// Ask for gzip-compressed responses to calls of `method`, sending
+    /// `Accept-Encoding: gzip`
+    ///
+    /// Worth it for methods with large results; small frequent responses are best left
+    /// uncompressed, which is the default. Such calls are sent on their own rather than
+    /// in an automatic batch, and their responses are decompressed before parsing.
+    /// `CallOptions::gzip_response` does the same for a single call. Results read with
+    /// `stream_array` are always requested uncompressed.
+    pub fn gzip_response(mut self, method: impl Into<String>) -> Self {
+        self.config.gzip_response_methods.push(method.into());
+        self
+    }
+
+    /// Call `f` for a fresh bearer token when a request is answered with
+    /// `401 Unauthorized`, then send the request once more with it
+    ///
+    /// The token is sent as `Authorization: Bearer <token>` with every later request to
+    /// the endpoint that answered `401`, in place of its own credentials; other endpoints
+    /// keep theirs. Refreshes are serialized: when several requests are rejected at once,
+    /// `f` runs once and the others retry with the token it returned. An error from `f`
+    /// fails the request with that error, and a token that is not a valid header value
+    /// with `RpcError::InvalidHeader`; a second 401 is returned as is.
+    pub fn on_unauthorized(
+        mut self,
+        f: impl Fn() -> Result<String, RpcError> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.on_unauthorized = Some(TokenRefresh::new(f));
+        self
+    }
+
+    /// Call `f` after every single call and batch request, e.g. for logging or metrics
+    ///
+    /// The event carries the call's `CallOptions::metadata`, so per-tenant accounting
+    /// can be done in one place.
+    pub fn on_request(mut self, f: impl Fn(&RequestEvent<'_>) + Send + Sync + 'static) -> Self {
+        self.config.on_request = Some(RequestHook::new(f));
+        self
+    }
+
+    /// Call `f` with the method, raw response body and error whenever a result does not
+    /// match the expected type
+    ///
+    /// The call still fails; the hook is meant for recording schema drift in
+    /// production. Batches report the wire method of each failing entry, or `"batch"`
+    /// when the whole batch is rejected.
+    pub fn on_deserialize_error(
+        mut self,
+        f: impl Fn(&str, &str, &RpcError) + Send + Sync + 'static,
+    ) -> Self {
+        self.config.on_deserialize_error = Some(DeserializeErrorHook::new(f));
+        self
+    }
+
+    pub fn build(self) -> Arc<C> {
+        C::from_config(self.config)
+    }
+}
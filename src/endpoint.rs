@@ -0,0 +1,244 @@
+//! RPC endpoints and the credentials used to reach them.
+
+use reqwest::blocking::RequestBuilder;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::Url;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// How requests to an endpoint are authenticated
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Auth {
+    /// No authentication
+    #[default]
+    None,
+    /// HTTP basic auth
+    Basic { user: String, pass: Option<String> },
+    /// `Authorization: Bearer <token>`
+    Bearer(String),
+}
+
+/// An outgoing request as seen by an [`AuthSigner`]
+#[derive(Debug)]
+pub struct RequestParts<'a> {
+    /// URL the request is sent to
+    pub url: &'a str,
+    /// Body bytes exactly as sent, i.e. after any compression
+    pub body: &'a [u8],
+    /// Headers to add to the request, e.g. a signature and its timestamp
+    pub headers: HeaderMap,
+}
+
+/// Callback computing request-specific auth headers, such as an HMAC over the body
+#[derive(Clone)]
+pub struct AuthSigner(Arc<dyn Fn(&mut RequestParts<'_>) + Send + Sync>);
+
+impl AuthSigner {
+    pub fn new(f: impl Fn(&mut RequestParts<'_>) + Send + Sync + 'static) -> Self {
+        AuthSigner(Arc::new(f))
+    }
+
+    pub fn sign(&self, parts: &mut RequestParts<'_>) {
+        (self.0)(parts)
+    }
+}
+
+impl fmt::Debug for AuthSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthSigner(..)")
+    }
+}
+
+/// A server URL together with its own credentials and extra headers
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub uri: String,
+    pub auth: Auth,
+    pub headers: Vec<(String, String)>,
+    /// Extra headers given as `reqwest` header types, sent after `headers`
+    pub raw_headers: Vec<(HeaderName, HeaderValue)>,
+    /// Query parameters appended to `uri` on every request
+    pub query: Vec<(String, String)>,
+    /// Name presented for TLS (SNI and certificate check) and as `Host`, while still
+    /// connecting to the address in `uri`
+    pub tls_server_name: Option<String>,
+}
+
+impl Endpoint {
+    pub fn new(uri: impl Into<String>) -> Self {
+        Endpoint {
+            uri: uri.into(),
+            auth: Auth::None,
+            headers: Vec::new(),
+            raw_headers: Vec::new(),
+            query: Vec::new(),
+            tls_server_name: None,
+        }
+    }
+
+    /// Use HTTP basic auth for this endpoint
+    pub fn basic_auth(mut self, user: impl Into<String>, pass: Option<String>) -> Self {
+        self.auth = Auth::Basic {
+            user: user.into(),
+            pass,
+        };
+        self
+    }
+
+    /// Use a bearer token for this endpoint
+    pub fn bearer_auth(mut self, token: impl Into<String>) -> Self {
+        self.auth = Auth::Bearer(token.into());
+        self
+    }
+
+    /// Send an extra header with every request to this endpoint
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Send an extra header built from `reqwest` header types with every request to
+    /// this endpoint
+    ///
+    /// Unlike [`header`](Self::header), the value may hold any bytes a `HeaderValue`
+    /// accepts, including non-UTF-8 ones, and can be marked with
+    /// `HeaderValue::set_sensitive` to keep it out of `Debug` output.
+    pub fn raw_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.raw_headers.push((name, value));
+        self
+    }
+
+    /// Append `name=value` to the query string of every request to this endpoint, e.g.
+    /// for providers expecting `?apikey=..`
+    ///
+    /// The parameter is percent-encoded and added after any query already in `uri`.
+    pub fn query_param(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.query.push((name.into(), value.into()));
+        self
+    }
+
+    /// Override the `Host` header sent to this endpoint
+    pub fn host_header(self, host: impl Into<String>) -> Self {
+        self.header("Host", host)
+    }
+
+    /// Connect to the IP address in `uri` but present `name` for TLS and as `Host`
+    ///
+    /// For IP-pinned connections to TLS endpoints whose certificate is issued for a
+    /// host name. Only applies when the host in `uri` is an IP address; reqwest's
+    /// resolver overrides are client-wide, so `name` should map to a single address
+    /// across all endpoints of a client.
+    pub fn tls_server_name(mut self, name: impl Into<String>) -> Self {
+        self.tls_server_name = Some(name.into());
+        self
+    }
+
+    /// Path of the local pipe this endpoint names, if its `uri` starts with
+    /// [`NAMED_PIPE_PREFIX`](crate::NAMED_PIPE_PREFIX)
+    pub fn named_pipe(&self) -> Option<&str> {
+        self.uri
+            .starts_with(crate::NAMED_PIPE_PREFIX)
+            .then_some(self.uri.as_str())
+    }
+
+    /// URL to request, including query parameters, and, for a TLS server name
+    /// override, the resolver entry that pins that name to the configured address
+    pub(crate) fn connect_target(&self) -> (String, Option<(String, SocketAddr)>) {
+        let (uri, resolve) = self.pinned_target();
+        (self.with_query(uri), resolve)
+    }
+
+    /// `uri` with this endpoint's query parameters appended
+    fn with_query(&self, uri: String) -> String {
+        if self.query.is_empty() {
+            return uri;
+        }
+        let Ok(mut url) = Url::parse(&uri) else {
+            return uri;
+        };
+        url.query_pairs_mut().extend_pairs(&self.query);
+        url.to_string()
+    }
+
+    fn pinned_target(&self) -> (String, Option<(String, SocketAddr)>) {
+        let Some(name) = &self.tls_server_name else {
+            return (self.uri.clone(), None);
+        };
+        let Ok(mut url) = Url::parse(&self.uri) else {
+            return (self.uri.clone(), None);
+        };
+        let ip = match url.host_str().map(|h| h.trim_matches(['[', ']'])) {
+            Some(host) => host.parse::<IpAddr>(),
+            None => return (self.uri.clone(), None),
+        };
+        let (Ok(ip), Some(port)) = (ip, url.port_or_known_default()) else {
+            return (self.uri.clone(), None);
+        };
+        if url.set_host(Some(name)).is_err() {
+            return (self.uri.clone(), None);
+        }
+        (
+            url.to_string(),
+            Some((name.clone(), SocketAddr::new(ip, port))),
+        )
+    }
+
+    /// Apply this endpoint's credentials and headers to a request
+    pub(crate) fn apply(&self, mut builder: RequestBuilder) -> RequestBuilder {
+        builder = match &self.auth {
+            Auth::None => builder,
+            Auth::Basic { user, pass } => builder.basic_auth(user, pass.as_ref()),
+            Auth::Bearer(token) => builder.bearer_auth(token),
+        };
+        for (name, value) in &self.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        for (name, value) in &self.raw_headers {
+            builder = builder.header(name, value);
+        }
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_server_name_pins_ip() {
+        let endpoint = Endpoint::new("https://10.0.0.5:8332/rpc").tls_server_name("node.example");
+        let (uri, resolve) = endpoint.connect_target();
+        assert_eq!(uri, "https://node.example:8332/rpc");
+        assert_eq!(
+            resolve,
+            Some(("node.example".to_string(), "10.0.0.5:8332".parse().unwrap()))
+        );
+
+        // Host names are left alone
+        let endpoint = Endpoint::new("https://rpc.example").tls_server_name("node.example");
+        assert_eq!(
+            endpoint.connect_target(),
+            ("https://rpc.example".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn test_query_params() {
+        let endpoint = Endpoint::new("https://rpc.example/v1?network=main")
+            .query_param("apikey", "k&y=1")
+            .query_param("tag", "a b");
+        assert_eq!(
+            endpoint.connect_target().0,
+            "https://rpc.example/v1?network=main&apikey=k%26y%3D1&tag=a+b"
+        );
+
+        let endpoint = Endpoint::new("https://10.0.0.5:8332/")
+            .tls_server_name("node.example")
+            .query_param("apikey", "k");
+        assert_eq!(
+            endpoint.connect_target().0,
+            "https://node.example:8332/?apikey=k"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! JSON-RPC request and response envelopes, for building and parsing messages without
+//! a generated client (e.g. in a mock server).
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A JSON-RPC request object
+///
+/// ```
+/// use throttled_json_rpc::RpcRequest;
+///
+/// let request = RpcRequest::new("getblockhash", [100]).with_id(1);
+/// assert_eq!(
+///     serde_json::to_string(&request).unwrap(),
+///     r#"{"method":"getblockhash","params":[100],"id":1}"#
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpcRequest<P = Value> {
+    /// Protocol version such as `"2.0"`; omitted when `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jsonrpc: Option<String>,
+    pub method: String,
+    pub params: P,
+    /// Request id; `None` makes the request a notification
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+}
+
+impl<P> RpcRequest<P> {
+    /// A notification (no id) calling `method` with `params`
+    pub fn new(method: impl Into<String>, params: P) -> Self {
+        RpcRequest {
+            jsonrpc: None,
+            method: method.into(),
+            params,
+            id: None,
+        }
+    }
+
+    pub fn with_id(mut self, id: impl Into<Value>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+}
+
+/// A JSON-RPC response object
+///
+/// Missing members parse as `None`. Serialized as Bitcoin-style servers send it, with
+/// `result` and `error` always present and one of them `null`.
+///
+/// ```
+/// use throttled_json_rpc::RpcResponse;
+///
+/// let reply: RpcResponse<u64> = serde_json::from_str(r#"{"result":5,"id":0}"#).unwrap();
+/// assert_eq!(reply.result, Some(5));
+/// assert_eq!(
+///     serde_json::to_string(&RpcResponse::<u64>::failure(serde_json::json!({"code": -1}), 0)).unwrap(),
+///     r#"{"result":null,"error":{"code":-1},"id":0}"#
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpcResponse<T = Value> {
+    pub result: Option<T>,
+    pub error: Option<Value>,
+    pub id: Option<Value>,
+}
+
+impl<T> RpcResponse<T> {
+    /// A successful response answering request `id`
+    pub fn success(result: T, id: impl Into<Value>) -> Self {
+        RpcResponse {
+            result: Some(result),
+            error: None,
+            id: Some(id.into()),
+        }
+    }
+
+    /// A failed response answering request `id` with an error object
+    pub fn failure(error: Value, id: impl Into<Value>) -> Self {
+        RpcResponse {
+            result: None,
+            error: Some(error),
+            id: Some(id.into()),
+        }
+    }
+}
+
+/// A server error in one shape, whatever form the server sent it in
+///
+/// JSON-RPC 2.0 servers send `{"code": .., "message": .., "data": ..}`, while 1.0-style
+/// servers may send a bare string. A string becomes the message, with code
+/// [`STRING_ERROR_CODE`](Self::STRING_ERROR_CODE); other values without a numeric
+/// `code` get the same code and their JSON text as the message. Such errors have
+/// `synthesized_code` set, telling them apart from a server that sent `-32000` itself.
+///
+/// ```
+/// use throttled_json_rpc::JsonRpcErrorObject;
+///
+/// let error = JsonRpcErrorObject::from_value(&serde_json::json!("Block not found"));
+/// assert_eq!(error.code, JsonRpcErrorObject::STRING_ERROR_CODE);
+/// assert!(error.synthesized_code);
+/// assert_eq!(error.message, "Block not found");
+///
+/// let error = JsonRpcErrorObject::from_value(&serde_json::json!({"code": -5, "message": "Invalid address"}));
+/// assert_eq!((error.code, error.message.as_str()), (-5, "Invalid address"));
+/// assert!(!error.synthesized_code);
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsonRpcErrorObject {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    /// The server sent no numeric `code`, and `code` is
+    /// [`STRING_ERROR_CODE`](Self::STRING_ERROR_CODE) in its place
+    #[serde(skip)]
+    pub synthesized_code: bool,
+}
+
+impl JsonRpcErrorObject {
+    /// Code given to errors that did not carry one, such as 1.0-style string errors
+    /// (the generic "server error" code of JSON-RPC 2.0, which servers also send
+    /// themselves; check `synthesized_code` to tell the two apart)
+    pub const STRING_ERROR_CODE: i64 = -32000;
+
+    /// Normalize an `error` member as sent by the server
+    pub fn from_value(error: &Value) -> Self {
+        let code = error.get("code").and_then(Value::as_i64);
+        let message = match (error, error.get("message")) {
+            (Value::String(s), _) => s.clone(),
+            (_, Some(Value::String(s))) => s.clone(),
+            (_, Some(other)) => other.to_string(),
+            (_, None) if code.is_some() => String::new(),
+            (other, None) => other.to_string(),
+        };
+        JsonRpcErrorObject {
+            code: code.unwrap_or(Self::STRING_ERROR_CODE),
+            message,
+            data: error.get("data").filter(|d| !d.is_null()).cloned(),
+            synthesized_code: code.is_none(),
+        }
+    }
+}
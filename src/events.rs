@@ -0,0 +1,67 @@
+//! Observability hooks invoked after requests and failed deserializations.
+
+use crate::RpcError;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Summary of one completed HTTP request, passed to the `on_request` hook
+#[derive(Debug)]
+pub struct RequestEvent<'a> {
+    /// Wire method name, or `"batch"` for batch requests
+    pub method: &'a str,
+    /// Metadata attached through `CallOptions::metadata`
+    pub metadata: &'a [(String, String)],
+    /// Time from entering the throttle until the response body was read
+    pub elapsed: Duration,
+    /// Part of `elapsed` spent waiting for the rate limiter and concurrency gate,
+    /// summed over retries
+    pub throttle_wait: Duration,
+    /// Rest of `elapsed`: sending the request and reading the response
+    pub network: Duration,
+    /// The request's error, if it failed before a JSON-RPC response was read
+    pub error: Option<&'a RpcError>,
+}
+
+/// Callback receiving a [`RequestEvent`] after every request
+#[derive(Clone)]
+pub struct RequestHook(Arc<dyn Fn(&RequestEvent<'_>) + Send + Sync>);
+
+impl RequestHook {
+    pub fn new(f: impl Fn(&RequestEvent<'_>) + Send + Sync + 'static) -> Self {
+        RequestHook(Arc::new(f))
+    }
+
+    pub fn call(&self, event: &RequestEvent<'_>) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for RequestHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RequestHook(..)")
+    }
+}
+
+/// Callback receiving the method, response body and error of every call whose result
+/// could not be deserialized
+#[derive(Clone)]
+pub struct DeserializeErrorHook(Arc<DeserializeErrorFn>);
+
+type DeserializeErrorFn = dyn Fn(&str, &str, &RpcError) + Send + Sync;
+
+impl DeserializeErrorHook {
+    pub fn new(f: impl Fn(&str, &str, &RpcError) + Send + Sync + 'static) -> Self {
+        DeserializeErrorHook(Arc::new(f))
+    }
+
+    pub fn call(&self, method: &str, body: &str, error: &RpcError) {
+        (self.0)(method, body, error)
+    }
+}
+
+impl fmt::Debug for DeserializeErrorHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DeserializeErrorHook(..)")
+    }
+}
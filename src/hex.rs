@@ -0,0 +1,299 @@
+//! Deserializer accepting `0x`-prefixed hex strings wherever an integer is expected.
+//!
+//! Used when a client is built with `.hex_numbers(true)`, e.g. for EVM chains that
+//! encode quantities as hex strings.
+
+use serde::de::{
+    self, Deserialize, DeserializeSeed, EnumAccess, MapAccess, SeqAccess, Unexpected,
+    VariantAccess, Visitor,
+};
+use serde::forward_to_deserialize_any;
+use serde_json::{Map, Value};
+
+/// Wraps a [`Value`] so integers may be given as JSON numbers or `0x` hex strings
+pub struct HexNumbers(pub Value);
+
+fn parse_hex(s: &str) -> Option<u128> {
+    let digits = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"))?;
+    u128::from_str_radix(digits, 16).ok()
+}
+
+macro_rules! deserialize_integer {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                match self.0 {
+                    Value::String(s) => match parse_hex(&s) {
+                        Some(n) => match u64::try_from(n) {
+                            Ok(n) => visitor.visit_u64(n),
+                            Err(_) => visitor.visit_u128(n),
+                        },
+                        None => Value::String(s).$method(visitor),
+                    },
+                    other => other.$method(visitor),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for HexNumbers {
+    type Error = serde_json::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Array(values) => visitor.visit_seq(Seq(values.into_iter())),
+            Value::Object(map) => visitor.visit_map(Object {
+                iter: map.into_iter(),
+                value: None,
+            }),
+            other => other.deserialize_any(visitor),
+        }
+    }
+
+    deserialize_integer! {
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_u128
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64 deserialize_i128
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            other => visitor.visit_some(HexNumbers(other)),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        // Externally tagged, as serde_json reads enums: `"Unit"` or `{"Variant": content}`
+        match self.0 {
+            Value::String(variant) => visitor.visit_enum(Variant {
+                variant,
+                content: None,
+            }),
+            Value::Object(map) if map.len() == 1 => {
+                let (variant, content) = map.into_iter().next().expect("map has one entry");
+                visitor.visit_enum(Variant {
+                    variant,
+                    content: Some(content),
+                })
+            }
+            other => other.deserialize_enum(name, variants, visitor),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool f32 f64 char str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct Seq(std::vec::IntoIter<Value>);
+
+impl<'de> SeqAccess<'de> for Seq {
+    type Error = serde_json::Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        self.0
+            .next()
+            .map(|value| seed.deserialize(HexNumbers(value)))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+struct Object {
+    iter: <Map<String, Value> as IntoIterator>::IntoIter,
+    value: Option<Value>,
+}
+
+impl<'de> MapAccess<'de> for Object {
+    type Error = serde_json::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(Value::String(key)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let value = self
+            .value
+            .take()
+            .ok_or_else(|| de::Error::custom("value is missing"))?;
+        seed.deserialize(HexNumbers(value))
+    }
+}
+
+/// Variant of an enum, whose content is deserialized through [`HexNumbers`]
+struct Variant {
+    variant: String,
+    content: Option<Value>,
+}
+
+impl<'de> EnumAccess<'de> for Variant {
+    type Error = serde_json::Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        mut self,
+        seed: V,
+    ) -> Result<(V::Value, Self), Self::Error> {
+        let variant = seed.deserialize(Value::String(std::mem::take(&mut self.variant)))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Variant {
+    type Error = serde_json::Error;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        match self.content {
+            Some(content) => Deserialize::deserialize(HexNumbers(content)),
+            None => Ok(()),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, Self::Error> {
+        match self.content {
+            Some(content) => seed.deserialize(HexNumbers(content)),
+            None => Err(de::Error::invalid_type(
+                Unexpected::UnitVariant,
+                &"newtype variant",
+            )),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self.content {
+            Some(content) => de::Deserializer::deserialize_any(HexNumbers(content), visitor),
+            None => Err(de::Error::invalid_type(
+                Unexpected::UnitVariant,
+                &"tuple variant",
+            )),
+        }
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self.content {
+            Some(content) => de::Deserializer::deserialize_any(HexNumbers(content), visitor),
+            None => Err(de::Error::invalid_type(
+                Unexpected::UnitVariant,
+                &"struct variant",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Block {
+        number: u64,
+        gas_used: Option<u32>,
+        hash: String,
+        logs: Vec<i64>,
+    }
+
+    #[test]
+    fn test_hex_and_decimal_integers() {
+        let value = json!({
+            "number": "0x10",
+            "gas_used": 7,
+            "hash": "0xabc",
+            "logs": ["0x1", 2],
+        });
+        let block = Block::deserialize(HexNumbers(value)).unwrap();
+        assert_eq!(
+            block,
+            Block {
+                number: 16,
+                gas_used: Some(7),
+                hash: "0xabc".to_string(),
+                logs: vec![1, 2],
+            }
+        );
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    enum Sync {
+        Done,
+        Block(u64),
+        Range(u64, u64),
+        Progress { current: u64, highest: Option<u64> },
+    }
+
+    #[test]
+    fn test_hex_in_enums() {
+        let syncs = Vec::<Sync>::deserialize(HexNumbers(json!([
+            "Done",
+            {"Block": "0x10"},
+            {"Range": ["0x1", 2]},
+            {"Progress": {"current": "0xff", "highest": "0x100"}},
+        ])))
+        .unwrap();
+        assert_eq!(
+            syncs,
+            vec![
+                Sync::Done,
+                Sync::Block(16),
+                Sync::Range(1, 2),
+                Sync::Progress {
+                    current: 255,
+                    highest: Some(256)
+                },
+            ]
+        );
+        assert!(Sync::deserialize(HexNumbers(json!({"Block": "0x1", "Done": null}))).is_err());
+        assert!(Sync::deserialize(HexNumbers(json!("Block"))).is_err());
+    }
+
+    #[test]
+    fn test_invalid_hex_is_an_error() {
+        assert!(u64::deserialize(HexNumbers(json!("0xzz"))).is_err());
+        assert!(u8::deserialize(HexNumbers(json!("0x100"))).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+//! Request ids: a per-client sequence, optionally offset or prefixed.
+
+use serde_json::Value;
+
+/// A contiguous block of request ids reserved for one request or batch
+#[derive(Debug, Clone)]
+pub struct IdBlock<'a> {
+    start: u64,
+    len: usize,
+    prefix: Option<&'a str>,
+}
+
+impl<'a> IdBlock<'a> {
+    pub(crate) fn new(start: u64, len: usize, prefix: Option<&'a str>) -> Self {
+        IdBlock { start, len, prefix }
+    }
+
+    /// Number of ids in the block
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Wire id of the `idx`-th request in the block
+    pub fn id(&self, idx: usize) -> Value {
+        let n = self.start.wrapping_add(idx as u64);
+        match self.prefix {
+            Some(prefix) => Value::String(format!("{}{}", prefix, n)),
+            None => Value::from(n),
+        }
+    }
+
+    /// Position in the block of the request that `id` answers, if it is one of ours
+    pub fn index_of(&self, id: &Value) -> Option<usize> {
+        let n = match (self.prefix, id) {
+            (Some(prefix), Value::String(s)) => s.strip_prefix(prefix)?.parse::<u64>().ok()?,
+            (None, Value::Number(n)) => n.as_u64()?,
+            _ => return None,
+        };
+        let idx = usize::try_from(n.wrapping_sub(self.start)).ok()?;
+        (idx < self.len).then_some(idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_numeric_ids() {
+        let block = IdBlock::new(100, 3, None);
+        assert_eq!(block.id(2), json!(102));
+        assert_eq!(block.index_of(&json!(101)), Some(1));
+        assert_eq!(block.index_of(&json!(103)), None);
+        assert_eq!(block.index_of(&json!(99)), None);
+        assert_eq!(block.index_of(&json!("101")), None);
+    }
+
+    #[test]
+    fn test_prefixed_ids() {
+        let block = IdBlock::new(5, 2, Some("crawler-"));
+        assert_eq!(block.id(1), json!("crawler-6"));
+        assert_eq!(block.index_of(&json!("crawler-5")), Some(0));
+        assert_eq!(block.index_of(&json!("indexer-5")), None);
+        assert_eq!(block.index_of(&json!(5)), None);
+    }
+}
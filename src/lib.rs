@@ -10,6 +10,17 @@
 //! - **Concurrency Control**: Limit simultaneous in-flight requests
 //! - **Request Batching**: Efficiently batch multiple RPC calls
 //! - **Flexible Response Types**: Support for both single-type and enum variant responses
+//! - **Metrics** (feature `metrics`): `client.metrics_text()` renders per-method request
+//!   counts, error counts by kind and a latency histogram in the Prometheus text format
+//! - **Error Paths** (feature `path-to-error`): deserialization errors name the offending
+//!   field, e.g. `result.tx[3].vout[0].value: invalid type: string "x", expected f64`
+//! - **Mock Server** (feature `mock`): `MockRpcServer` answers calls on a local port
+//!   with mocked results, errors, HTTP statuses and delays, for integration tests
+//! - **Lean Dependencies**: reqwest is used without its default features; TLS backends
+//!   (`native-tls`, `rustls-tls`, ..), `charset`, `http2` and `system-proxy` are
+//!   forwarded as features of this crate, all but `rustls-tls` on by default
+//! - **Typed Arguments**: Parameters and results may be any serde type, including newtypes
+//!   such as `struct BlockHeight(u64)`, which go over the wire as their inner value
 //!
 //! ## Throttling Behavior
 //!
@@ -18,18 +29,35 @@
 //! ### Rate Limiting (RPS)
 //! - **When**: `rps > 0`
 //! - **How**: Enforces minimum time `1/rps` seconds between consecutive requests
-//! - **Behavior**: Thread sleeps if previous request was too recent
-//! - **Scope**: Global across all threads using the same client instance
+//! - **Behavior**: Thread sleeps if previous request was too recent, or fails fast with
+//!   [`RpcError::RateLimited`] under [`WhenThrottled::Reject`]
+//! - **Scope**: Global across all threads using the same client instance, or per thread
+//!   with [`ThrottleScope::PerThread`]
 //!
 //! ### Concurrency Limiting
 //! - **When**: `max_concurrency > 0`
 //! - **How**: Limits number of simultaneous in-flight requests
 //! - **Behavior**: Thread blocks (via Condvar) until a slot is available
 //! - **Scope**: Global across all threads using the same client instance
+//! - **Priority**: Waiters with a higher [`CallOptions::priority`] acquire the next free
+//!   slot first; equal priorities are served in arrival order
+//!
+//! ### Batching and Concurrency
+//! - A method called directly on the client is sent on its own immediately, even with
+//!   `max_batch_size > 0`; only with a batch window is it queued, and then for at most
+//!   the window, so a batch that never fills up is still sent
+//! - Calls are batched first and acquire a concurrency slot second: a call queued for an
+//!   automatic batch (`ClientBuilder::batch_window`) holds no slot while it waits
+//! - Only the thread sending a batch passes the rate limiter and concurrency gate, once
+//!   per batch request, and releases its slot as soon as the response body has been read
+//! - A batch therefore never waits on a slot held by one of its own queued calls, so
+//!   batching and concurrency limits cannot deadlock each other
 //!
 //! ### Important Notes
 //! - This is a **blocking/synchronous** client - threads will sleep/block
-//! - For async workloads, consider wrapping calls in `tokio::task::spawn_blocking`
+//! - For async workloads, consider wrapping calls in `tokio::task::spawn_blocking`; pass a
+//!   [`CancelToken`] via [`CallOptions::cancel_token`] so a cancelled task stops waiting on
+//!   the throttle and returns [`RpcError::Cancelled`] instead of holding a slot
 //! - Timeouts are controlled by the underlying `reqwest` client (default: 30s connect, no read timeout)
 //!
 //! ## Example
@@ -56,6 +84,14 @@
 //!
 //! let block_hash = client.getblockhash(100)?;
 //! println!("Block hash: {}", block_hash);
+//!
+//! // The same client via the builder, with a non-default Content-Type
+//! let client = MyRpcClient::builder("http://localhost:8332")
+//!     .auth("rpcuser", Some("rpcpass".to_string()))
+//!     .max_concurrency(5)
+//!     .rps(10)
+//!     .content_type("text/plain")
+//!     .build();
 //! # Ok(())
 //! # }
 //! ```
@@ -69,6 +105,34 @@ pub enum RpcError {
     #[error("HTTP request failed: {0}")]
     HttpError(#[from] reqwest::Error),
 
+    /// The TLS handshake with the server failed, e.g. over an untrusted or expired
+    /// certificate or a protocol mismatch
+    ///
+    /// Unlike a refused connection, retrying the same endpoint will not help.
+    #[error("TLS handshake failed: {0}")]
+    Tls(reqwest::Error),
+
+    /// Reading from or writing to a local transport failed
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
+
+    /// The endpoint's transport is not available on this platform or for this operation
+    #[error("Unsupported transport: {0}")]
+    UnsupportedTransport(&'static str),
+
+    /// Request could not be serialized to JSON
+    #[error("Request serialization failed: {0}")]
+    SerializeError(#[source] serde_json::Error),
+
+    /// A header value, such as a token returned by `on_unauthorized`, contains
+    /// characters not allowed in HTTP headers
+    #[error("Invalid header value: {0}")]
+    InvalidHeader(#[source] reqwest::header::InvalidHeaderValue),
+
+    /// A batch passed to `send_raw_batch` is not a JSON array
+    #[error("A batch must be a JSON array")]
+    InvalidBatch,
+
     /// JSON deserialization failed
     #[error("JSON deserialization failed: {source}\nBody: {body}")]
     JsonError {
@@ -80,10 +144,21 @@ pub enum RpcError {
     #[error("RPC error: {error:?}")]
     RpcError { error: serde_json::Value },
 
+    /// Response carried both a result and an error under `AmbiguousResponsePolicy::Reject`
+    #[error("Response contains both a result and an error: {error:?}")]
+    AmbiguousResponse { error: serde_json::Value },
+
     /// Response missing required ID field
     #[error("Response missing ID field")]
     MissingId,
 
+    /// Response `id` differs from the request's, with `ClientBuilder::verify_id`
+    #[error("Response id {got} does not match request id {expected}")]
+    IdMismatch {
+        expected: serde_json::Value,
+        got: serde_json::Value,
+    },
+
     /// Response missing in batch result
     #[error("Missing response in batch result")]
     MissingResponse,
@@ -99,20 +174,197 @@ pub enum RpcError {
         expected: &'static str,
     },
 
+    /// The rate limit did not allow the request yet under `WhenThrottled::Reject`
+    #[error("Rate limit reached; next request allowed in {retry_after:?}")]
+    RateLimited { retry_after: std::time::Duration },
+
+    /// Call was cancelled through its `CancelToken` before being sent
+    #[error("RPC call cancelled")]
+    Cancelled,
+
+    /// Server answered with an empty body where a JSON-RPC response was expected
+    #[error("Server returned an empty response body")]
+    EmptyResponse,
+
+    /// Response body is not JSON (e.g. an HTML page from the wrong port)
+    #[error("Response is not JSON (content type: {}): {snippet}", content_type.as_deref().unwrap_or("none"))]
+    NotJson {
+        content_type: Option<String>,
+        snippet: String,
+    },
+
+    /// The automatic batch this call was queued in failed as a whole
+    #[error("Batch request failed: {0}")]
+    BatchFailed(std::sync::Arc<RpcError>),
+
+    /// Error of a call tagged through `CallOptions::metadata`
+    #[error("{source} [{}]", format_metadata(metadata))]
+    Tagged {
+        metadata: Vec<(String, String)>,
+        source: Box<RpcError>,
+    },
+
     /// Cannot deserialize to any enum variant
-    #[error("Cannot deserialize to any variant of {enum_name}:\n{body}")]
+    #[error(
+        "Cannot deserialize to any variant of {enum_name}:\n{}{body}",
+        variant_error_lines(variant_errors)
+    )]
     CannotDeserialize {
         enum_name: &'static str,
+        /// Each variant tried, in declaration order, with the error deserializing into it
+        variant_errors: Vec<(&'static str, serde_json::Error)>,
         body: String,
     },
 }
 
+/// One `  Variant: error` line per variant of a `CannotDeserialize`
+fn variant_error_lines(errors: &[(&'static str, serde_json::Error)]) -> String {
+    errors
+        .iter()
+        .map(|(variant, error)| format!("  {}: {}\n", variant, error))
+        .collect()
+}
+
+impl RpcError {
+    /// Wrap the error with call metadata; errors without metadata are returned as is
+    pub fn with_metadata(self, metadata: &[(String, String)]) -> Self {
+        if metadata.is_empty() || matches!(self, RpcError::Tagged { .. }) {
+            return self;
+        }
+        RpcError::Tagged {
+            metadata: metadata.to_vec(),
+            source: Box::new(self),
+        }
+    }
+
+    /// Metadata of the call that failed, if it was tagged
+    pub fn metadata(&self) -> &[(String, String)] {
+        match self {
+            RpcError::Tagged { metadata, .. } => metadata,
+            _ => &[],
+        }
+    }
+
+    /// The underlying error, looking through `Tagged`
+    pub fn untagged(&self) -> &RpcError {
+        match self {
+            RpcError::Tagged { source, .. } => source,
+            other => other,
+        }
+    }
+
+    /// Whether the error is worth retrying as is: the endpoint could not be reached
+    /// or did not answer in time
+    pub fn is_transient(&self) -> bool {
+        match self.untagged() {
+            RpcError::HttpError(e) => e.is_connect() || e.is_timeout(),
+            RpcError::BatchFailed(e) => e.is_transient(),
+            _ => false,
+        }
+    }
+
+    /// The server's error in a uniform shape, whether it sent a JSON-RPC 2.0 error
+    /// object or a 1.0-style string
+    ///
+    /// Returns `None` when the error did not come from the server.
+    pub fn error_object(&self) -> Option<JsonRpcErrorObject> {
+        match self.untagged() {
+            RpcError::RpcError { error } | RpcError::AmbiguousResponse { error } => {
+                Some(JsonRpcErrorObject::from_value(error))
+            }
+            _ => None,
+        }
+    }
+
+    /// Deserialize the `data` member of the server's error object
+    ///
+    /// Returns `Ok(None)` when the error did not come from the server or its error
+    /// object has no (or a null) `data` member.
+    pub fn data<D: serde::de::DeserializeOwned>(&self) -> Result<Option<D>, serde_json::Error> {
+        let error = match self.untagged() {
+            RpcError::RpcError { error } | RpcError::AmbiguousResponse { error } => error,
+            _ => return Ok(None),
+        };
+        match error.get("data") {
+            None | Some(serde_json::Value::Null) => Ok(None),
+            Some(data) => D::deserialize(data).map(Some),
+        }
+    }
+}
+
+fn format_metadata(metadata: &[(String, String)]) -> String {
+    metadata
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 #[macro_use]
 mod macros;
+mod batching;
+mod cache;
+mod calibrate;
+mod config;
+mod dsn;
+mod endpoint;
+mod envelope;
+mod events;
+mod hex;
+mod ids;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "mock")]
+mod mock;
+mod options;
+mod pipe;
+mod pool;
+mod response;
+mod stream;
+#[cfg(any(test, feature = "mock"))]
+mod test_util;
+mod throttle;
+mod transport;
+
+pub use calibrate::{CalibrationReport, CALIBRATION_ERROR_LIMIT, MAX_CALIBRATION_SAMPLES};
+pub use config::{
+    AmbiguousResponsePolicy, ClientBuilder, ClientConfig, FromConfig, HttpVersion, MethodMapper,
+    ParamStyle, ResponseTransform, RetryPredicate, ThrottleConfig, TokenRefresh, DEFAULT_ACCEPT,
+    DEFAULT_COMPRESSION_THRESHOLD, DEFAULT_CONTENT_TYPE, DEFAULT_HEALTH_CHECK_METHOD,
+    DEFAULT_MAX_ERROR_BODY, DEFAULT_RETRY_BACKOFF,
+};
+pub use dsn::DsnError;
+pub use endpoint::{Auth, AuthSigner, Endpoint, RequestParts};
+pub use envelope::{JsonRpcErrorObject, RpcRequest, RpcResponse};
+pub use events::{DeserializeErrorHook, RequestEvent, RequestHook};
+pub use hex::HexNumbers;
+pub use ids::IdBlock;
+#[cfg(feature = "metrics")]
+pub use metrics::LATENCY_BUCKETS;
+#[cfg(feature = "mock")]
+pub use mock::{MockResponse, MockRpcServer, METHOD_NOT_FOUND};
+pub use options::{CallOptions, CancelToken};
+pub use pipe::NAMED_PIPE_PREFIX;
+pub use pool::{call_many, UNCAPPED_CALL_MANY_WORKERS};
+pub use response::{BatchEntry, BatchResponse, RawResponse};
+pub use stream::ResultStream;
+pub use throttle::{
+    AcquireAsync, AsyncPermit, BatchCost, Clock, ConcurrencyGate, GateGuard, InstantClock,
+    RateLimiter, SpinClock, Throttle, ThrottleScope, Unthrottled, WhenThrottled, SATURATION_WINDOW,
+};
+// Used by code generated with `jsonrpc_client!`, not part of the public API
+#[doc(hidden)]
+pub use transport::HttpTransport;
+pub use transport::{read_body, MAX_WARM_UP_THREADS, NOT_JSON_SNIPPET_LEN};
+
+pub use reqwest;
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::{self, TestServer};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn test_macro_expansion() {
@@ -126,4 +378,3313 @@ mod tests {
         // Test that the macro expands without errors
         let _client = TestClient::new("http://localhost:8332".to_string(), None, None, 0, 0, 0);
     }
+
+    #[test]
+    fn test_call_with_options() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn test_method(&self, arg: u64) -> Result<String>;
+            enum:
+        });
+
+        let client = TestClient::new("http://127.0.0.1:1".to_string(), None, None, 1, 0, 0);
+        let res = client
+            .with_options(CallOptions::new().priority(10))
+            .test_method(1);
+        assert!(matches!(res, Err(RpcError::HttpError(_))));
+        assert_eq!(client.transport.gate().in_flight(), 0);
+    }
+
+    #[test]
+    fn test_content_type() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn test_method(&self) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":"ok","error":null,"id":0}"#);
+        let client = TestClient::builder(&server.uri).build();
+        assert_eq!(client.test_method().unwrap(), "ok");
+        let client = TestClient::builder(&server.uri)
+            .content_type("text/plain")
+            .build();
+        assert_eq!(client.test_method().unwrap(), "ok");
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].header("content-type"),
+            Some(DEFAULT_CONTENT_TYPE)
+        );
+        assert_eq!(requests[1].header("content-type"), Some("text/plain"));
+        assert_eq!(requests[1].json()["method"], "test_method");
+    }
+
+    #[test]
+    fn test_accept() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn test_method(&self) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":"ok","error":null,"id":0}"#);
+        let client = TestClient::builder(&server.uri).build();
+        assert_eq!(client.test_method().unwrap(), "ok");
+        let client = TestClient::builder(&server.uri)
+            .accept("application/json-rpc")
+            .build();
+        assert_eq!(client.test_method().unwrap(), "ok");
+
+        let requests = server.requests();
+        assert_eq!(requests[0].header("accept"), Some(DEFAULT_ACCEPT));
+        assert_eq!(requests[1].header("accept"), Some("application/json-rpc"));
+    }
+
+    #[test]
+    fn test_notification_batch() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn setloglevel(&self, category: String, level: u32) -> Result<()>;
+            enum:
+        });
+
+        let server = TestServer::start(|_| test_util::Reply::json("").status(204));
+        let client = TestClient::builder(&server.uri).build();
+        let mut batch = client.notification_batch();
+        batch
+            .setloglevel("net".to_string(), 1)
+            .setloglevel("rpc".to_string(), 2);
+        assert_eq!(batch.len(), 2);
+        batch.send().unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].json(),
+            serde_json::json!([
+                {"method": "setloglevel", "params": ["net", 1]},
+                {"method": "setloglevel", "params": ["rpc", 2]},
+            ])
+        );
+
+        // An empty batch sends nothing
+        client.notification_batch().send().unwrap();
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_failover_uses_endpoint_credentials() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn test_method(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":7,"error":null,"id":0}"#);
+        let client = TestClient::builder("http://127.0.0.1:1")
+            .auth("user", Some("pass".to_string()))
+            .fallback(
+                Endpoint::new(&server.uri)
+                    .bearer_auth("token")
+                    .header("x-api-key", "key"),
+            )
+            .build();
+        assert_eq!(client.test_method().unwrap(), 7);
+        assert_eq!(client.transport.active_endpoint(), 1);
+        assert_eq!(client.test_method().unwrap(), 7);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        for request in requests {
+            assert_eq!(request.header("authorization"), Some("Bearer token"));
+            assert_eq!(request.header("x-api-key"), Some("key"));
+        }
+    }
+
+    #[test]
+    fn test_html_body_is_not_json() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn test_method(&self) -> Result<u64>;
+            enum:
+        });
+
+        let page = format!("<!DOCTYPE html><html>{}</html>", "x".repeat(1000));
+        let server = TestServer::start(move |_| test_util::Reply {
+            status: 200,
+            headers: vec![("Content-Type".into(), "text/html".into())],
+            body: page.as_bytes().to_vec(),
+            ..Default::default()
+        });
+        let client = TestClient::builder(&server.uri).build();
+        match client.test_method() {
+            Err(RpcError::NotJson {
+                content_type,
+                snippet,
+            }) => {
+                assert!(content_type.unwrap().contains("text/html"));
+                assert!(snippet.starts_with("<!DOCTYPE html>"));
+                assert!(snippet.len() < 300);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // JSON mislabeled as text/plain is still accepted
+        let server = TestServer::start(|_| test_util::Reply {
+            status: 200,
+            headers: vec![("Content-Type".into(), "text/plain".into())],
+            body: br#"{"result":1,"error":null,"id":0}"#.to_vec(),
+            ..Default::default()
+        });
+        let client = TestClient::builder(&server.uri).build();
+        assert_eq!(client.test_method().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_hex_numbers() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn eth_blocknumber(&self) -> Result<u64>;
+                pub fn eth_status(&self) -> Result<Status>;
+            enum:
+                pub fn eth_syncing(&self) -> Result<Syncing(Vec<u64>)|NotSyncing(bool)>;
+        });
+
+        #[derive(Deserialize, Debug, PartialEq)]
+        #[serde(rename_all = "lowercase")]
+        enum Status {
+            Syncing { current: u64, highest: u64 },
+        }
+
+        let server = TestServer::start(|req| {
+            test_util::Reply::json(match req.json()["method"].as_str().unwrap() {
+                "eth_blocknumber" => r#"{"result":"0x1b4","id":0}"#,
+                "eth_status" => {
+                    r#"{"result":{"syncing":{"current":"0x10","highest":"0x20"}},"id":0}"#
+                }
+                _ => r#"{"result":["0x1",2],"id":0}"#,
+            })
+        });
+        let client = TestClient::builder(&server.uri).build();
+        assert!(matches!(
+            client.eth_blocknumber(),
+            Err(RpcError::JsonError { .. })
+        ));
+
+        let client = TestClient::builder(&server.uri).hex_numbers(true).build();
+        assert_eq!(client.eth_blocknumber().unwrap(), 436);
+        assert_eq!(client.eth_syncing().unwrap().Syncing().unwrap(), vec![1, 2]);
+        assert_eq!(
+            client.eth_status().unwrap(),
+            Status::Syncing {
+                current: 16,
+                highest: 32
+            }
+        );
+    }
+
+    #[test]
+    fn test_health_check() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn test_method(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| match req.header("authorization") {
+            Some(_) => test_util::Reply::json(
+                r#"{"result":null,"error":{"code":-32601,"message":"Method not found"},"id":0}"#,
+            )
+            .status(404),
+            None => test_util::Reply::json("").status(401),
+        });
+        let client = TestClient::builder(&server.uri)
+            .auth("user", Some("pass".to_string()))
+            .health_check_method("web3_clientVersion")
+            .build();
+        client.health_check().unwrap();
+        assert_eq!(server.requests()[0].json()["method"], "web3_clientVersion");
+
+        let client = TestClient::builder(&server.uri).build();
+        assert!(matches!(client.health_check(), Err(RpcError::HttpError(_))));
+        let client = TestClient::builder("http://127.0.0.1:1").build();
+        assert!(matches!(client.health_check(), Err(RpcError::HttpError(_))));
+    }
+
+    #[test]
+    fn test_method_prefix() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getinfo(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":[1],"error":null,"id":0}"#);
+        let client = TestClient::builder(&server.uri)
+            .method_prefix("daemon.")
+            .build();
+        let _ = client.getinfo();
+        let mut notifications = client.notification_batch();
+        notifications.getinfo();
+        notifications.send().unwrap();
+
+        let client = TestClient::builder(&server.uri)
+            .method_mapper(|name| name.to_uppercase())
+            .build();
+        let _ = client.getinfo();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].json()["method"], "daemon.getinfo");
+        assert_eq!(requests[1].json()[0]["method"], "daemon.getinfo");
+        assert_eq!(requests[2].json()["method"], "GETINFO");
+    }
+
+    #[test]
+    fn test_empty_response() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn test_method(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json("");
+        let client = TestClient::builder(&server.uri).build();
+        assert!(matches!(client.test_method(), Err(RpcError::EmptyResponse)));
+        assert!(matches!(
+            client.batcher::<u64>().send(),
+            Ok(ref results) if results.is_empty()
+        ));
+        let mut batch = client.batcher::<u64>();
+        batch.test_method().unwrap();
+        assert!(matches!(batch.send(), Err(RpcError::EmptyResponse)));
+
+        // Notifications expect no body, so an empty one is a success
+        let mut notifications = client.notification_batch();
+        notifications.test_method();
+        notifications.send().unwrap();
+    }
+
+    #[test]
+    fn test_batch_iter() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let replies: Vec<_> = req
+                .json()
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|call| match call["params"][0].as_u64().unwrap() {
+                    3 => {
+                        serde_json::json!({"result": null, "error": {"code": -8}, "id": call["id"]})
+                    }
+                    h => serde_json::json!({"result": format!("hash{}", h), "id": call["id"]}),
+                })
+                .collect();
+            test_util::Reply::json(&serde_json::to_string(&replies).unwrap())
+        });
+        let client = TestClient::builder(&server.uri).max_batch_size(2).build();
+        let results: Vec<Result<String, RpcError>> = client
+            .batch_iter(0..5, 10, |batch, height| batch.getblockhash(height))
+            .collect();
+
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[0].as_ref().unwrap(), "hash0");
+        assert_eq!(results[2].as_ref().unwrap(), "hash2");
+        assert!(matches!(results[3], Err(RpcError::RpcError { .. })));
+        assert_eq!(results[4].as_ref().unwrap(), "hash4");
+        // The window is capped by max_batch_size
+        assert_eq!(server.requests().len(), 3);
+
+        // A failed window yields one error per item, keeping later results in place
+        let server = TestServer::start(|req| {
+            let calls = req.json();
+            let calls = calls.as_array().unwrap();
+            if calls[0]["params"][0] == 2 {
+                return test_util::Reply::json("<html>Bad Gateway</html>").status(502);
+            }
+            let replies: Vec<_> = calls
+                .iter()
+                .map(|call| {
+                    let h = call["params"][0].as_u64().unwrap();
+                    serde_json::json!({"result": format!("hash{}", h), "id": call["id"]})
+                })
+                .collect();
+            test_util::Reply::json(&serde_json::to_string(&replies).unwrap())
+        });
+        let client = TestClient::builder(&server.uri).build();
+        let results: Vec<Result<String, RpcError>> = client
+            .batch_iter(0..6, 2, |batch, height| batch.getblockhash(height))
+            .collect();
+        assert_eq!(results.len(), 6);
+        assert_eq!(results[1].as_ref().unwrap(), "hash1");
+        for result in &results[2..4] {
+            assert!(
+                matches!(result, Err(RpcError::BatchFailed(_))),
+                "{:?}",
+                result
+            );
+        }
+        assert_eq!(results[4].as_ref().unwrap(), "hash4");
+        assert_eq!(results[5].as_ref().unwrap(), "hash5");
+    }
+
+    #[test]
+    fn test_host_override() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn test_method(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let client = TestClient::builder(&server.uri)
+            .host_header("proxy.example")
+            .build();
+        client.test_method().unwrap();
+        let port = server.uri.rsplit(':').next().unwrap();
+        let client = TestClient::builder(&server.uri)
+            .tls_server_name("node.example")
+            .build();
+        client.test_method().unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].header("host"), Some("proxy.example"));
+        assert_eq!(
+            requests[1].header("host"),
+            Some(format!("node.example:{}", port).as_str())
+        );
+    }
+
+    #[test]
+    fn test_id_prefix_and_offset() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let body = req.json();
+            let reply = match body.as_array() {
+                // Answer out of order to exercise id correlation
+                Some(calls) => serde_json::Value::Array(
+                    calls
+                        .iter()
+                        .rev()
+                        .map(|call| {
+                            serde_json::json!({
+                                "result": format!("hash{}", call["params"][0]),
+                                "id": call["id"],
+                            })
+                        })
+                        .collect(),
+                ),
+                None => serde_json::json!({"result": "hash0", "id": body["id"]}),
+            };
+            test_util::Reply::json(&reply.to_string())
+        });
+        let client = TestClient::builder(&server.uri)
+            .id_offset(100)
+            .id_prefix("crawler-")
+            .build();
+        assert_eq!(client.getblockhash(0).unwrap(), "hash0");
+        let mut batch = client.batcher::<String>();
+        batch.getblockhash(1).unwrap();
+        batch.getblockhash(2).unwrap();
+        assert_eq!(batch.send().unwrap(), vec!["hash1", "hash2"]);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].json()["id"], "crawler-100");
+        let batch = requests[1].json();
+        assert_eq!(batch[0]["id"], "crawler-101");
+        assert_eq!(batch[1]["id"], "crawler-102");
+    }
+
+    #[test]
+    fn test_bom_prefixed_body() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn test_method(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::start(|_| test_util::Reply {
+            status: 200,
+            headers: vec![(
+                "Content-Type".into(),
+                "application/json; charset=utf-8".into(),
+            )],
+            body: "\u{feff}{\"result\":7,\"id\":0}".as_bytes().to_vec(),
+            ..Default::default()
+        });
+        let client = TestClient::builder(&server.uri).build();
+        assert_eq!(client.test_method().unwrap(), 7);
+    }
+
+    #[test]
+    fn test_compress_requests() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let body = req.json();
+            let reply = match body.as_array() {
+                Some(calls) => serde_json::Value::Array(
+                    calls
+                        .iter()
+                        .map(|call| serde_json::json!({"result": "hash", "id": call["id"]}))
+                        .collect(),
+                ),
+                None => serde_json::json!({"result": "hash", "id": body["id"]}),
+            };
+            test_util::Reply::json(&reply.to_string())
+        });
+        let send_batch = |client: &TestClient| {
+            let mut batch = client.batcher::<String>();
+            for height in 0..100 {
+                batch.getblockhash(height).unwrap();
+            }
+            assert_eq!(batch.send().unwrap().len(), 100);
+        };
+
+        // Compression is opt-in
+        let client = TestClient::builder(&server.uri)
+            .compression_threshold(0)
+            .build();
+        send_batch(&client);
+
+        let client = TestClient::builder(&server.uri)
+            .compress_requests(true)
+            .compression_threshold(1024)
+            .build();
+        client.getblockhash(0).unwrap();
+        send_batch(&client);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].header("content-encoding"), None);
+        // Small requests stay uncompressed
+        assert_eq!(requests[1].header("content-encoding"), None);
+        assert_eq!(requests[2].header("content-encoding"), Some("gzip"));
+        assert!(requests[2].body.len() < requests[0].body.len());
+        assert_eq!(requests[2].json().as_array().unwrap().len(), 100);
+    }
+
+    #[test]
+    fn test_call_metadata() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn test_method(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":null,"error":{"code":-1},"id":0}"#);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let client = TestClient::builder(&server.uri)
+            .on_request(move |event| {
+                seen.lock()
+                    .unwrap()
+                    .push((event.method.to_string(), event.metadata.to_vec()));
+            })
+            .build();
+        let err = client
+            .with_options(CallOptions::new().metadata("tenant", "acme"))
+            .test_method()
+            .unwrap_err();
+
+        let tag = vec![("tenant".to_string(), "acme".to_string())];
+        assert_eq!(err.metadata(), tag.as_slice());
+        assert!(matches!(err.untagged(), RpcError::RpcError { .. }));
+        assert!(err.to_string().ends_with("[tenant=acme]"));
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![("test_method".to_string(), tag)]
+        );
+
+        // Untagged calls keep their plain errors
+        assert!(matches!(
+            client.test_method(),
+            Err(RpcError::RpcError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_on_deserialize_error() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let body = req.json();
+            let reply = match body.as_array() {
+                Some(calls) => serde_json::json!([
+                    {"result": "hash0", "id": calls[0]["id"]},
+                    {"result": 7, "id": calls[1]["id"]},
+                ]),
+                None => serde_json::json!({"result": "many", "id": body["id"]}),
+            };
+            test_util::Reply::json(&reply.to_string())
+        });
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let seen = failures.clone();
+        let client = TestClient::builder(&server.uri)
+            .on_deserialize_error(move |method, body, error| {
+                assert!(matches!(error, RpcError::JsonError { .. }));
+                seen.lock()
+                    .unwrap()
+                    .push((method.to_string(), body.to_string()));
+            })
+            .build();
+
+        assert!(matches!(
+            client.getblockcount(),
+            Err(RpcError::JsonError { .. })
+        ));
+        let mut batch = client.batcher::<String>();
+        batch.getblockhash(0).unwrap();
+        batch.getblockhash(1).unwrap();
+        let results = batch.send_each().unwrap();
+        assert_eq!(results[0].as_ref().unwrap(), "hash0");
+        assert!(results[1].is_err());
+
+        let failures = failures.lock().unwrap();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].0, "getblockcount");
+        assert!(failures[0].1.contains("many"));
+        assert_eq!(failures[1].0, "getblockhash");
+    }
+
+    #[test]
+    fn test_auto_batch_and_flush() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let replies: Vec<_> = req
+                .json()
+                .as_array()
+                .unwrap()
+                .iter()
+                .rev()
+                .map(|call| serde_json::json!({"result": format!("hash{}", call["params"][0]), "id": call["id"]}))
+                .collect();
+            test_util::Reply::json(&serde_json::to_string(&replies).unwrap())
+        });
+
+        // Calls issued within the window share one request
+        let client = TestClient::builder(&server.uri)
+            .batch_window(Duration::from_millis(300))
+            .build();
+        std::thread::scope(|s| {
+            let calls: Vec<_> = (0..3)
+                .map(|h| {
+                    let client = &client;
+                    s.spawn(move || client.getblockhash(h).unwrap())
+                })
+                .collect();
+            for (h, call) in calls.into_iter().enumerate() {
+                assert_eq!(call.join().unwrap(), format!("hash{}", h));
+            }
+        });
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].json().as_array().unwrap().len(), 3);
+
+        // flush_batch sends the queue without waiting out the window
+        let client = TestClient::builder(&server.uri)
+            .batch_window(Duration::from_secs(30))
+            .build();
+        let started = Instant::now();
+        std::thread::scope(|s| {
+            let call = s.spawn(|| client.getblockhash(7));
+            std::thread::sleep(Duration::from_millis(100));
+            client.flush_batch().unwrap();
+            assert_eq!(call.join().unwrap().unwrap(), "hash7");
+        });
+        assert!(started.elapsed() < Duration::from_secs(10));
+        // Nothing queued: flushing is a no-op
+        client.flush_batch().unwrap();
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn test_auto_batch_with_concurrency_limit_stress() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            std::thread::sleep(Duration::from_millis(2));
+            let replies: Vec<_> = req
+                .json()
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|call| {
+                    serde_json::json!({"result": format!("hash{}", call["params"][0]), "id": call["id"]})
+                })
+                .collect();
+            test_util::Reply::json(&serde_json::to_string(&replies).unwrap())
+        });
+        let client = TestClient::builder(&server.uri)
+            .max_concurrency(1)
+            .max_batch_size(4)
+            .batch_window(Duration::from_millis(5))
+            .build();
+
+        let (done, finished) = std::sync::mpsc::channel();
+        let worker = client.clone();
+        std::thread::spawn(move || {
+            std::thread::scope(|s| {
+                for t in 0..16u64 {
+                    let client = &worker;
+                    s.spawn(move || {
+                        for i in 0..10 {
+                            let height = t * 100 + i;
+                            let hash = client.getblockhash(height).unwrap();
+                            assert_eq!(hash, format!("hash{}", height));
+                        }
+                    });
+                }
+            });
+            done.send(()).unwrap();
+        });
+        finished
+            .recv_timeout(Duration::from_secs(60))
+            .expect("batching and the concurrency gate deadlocked");
+        assert_eq!(client.transport.gate().in_flight(), 0);
+        let sent: usize = server
+            .requests()
+            .iter()
+            .map(|r| r.json().as_array().unwrap().len())
+            .sum();
+        assert_eq!(sent, 160);
+    }
+
+    #[test]
+    fn test_error_data() {
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct Revert {
+            reason: String,
+        }
+
+        let err = RpcError::RpcError {
+            error: serde_json::json!({"code": 3, "data": {"reason": "out of gas"}}),
+        };
+        assert_eq!(
+            err.data::<Revert>().unwrap(),
+            Some(Revert {
+                reason: "out of gas".to_string()
+            })
+        );
+        assert!(err.data::<u64>().is_err());
+
+        let err = RpcError::RpcError {
+            error: serde_json::json!({"code": -1, "message": "oops"}),
+        };
+        assert_eq!(err.data::<Revert>().unwrap(), None);
+        assert_eq!(RpcError::NullResponse.data::<Revert>().unwrap(), None);
+    }
+
+    #[test]
+    fn test_throttle_config() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn test_method(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::start(|_| {
+            std::thread::sleep(Duration::from_millis(500));
+            test_util::Reply::json(r#"{"result":1,"id":0}"#)
+        });
+        let throttle = ThrottleConfig {
+            max_concurrency: 2,
+            rps: 50,
+            timeout: Some(Duration::from_millis(100)),
+            ..ThrottleConfig::default()
+        };
+        let client = TestClient::with_throttle(&server.uri, throttle.clone());
+        let other = TestClient::builder(&server.uri)
+            .throttle(throttle.clone())
+            .build();
+        assert_eq!(client.config().throttle, throttle);
+        assert_eq!(other.config().throttle, throttle);
+        assert_eq!(client.transport.gate().max_concurrency(), 2);
+        match client.test_method() {
+            Err(RpcError::HttpError(e)) => assert!(e.is_timeout()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_closed_connections() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn test_method(&self) -> Result<u64>;
+            enum:
+        });
+
+        // Connection 0 answers with `Connection: close`, connection 1 is closed by the
+        // server shortly after answering, and connection 2 answers one request, then
+        // reads the next one and closes without answering it
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        let received = Arc::new(AtomicUsize::new(0));
+        let seen = received.clone();
+        std::thread::spawn(move || {
+            for (n, stream) in listener.incoming().enumerate() {
+                let stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut stream = stream;
+                let mut answered = 0;
+                loop {
+                    let mut len = 0;
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                            break;
+                        }
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                        if let Some(v) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                            len = v.trim().parse().unwrap();
+                        }
+                    }
+                    let mut body = vec![0; len];
+                    if len == 0 || std::io::Read::read_exact(&mut reader, &mut body).is_err() {
+                        break;
+                    }
+                    seen.fetch_add(1, Ordering::SeqCst);
+                    if n == 2 && answered == 1 {
+                        break;
+                    }
+                    let reply = r#"{"result":1,"id":0}"#;
+                    let close = if n == 0 { "Connection: close\r\n" } else { "" };
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n{}Content-Length: {}\r\n\r\n{}",
+                        close,
+                        reply.len(),
+                        reply
+                    )
+                    .unwrap();
+                    answered += 1;
+                    if n < 2 {
+                        std::thread::sleep(Duration::from_millis(50));
+                        break;
+                    }
+                }
+            }
+        });
+
+        // A connection the server asked to close, or closed while idle, is not reused
+        let client = TestClient::builder(&uri).build();
+        assert_eq!(client.test_method().unwrap(), 1);
+        assert_eq!(client.test_method().unwrap(), 1);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(client.test_method().unwrap(), 1);
+
+        // A request the server has read may have been executed, so it is not resent
+        assert!(matches!(client.test_method(), Err(RpcError::HttpError(_))));
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(received.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_newtype_params_and_results() {
+        #[derive(serde::Serialize, Clone, Copy)]
+        pub struct BlockHeight(u64);
+
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        pub struct BlockHash(String);
+
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: BlockHeight) -> Result<BlockHash>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let body = req.json();
+            let reply = match body.as_array() {
+                Some(calls) => serde_json::Value::Array(
+                    calls
+                        .iter()
+                        .map(|call| serde_json::json!({"result": "00ab", "id": call["id"]}))
+                        .collect(),
+                ),
+                None => serde_json::json!({"result": "00ab", "id": body["id"]}),
+            };
+            test_util::Reply::json(&reply.to_string())
+        });
+        let client = TestClient::builder(&server.uri).build();
+        assert_eq!(
+            client.getblockhash(BlockHeight(5)).unwrap(),
+            BlockHash("00ab".to_string())
+        );
+        let mut batch = client.batcher::<BlockHash>();
+        batch.getblockhash(BlockHeight(6)).unwrap();
+        assert_eq!(batch.send().unwrap(), vec![BlockHash("00ab".to_string())]);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].json()["params"], serde_json::json!([5]));
+        assert_eq!(requests[1].json()[0]["params"], serde_json::json!([6]));
+    }
+
+    #[test]
+    fn test_vec_param_is_nested() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn createmultisig(&self, nrequired: u32, keys: Vec<String>) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":"2N","id":0}"#);
+        let client = TestClient::builder(&server.uri).build();
+        client
+            .createmultisig(2, vec!["k1".to_string(), "k2".to_string()])
+            .unwrap();
+        client.createmultisig(1, Vec::new()).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].json()["params"],
+            serde_json::json!([2, ["k1", "k2"]])
+        );
+        // An empty list is still sent, unlike a trailing null
+        assert_eq!(requests[1].json()["params"], serde_json::json!([1, []]));
+    }
+
+    #[test]
+    fn test_named_params() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getnewaddress(&self, label: Option<String>, address_type: Option<String>) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":"addr","id":0}"#);
+        let client = TestClient::builder(&server.uri)
+            .param_style(ParamStyle::Named)
+            .build();
+        client
+            .getnewaddress(None, Some("bech32".to_string()))
+            .unwrap();
+        let client = TestClient::builder(&server.uri)
+            .param_style(ParamStyle::Named)
+            .omit_null_params(false)
+            .build();
+        client
+            .getnewaddress(None, Some("bech32".to_string()))
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].json()["params"],
+            serde_json::json!({"address_type": "bech32"})
+        );
+        assert_eq!(
+            requests[1].json()["params"],
+            serde_json::json!({"label": null, "address_type": "bech32"})
+        );
+    }
+
+    #[test]
+    fn test_calibrate() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let count = std::sync::atomic::AtomicUsize::new(0);
+        let server = TestServer::start(move |_| {
+            match count.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                1 => test_util::Reply::json("{}").status(429),
+                3 => test_util::Reply::json(r#"{"result":null,"error":{"code":-1},"id":0}"#),
+                _ => test_util::Reply::json(r#"{"result":1,"id":0}"#),
+            }
+        });
+        let client = TestClient::builder(&server.uri).build();
+        let report = client.calibrate("getblockcount", 6);
+        assert_eq!(report.requests, 6);
+        assert_eq!(report.errors, 2);
+        assert_eq!(report.rate_limited, 1);
+        assert!(!report.stopped_early);
+        assert!(report.achieved_rps() > 0.0);
+
+        // A failing server stops the run early
+        let server = TestServer::start(|_| test_util::Reply::json("{}").status(500));
+        let client = TestClient::builder(&server.uri).build();
+        let report = client.calibrate("getblockcount", 100);
+        assert_eq!(report.requests, CALIBRATION_ERROR_LIMIT);
+        assert!(report.stopped_early);
+        assert_eq!(report.error_rate(), 1.0);
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_named_pipe_unsupported() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let client = TestClient::builder(r"\\.\pipe\pivxd").build();
+        assert!(matches!(
+            client.getblockcount(),
+            Err(RpcError::UnsupportedTransport(_))
+        ));
+    }
+
+    #[test]
+    fn test_retry_if() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let count = std::sync::atomic::AtomicUsize::new(0);
+        let server = TestServer::start(move |_| {
+            match count.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 | 1 => test_util::Reply::json(
+                    r#"{"result":null,"error":{"code":-32000,"message":"busy"},"id":0}"#,
+                ),
+                _ => test_util::Reply::json(r#"{"result":7,"id":0}"#),
+            }
+        });
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let seen = attempts.clone();
+        let client = TestClient::builder(&server.uri)
+            .max_retries(3)
+            .retry_backoff(Duration::from_millis(40))
+            .retry_if(move |e, attempt| {
+                seen.lock().unwrap().push(attempt);
+                matches!(e, RpcError::RpcError { error } if error["code"] == -32000)
+            })
+            .build();
+        let start = Instant::now();
+        assert_eq!(client.getblockcount().unwrap(), 7);
+        assert_eq!(*attempts.lock().unwrap(), vec![1, 2]);
+        assert_eq!(server.requests().len(), 3);
+        // 40ms before the first retry, 80ms before the second
+        assert!(start.elapsed() >= Duration::from_millis(120));
+
+        // Without max_retries the predicate is never consulted
+        let server = TestServer::start(|_| {
+            test_util::Reply::json(r#"{"result":null,"error":{"code":-32000},"id":0}"#)
+        });
+        let client = TestClient::builder(&server.uri)
+            .retry_if(|_, _| true)
+            .build();
+        assert!(matches!(
+            client.getblockcount(),
+            Err(RpcError::RpcError { .. })
+        ));
+        assert_eq!(server.requests().len(), 1);
+
+        // Retries are bounded by max_retries
+        let client = TestClient::builder(&server.uri)
+            .max_retries(2)
+            .retry_if(|_, _| true)
+            .build();
+        assert!(client.getblockcount().is_err());
+        assert_eq!(server.requests().len(), 4);
+
+        // Cancelling during a long backoff ends the call without another attempt
+        let client = TestClient::builder(&server.uri)
+            .max_retries(2)
+            .retry_backoff(Duration::from_secs(10))
+            .retry_if(|_, _| true)
+            .build();
+        let token = CancelToken::new();
+        let start = Instant::now();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(Duration::from_millis(100));
+                token.cancel();
+            });
+            let result = client
+                .with_options(CallOptions::new().cancel_token(token.clone()))
+                .getblockcount();
+            assert!(matches!(result, Err(RpcError::Cancelled)));
+        });
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(server.requests().len(), 5);
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn test_metrics_text() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let count = std::sync::atomic::AtomicUsize::new(0);
+        let server = TestServer::start(move |_| {
+            match count.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => test_util::Reply::json(r#"{"result":1,"id":0}"#),
+                1 => test_util::Reply::json(r#"{"result":[1,{"error":2}],"error":null,"id":0}"#),
+                _ => test_util::Reply::json(r#"{"result":null,"error":{"code":-1},"id":0}"#),
+            }
+        });
+        let client = TestClient::builder(&server.uri).build();
+        client.getblockcount().unwrap();
+        client
+            .send_raw(serde_json::json!({"method": "getblockcount"}))
+            .unwrap();
+        client.getblockcount().unwrap_err();
+
+        let text = client.metrics_text();
+        assert!(text.contains("jsonrpc_requests_total{method=\"getblockcount\"} 3\n"));
+        assert!(text.contains("jsonrpc_errors_total{method=\"getblockcount\",kind=\"rpc\"} 1\n"));
+        assert!(text.contains(
+            "jsonrpc_request_duration_seconds_bucket{method=\"getblockcount\",le=\"+Inf\"} 3\n"
+        ));
+    }
+
+    #[test]
+    fn test_send_raw() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::start(|_| {
+            test_util::Reply::json(r#"{"result":null,"error":{"code":-5},"id":"custom"}"#)
+        });
+        let client = TestClient::builder(&server.uri)
+            .auth("user", Some("pass".to_string()))
+            .build();
+        let request = serde_json::json!({
+            "jsonrpc": "1.0",
+            "method": "getblockcount",
+            "id": "custom",
+            "x-extra": true,
+        });
+        let reply = client.send_raw(request.clone()).unwrap();
+        assert_eq!(reply["error"]["code"], -5);
+
+        let recorded = &server.requests()[0];
+        assert_eq!(recorded.json(), request);
+        assert!(recorded.header("authorization").is_some());
+    }
+
+    #[test]
+    fn test_batch_collect() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let reqs = req.json();
+            let replies: Vec<_> = reqs
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|r| match r["params"][0].as_u64().unwrap() {
+                    3 => serde_json::json!({"result": null, "error": {"code": -8}, "id": r["id"]}),
+                    h => serde_json::json!({"result": format!("hash{}", h), "id": r["id"]}),
+                })
+                .rev()
+                .collect();
+            test_util::Reply::json(&serde_json::Value::Array(replies).to_string())
+        });
+        let client = TestClient::builder(&server.uri).max_batch_size(2).build();
+        let mut batch = client.batcher::<String>();
+        for height in 0..5 {
+            batch.getblockhash(height).unwrap();
+        }
+        let results = batch.collect().unwrap();
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[0].as_deref().unwrap(), "hash0");
+        assert_eq!(results[2].as_deref().unwrap(), "hash2");
+        assert!(matches!(results[3], Err(RpcError::RpcError { .. })));
+        assert_eq!(results[4].as_deref().unwrap(), "hash4");
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_when_throttled_reject() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let client = TestClient::builder(&server.uri)
+            .rps(5)
+            .when_throttled(WhenThrottled::Reject)
+            .build();
+        std::thread::sleep(Duration::from_millis(210));
+        assert_eq!(client.getblockcount().unwrap(), 1);
+        assert!(matches!(
+            client.getblockcount(),
+            Err(RpcError::RateLimited { .. })
+        ));
+        assert_eq!(server.requests().len(), 1);
+
+        // A single call can still choose to wait
+        let start = Instant::now();
+        let count = client
+            .with_options(CallOptions::new().when_throttled(WhenThrottled::Block))
+            .getblockcount()
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_warm_up() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let in_flight = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let peak = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (current, max) = (in_flight.clone(), peak.clone());
+        let server = TestServer::start(move |_| {
+            use std::sync::atomic::Ordering::SeqCst;
+            max.fetch_max(current.fetch_add(1, SeqCst) + 1, SeqCst);
+            std::thread::sleep(Duration::from_millis(50));
+            current.fetch_sub(1, SeqCst);
+            test_util::Reply::json(r#"{"result":1,"id":0}"#)
+        });
+        let client = TestClient::builder(&server.uri).max_concurrency(2).build();
+        client.warm_up(4).unwrap();
+        assert_eq!(server.requests().len(), 4);
+        assert_eq!(peak.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        // More pings than threads: each thread sends several in turn
+        client.warm_up(MAX_WARM_UP_THREADS + 3).unwrap();
+        assert_eq!(server.requests().len(), 4 + MAX_WARM_UP_THREADS + 3);
+        assert_eq!(peak.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        let server = TestServer::start(|_| test_util::Reply::json("{}").status(401));
+        let client = TestClient::builder(&server.uri).build();
+        assert!(matches!(client.warm_up(2), Err(RpcError::HttpError(_))));
+    }
+
+    #[test]
+    fn test_immediate_call_skips_auto_batch() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let body = req.json();
+            let reply = match body.as_array() {
+                Some(calls) => serde_json::Value::Array(
+                    calls
+                        .iter()
+                        .map(|call| serde_json::json!({"result": 2, "id": call["id"]}))
+                        .collect(),
+                ),
+                None => serde_json::json!({"result": 1, "id": body["id"]}),
+            };
+            test_util::Reply::json(&reply.to_string())
+        });
+        let client = TestClient::builder(&server.uri)
+            .rps(20)
+            .batch_window(Duration::from_secs(5))
+            .build();
+
+        std::thread::scope(|s| {
+            let batched = s.spawn(|| client.getblockcount());
+            std::thread::sleep(Duration::from_millis(50));
+
+            let start = Instant::now();
+            let count = client
+                .with_options(CallOptions::new().immediate())
+                .getblockcount()
+                .unwrap();
+            assert_eq!(count, 1);
+            assert!(start.elapsed() < Duration::from_secs(1));
+
+            client.flush_batch().unwrap();
+            assert_eq!(batched.join().unwrap().unwrap(), 2);
+        });
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].json().is_object());
+        assert!(requests[1].json().is_array());
+    }
+
+    #[test]
+    fn test_single_call_with_max_batch_size() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let body = req.json();
+            let reply = match body.as_array() {
+                Some(calls) => serde_json::Value::Array(
+                    calls
+                        .iter()
+                        .map(|call| serde_json::json!({"result": 2, "id": call["id"]}))
+                        .collect(),
+                ),
+                None => serde_json::json!({"result": 1, "id": body["id"]}),
+            };
+            test_util::Reply::json(&reply.to_string())
+        });
+
+        // Without a batch window a direct call is sent on its own right away
+        let client = TestClient::builder(&server.uri).max_batch_size(10).build();
+        let start = Instant::now();
+        assert_eq!(client.getblockcount().unwrap(), 1);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(server.requests()[0].json().is_object());
+
+        // With one, a lone call goes out as a batch of one once the window elapses
+        let client = TestClient::builder(&server.uri)
+            .max_batch_size(10)
+            .batch_window(Duration::from_millis(100))
+            .build();
+        let start = Instant::now();
+        assert_eq!(client.getblockcount().unwrap(), 2);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(server.requests()[1].json().as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_from_dsn() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let dsn = server.uri.replace("http://", "http://user:pass@") + "?rps=100&concurrency=2";
+        let client = TestClient::from_dsn(&dsn).unwrap();
+        assert_eq!(client.config().throttle.max_concurrency, 2);
+        assert_eq!(client.getblockcount().unwrap(), 1);
+        assert!(server.requests()[0].header("authorization").is_some());
+
+        let builder: ClientBuilder<TestClient> = dsn.parse().unwrap();
+        assert_eq!(builder.rps(5).build().config().throttle.rps, 5);
+
+        assert!(matches!(
+            TestClient::from_dsn("http://localhost?bogus=1"),
+            Err(DsnError::UnknownParam(_))
+        ));
+    }
+
+    #[test]
+    fn test_struct_style_enum_variants() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+            enum:
+                pub fn submit(&self) -> Result<Ok { txid: String } | Err { code: i64, message: String } | Raw(String)>;
+        });
+
+        let server = TestServer::json(r#"{"result":{"txid":"ab12"},"id":0}"#);
+        let client = TestClient::builder(&server.uri).build();
+        match client.submit().unwrap() {
+            reply::submit::Ok { txid } => assert_eq!(txid, "ab12"),
+            other => panic!("unexpected variant {:?}", other),
+        }
+
+        let server = TestServer::json(r#"{"result":{"code":-26,"message":"rejected"},"id":0}"#);
+        let client = TestClient::builder(&server.uri).build();
+        let (code, message) = client.submit().unwrap().Err().unwrap();
+        assert_eq!((code, message.as_str()), (-26, "rejected"));
+
+        let server = TestServer::json(r#"{"result":"0100","id":0}"#);
+        let client = TestClient::builder(&server.uri).build();
+        let reply = client.submit().unwrap();
+        assert!(matches!(reply, reply::submit::Raw(ref hex) if hex == "0100"));
+        assert!(matches!(
+            reply.Ok(),
+            Err(RpcError::WrongVariant { expected: "Ok", .. })
+        ));
+    }
+
+    #[test]
+    fn test_cache_hit_status() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let body = req.json();
+            let reply = match body["method"].as_str().unwrap() {
+                "getblockhash" => serde_json::json!({"result": "ab", "id": body["id"]}),
+                _ => serde_json::json!({"result": 5, "id": body["id"]}),
+            };
+            test_util::Reply::json(&reply.to_string())
+        });
+        let client = TestClient::builder(&server.uri)
+            .cache_method("getblockhash", Duration::from_secs(60))
+            .build();
+        assert!(!client.last_was_cache_hit());
+
+        assert_eq!(client.getblockhash(1).unwrap(), "ab");
+        assert!(!client.last_was_cache_hit());
+        assert_eq!(client.getblockhash(1).unwrap(), "ab");
+        assert!(client.last_was_cache_hit());
+        assert_eq!(server.requests().len(), 1);
+
+        // Other arguments and uncached methods go to the server
+        client.getblockhash(2).unwrap();
+        assert!(!client.last_was_cache_hit());
+        client.getblockhash(1).unwrap();
+        client.getblockcount().unwrap();
+        assert!(!client.last_was_cache_hit());
+        assert_eq!(server.requests().len(), 3);
+
+        // The status is tracked per thread
+        std::thread::scope(|s| {
+            s.spawn(|| assert!(!client.last_was_cache_hit()));
+        });
+    }
+
+    #[test]
+    fn test_static_resolve() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn test_method(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let port = server.uri.rsplit(':').next().unwrap();
+        let client = TestClient::builder(format!("http://node.internal:{}", port))
+            .resolve("node.internal", "127.0.0.1:0".parse().unwrap())
+            .build();
+        assert_eq!(client.test_method().unwrap(), 1);
+        assert_eq!(
+            server.requests()[0].header("host"),
+            Some(format!("node.internal:{}", port).as_str())
+        );
+    }
+
+    #[test]
+    fn test_shared_throttle() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let throttle = Arc::new(Throttle::new(&ThrottleConfig {
+            rps: 10,
+            ..ThrottleConfig::default()
+        }));
+        let a = TestClient::builder(&server.uri)
+            .shared_throttle(throttle.clone())
+            .build();
+        let b = TestClient::builder(&server.uri)
+            .shared_throttle(a.throttle().clone())
+            .build();
+        assert!(Arc::ptr_eq(a.throttle(), b.throttle()));
+
+        // Four requests across both clients are spaced 100ms apart
+        let start = Instant::now();
+        for _ in 0..2 {
+            a.getblockcount().unwrap();
+            b.getblockcount().unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(290));
+
+        // Callers outside the clients are held to the same limit
+        assert!(throttle.try_acquire().is_err());
+    }
+
+    #[test]
+    fn test_stream_array() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn listunspent(&self, minconf: u64) -> Result<Vec<serde_json::Value>>;
+            enum:
+        });
+
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct Unspent {
+            txid: String,
+            vout: u32,
+        }
+
+        let server = TestServer::json(
+            r#"{"result":[{"txid":"aa","vout":0},{"txid":"bb","vout":1}],"error":null,"id":0}"#,
+        );
+        let client = TestClient::builder(&server.uri).build();
+        let items: Vec<Unspent> = client
+            .stream_array("listunspent", (1,))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            items,
+            vec![
+                Unspent {
+                    txid: "aa".to_string(),
+                    vout: 0
+                },
+                Unspent {
+                    txid: "bb".to_string(),
+                    vout: 1
+                },
+            ]
+        );
+        let request = server.requests()[0].json();
+        assert_eq!(request["method"], "listunspent");
+        assert_eq!(request["params"], serde_json::json!([1]));
+
+        let server = TestServer::json(r#"{"result":null,"error":{"code":-4},"id":0}"#);
+        let client = TestClient::builder(&server.uri).build();
+        assert!(matches!(
+            client.stream_array::<Unspent, _>("listunspent", ()),
+            Err(RpcError::RpcError { .. })
+        ));
+
+        // The response is checked as for other calls before streaming starts
+        let server = TestServer::start(|_| {
+            test_util::Reply::json("<html>Bad Gateway</html>")
+                .header("Content-Type", "text/html")
+                .status(502)
+        });
+        let client = TestClient::builder(&server.uri).build();
+        match client.stream_array::<Unspent, _>("listunspent", ()) {
+            Err(RpcError::HttpError(e)) => {
+                assert_eq!(e.status(), Some(reqwest::StatusCode::BAD_GATEWAY))
+            }
+            other => panic!("expected HttpError, got {:?}", other.err()),
+        }
+        let server = TestServer::start(|_| {
+            test_util::Reply::json(r#"{"result":null,"error":{"code":-28},"id":0}"#).status(500)
+        });
+        let client = TestClient::builder(&server.uri).build();
+        let error = client
+            .stream_array::<Unspent, _>("listunspent", ())
+            .err()
+            .unwrap();
+        assert_eq!(error.error_object().unwrap().code, -28);
+        let server = TestServer::start(|_| test_util::Reply {
+            status: 200,
+            headers: vec![("Content-Type".into(), "text/plain".into())],
+            body: b"Service Unavailable".to_vec(),
+            ..Default::default()
+        });
+        let client = TestClient::builder(&server.uri).build();
+        assert!(matches!(
+            client.stream_array::<Unspent, _>("listunspent", ()),
+            Err(RpcError::NotJson { .. })
+        ));
+        let server = TestServer::json("");
+        let client = TestClient::builder(&server.uri).build();
+        assert!(matches!(
+            client.stream_array::<Unspent, _>("listunspent", ()),
+            Err(RpcError::EmptyResponse)
+        ));
+
+        // The stream holds its concurrency slot until dropped
+        let server = TestServer::json(r#"{"result":[1,2],"id":0}"#);
+        let client = TestClient::builder(&server.uri).max_concurrency(2).build();
+        let stream = client.stream_array::<u64, _>("listunspent", ()).unwrap();
+        assert_eq!(client.transport.gate().in_flight(), 1);
+        drop(stream);
+        assert_eq!(client.transport.gate().in_flight(), 0);
+    }
+
+    #[test]
+    fn test_auth_signer() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let client = TestClient::builder(&server.uri)
+            .auth_signer(|req| {
+                // Stand-in for an HMAC: the body length and the last URL character
+                let signature = format!("{}-{}", req.body.len(), req.url.chars().last().unwrap());
+                req.headers
+                    .insert("x-signature", signature.parse().unwrap());
+            })
+            .build();
+        client.getblockcount().unwrap();
+
+        let recorded = &server.requests()[0];
+        let expected = format!(
+            "{}-{}",
+            recorded.body.len(),
+            server.uri.chars().last().unwrap()
+        );
+        assert_eq!(recorded.header("x-signature"), Some(expected.as_str()));
+    }
+
+    #[test]
+    #[cfg(feature = "http2")]
+    fn test_http_version() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let client = TestClient::builder(&server.uri)
+            .http_version(HttpVersion::Http1Only)
+            .build();
+        assert_eq!(client.getblockcount().unwrap(), 1);
+
+        // The HTTP/1.1-only test server sees the HTTP/2 connection preface
+        let client = TestClient::builder(&server.uri)
+            .http2_prior_knowledge(true)
+            .timeout(Duration::from_secs(5))
+            .build();
+        assert!(client.getblockcount().is_err());
+        assert_eq!(server.requests()[1].method, "PRI");
+    }
+
+    #[test]
+    fn test_error_object() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblock(&self, hash: &str) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":null,"error":"Block not found","id":0}"#);
+        let client = TestClient::builder(&server.uri).build();
+        let error = client.getblock("00").unwrap_err().error_object().unwrap();
+        assert_eq!(error.code, JsonRpcErrorObject::STRING_ERROR_CODE);
+        assert!(error.synthesized_code);
+        assert_eq!(error.message, "Block not found");
+        assert_eq!(error.data, None);
+
+        // A server's own -32000 is not marked as synthesized
+        let server = TestServer::json(
+            r#"{"result":null,"error":{"code":-32000,"message":"already in mempool"},"id":0}"#,
+        );
+        let client = TestClient::builder(&server.uri).build();
+        let error = client.getblock("00").unwrap_err().error_object().unwrap();
+        assert_eq!(error.code, -32000);
+        assert!(!error.synthesized_code);
+
+        let server = TestServer::json(
+            r#"{"result":null,"error":{"code":-5,"message":"Invalid hash","data":[1]},"id":0}"#,
+        );
+        let client = TestClient::builder(&server.uri).build();
+        let error = client.getblock("00").unwrap_err().error_object().unwrap();
+        assert_eq!(
+            error,
+            JsonRpcErrorObject {
+                code: -5,
+                message: "Invalid hash".to_string(),
+                data: Some(serde_json::json!([1])),
+                synthesized_code: false,
+            }
+        );
+
+        assert_eq!(RpcError::NullResponse.error_object(), None);
+    }
+
+    #[test]
+    fn test_bare_result() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getheight(&self) -> Result<u64>;
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json("42");
+        let client = TestClient::builder(&server.uri)
+            .bare_result("getheight")
+            .build();
+        assert_eq!(client.getheight().unwrap(), 42);
+        assert!(matches!(
+            client.getblockcount(),
+            Err(RpcError::JsonError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_call_with_id() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let reply =
+                |call: &serde_json::Value| serde_json::json!({"result": 1, "id": call["id"]});
+            let reply = match req.json() {
+                serde_json::Value::Array(calls) => calls.iter().map(reply).collect(),
+                call => reply(&call),
+            };
+            test_util::Reply::json(&reply.to_string())
+        });
+        let client = TestClient::builder(&server.uri)
+            .batch_window(Duration::from_millis(50))
+            .build();
+        let options = CallOptions::new().id("replay-7");
+        assert_eq!(client.with_options(options).getblockcount().unwrap(), 1);
+        assert_eq!(client.getblockcount().unwrap(), 1);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].json()["id"], "replay-7");
+        // The explicit id skips the automatic batch and leaves the sequence untouched
+        assert!(requests[0].json().is_object());
+        assert_eq!(requests[1].json()[0]["id"], 0);
+    }
+
+    #[test]
+    fn test_pending_batch() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+                pub fn getbestblockhash(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"[{"result":1,"id":0},{"result":2,"id":1}]"#);
+        let client = TestClient::builder(&server.uri)
+            .batch_window(Duration::from_secs(10))
+            .build();
+        assert_eq!(client.pending_batch_len(), 0);
+
+        std::thread::scope(|s| {
+            let first = s.spawn(|| client.getblockcount());
+            while client.pending_batch_len() < 1 {
+                std::thread::yield_now();
+            }
+            let second = s.spawn(|| client.getbestblockhash());
+            while client.pending_batch_len() < 2 {
+                std::thread::yield_now();
+            }
+            assert_eq!(
+                client.pending_batch_methods(),
+                vec!["getblockcount", "getbestblockhash"]
+            );
+            client.flush_batch().unwrap();
+            assert_eq!(client.pending_batch_len(), 0);
+            assert_eq!(first.join().unwrap().unwrap(), 1);
+            assert_eq!(second.join().unwrap().unwrap(), 2);
+        });
+    }
+
+    #[test]
+    fn test_wire_name_override() {
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct Block {
+            height: u64,
+        }
+
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblock_hex(&self, hash: &str, verbosity: u8) -> Result<String> as "getblock";
+                pub fn getblock_verbose(&self, hash: &str, verbosity: u8) -> Result<Block> as "getblock";
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            test_util::Reply::json(match req.json()["params"][1].as_u64().unwrap() {
+                0 => r#"{"result":"00ff","id":0}"#,
+                _ => r#"{"result":{"height":7},"id":0}"#,
+            })
+        });
+        let client = TestClient::builder(&server.uri).build();
+        assert_eq!(client.getblock_hex("aa", 0).unwrap(), "00ff");
+        assert_eq!(
+            client.getblock_verbose("aa", 1).unwrap(),
+            Block { height: 7 }
+        );
+        for req in server.requests() {
+            assert_eq!(req.json()["method"], "getblock");
+        }
+    }
+
+    #[test]
+    fn test_auto_batch_errors() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+            enum:
+        });
+
+        fn run(server: &TestServer) -> Vec<Result<String, RpcError>> {
+            let client = TestClient::builder(&server.uri)
+                .batch_window(Duration::from_millis(200))
+                .build();
+            std::thread::scope(|s| {
+                let calls: Vec<_> = (0..3)
+                    .map(|h| {
+                        let client = &client;
+                        s.spawn(move || client.getblockhash(h))
+                    })
+                    .collect();
+                calls.into_iter().map(|c| c.join().unwrap()).collect()
+            })
+        }
+
+        // The whole batch failing: every call gets the actual error, with the status
+        let server = TestServer::start(|_| {
+            test_util::Reply::json("<html>Internal Server Error</html>").status(500)
+        });
+        for result in run(&server) {
+            match result {
+                Err(RpcError::BatchFailed(e)) => match &*e {
+                    RpcError::HttpError(e) => {
+                        assert_eq!(e.status(), Some(reqwest::StatusCode::INTERNAL_SERVER_ERROR))
+                    }
+                    e => panic!("expected HttpError, got {:?}", e),
+                },
+                other => panic!("expected BatchFailed, got {:?}", other),
+            }
+        }
+
+        // The server rejecting the batch with one error object
+        let server = TestServer::start(|_| {
+            test_util::Reply::json(
+                r#"{"result":null,"error":{"code":-32600,"message":"Invalid Request"},"id":null}"#,
+            )
+            .status(500)
+        });
+        for result in run(&server) {
+            let Err(RpcError::BatchFailed(e)) = result else {
+                panic!("expected BatchFailed, got {:?}", result);
+            };
+            assert_eq!(e.error_object().unwrap().code, -32600);
+        }
+
+        // One bad entry fails only its own call
+        let server = TestServer::start(|req| {
+            let replies: Vec<_> = req
+                .json()
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|call| match call["params"][0].as_u64().unwrap() {
+                    1 => serde_json::json!({"result": null, "error": {"code": -8, "message": "Block height out of range"}, "id": call["id"]}),
+                    h => serde_json::json!({"result": format!("hash{}", h), "id": call["id"]}),
+                })
+                .collect();
+            test_util::Reply::json(&serde_json::to_string(&replies).unwrap())
+        });
+        let results = run(&server);
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(results[0].as_ref().unwrap(), "hash0");
+        assert_eq!(
+            results[1]
+                .as_ref()
+                .unwrap_err()
+                .error_object()
+                .unwrap()
+                .code,
+            -8
+        );
+        assert_eq!(results[2].as_ref().unwrap(), "hash2");
+    }
+
+    #[test]
+    fn test_tcp_options() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let client = TestClient::builder(&server.uri).build();
+        assert!(client.config().tcp_nodelay);
+        assert_eq!(client.config().tcp_keepalive, None);
+
+        let client = TestClient::builder(&server.uri)
+            .tcp_nodelay(false)
+            .tcp_keepalive(Some(Duration::from_secs(30)))
+            .build();
+        assert_eq!(client.getblockcount().unwrap(), 1);
+        assert!(!client.config().tcp_nodelay);
+        assert_eq!(client.config().tcp_keepalive, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_on_response_json() {
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct Info {
+            blocks: u64,
+            difficulty: f64,
+        }
+
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getinfo(&self) -> Result<Info>;
+            enum:
+                pub fn getcount(&self) -> Result<Count(u64) | Text(String)>;
+        });
+
+        let server = TestServer::start(|req| {
+            test_util::Reply::json(match req.json()["method"].as_str().unwrap() {
+                "getinfo" => r#"{"result":{"blocks":"12","difficulty":"1.5"},"id":0}"#,
+                _ => r#"{"result":"7","id":0}"#,
+            })
+        });
+        let calls = Arc::new(Mutex::new(0));
+        let counter = calls.clone();
+        let client = TestClient::builder(&server.uri)
+            .on_response_json(move |value| {
+                *counter.lock().unwrap() += 1;
+                let numeric = |v: &mut serde_json::Value| {
+                    if let Some(n) = v.as_str().and_then(|s| s.parse::<f64>().ok()) {
+                        *v = if n.fract() == 0.0 {
+                            serde_json::json!(n as u64)
+                        } else {
+                            serde_json::json!(n)
+                        };
+                    }
+                };
+                match value {
+                    serde_json::Value::Object(fields) => fields.values_mut().for_each(numeric),
+                    other => numeric(other),
+                }
+            })
+            .build();
+        assert_eq!(
+            client.getinfo().unwrap(),
+            Info {
+                blocks: 12,
+                difficulty: 1.5
+            }
+        );
+        assert!(matches!(
+            client.getcount().unwrap(),
+            reply::getcount::Count(7)
+        ));
+        // Applied once per call, including enum methods
+        assert_eq!(*calls.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_request_event_timings() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::start(|_| {
+            std::thread::sleep(Duration::from_millis(100));
+            test_util::Reply::json(r#"{"result":1,"id":0}"#)
+        });
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let client = TestClient::builder(&server.uri)
+            .rps(4)
+            .on_request(move |event| {
+                seen.lock()
+                    .unwrap()
+                    .push((event.elapsed, event.throttle_wait, event.network));
+            })
+            .build();
+        client.getblockcount().unwrap();
+        client.getblockcount().unwrap();
+
+        let events = events.lock().unwrap();
+        for (elapsed, throttle_wait, network) in events.iter() {
+            assert_eq!(*throttle_wait + *network, *elapsed);
+            assert!(*network >= Duration::from_millis(100));
+        }
+        // The second call waits out what remains of the 250ms interval
+        assert!(events[1].1 >= Duration::from_millis(100));
+        assert!(events[1].1 < Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_cache_etag() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let body = req.json();
+            if body["method"] == "getblockcount" {
+                return test_util::Reply::json(r#"{"result":5,"id":0}"#);
+            }
+            if req.header("if-none-match") == Some("\"h1\"") {
+                return test_util::Reply::json("").status(304);
+            }
+            test_util::Reply::json(
+                &serde_json::json!({"result": "ab", "id": body["id"]}).to_string(),
+            )
+            .header("ETag", "\"h1\"")
+        });
+        let client = TestClient::builder(&server.uri)
+            .cache_method("getblockhash", Duration::from_millis(50))
+            .cache_method("getblockcount", Duration::from_millis(50))
+            .build();
+
+        assert_eq!(client.getblockhash(1).unwrap(), "ab");
+        assert!(!client.last_was_cache_hit());
+        std::thread::sleep(Duration::from_millis(60));
+        // Expired: revalidated, and the 304 answers from the cache
+        assert_eq!(client.getblockhash(1).unwrap(), "ab");
+        assert!(client.last_was_cache_hit());
+        // Renewed by the 304
+        assert_eq!(client.getblockhash(1).unwrap(), "ab");
+        assert!(client.last_was_cache_hit());
+
+        // Without an ETag, expired entries are fetched again unconditionally
+        client.getblockcount().unwrap();
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(client.getblockcount().unwrap(), 5);
+        assert!(!client.last_was_cache_hit());
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[0].header("if-none-match"), None);
+        assert_eq!(requests[1].header("if-none-match"), Some("\"h1\""));
+        assert_eq!(requests[3].header("if-none-match"), None);
+    }
+
+    #[test]
+    fn test_method_visibility() {
+        mod node {
+            jsonrpc_client!(pub struct Client {
+                single:
+                    pub fn getblockcount(&self) -> Result<u64>;
+                    pub(super) fn getbestblockhash(&self) -> Result<String>;
+                    pub(self) fn getblockhash(&self, height: u64) -> Result<String>;
+                enum:
+                    pub(crate) fn getcount(&self) -> Result<Count(u64) | Text(String)>;
+            });
+
+            impl Client {
+                /// Hash of the first block, through the private method
+                pub fn genesis_hash(&self) -> Result<String, RpcError> {
+                    self.getblockhash(0)
+                }
+            }
+        }
+
+        let server = TestServer::start(|req| {
+            test_util::Reply::json(match req.json()["method"].as_str().unwrap() {
+                "getblockcount" | "getcount" => r#"{"result":1,"id":0}"#,
+                _ => r#"{"result":"00ab","id":0}"#,
+            })
+        });
+        let client = node::Client::builder(&server.uri).build();
+        assert_eq!(client.getblockcount().unwrap(), 1);
+        assert_eq!(client.getbestblockhash().unwrap(), "00ab");
+        assert_eq!(client.genesis_hash().unwrap(), "00ab");
+        assert!(matches!(
+            client.getcount().unwrap(),
+            node::reply::getcount::Count(1)
+        ));
+        let mut batch = client.notification_batch();
+        batch.getbestblockhash();
+        assert_eq!(batch.len(), 1);
+    }
+
+    #[test]
+    fn test_query_param() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let client = TestClient::builder(format!("{}/rpc", server.uri))
+            .query_param("apikey", "s3cret")
+            .build();
+        assert_eq!(client.getblockcount().unwrap(), 1);
+        client.health_check().unwrap();
+        for req in server.requests() {
+            assert_eq!(req.path, "/rpc?apikey=s3cret");
+        }
+    }
+
+    #[test]
+    fn test_method_path() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn block_number(&self) -> Result<String> as "eth_blockNumber" at "/eth";
+                pub fn net_version(&self) -> Result<String> at "net";
+                pub fn client_version(&self) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":"0x1","id":0}"#);
+        let client = TestClient::builder(format!("{}/rpc/", server.uri))
+            .query_param("apikey", "k")
+            .build();
+        client.block_number().unwrap();
+        client.net_version().unwrap();
+        client.client_version().unwrap();
+        client
+            .with_options(CallOptions::new().path("/admin"))
+            .block_number()
+            .unwrap();
+        let paths: Vec<_> = server.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            paths,
+            [
+                "/rpc/eth?apikey=k",
+                "/rpc/net?apikey=k",
+                "/rpc/?apikey=k",
+                "/rpc/admin?apikey=k"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_id() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let id = req.json()["id"].as_u64().unwrap();
+            test_util::Reply::json(&format!(r#"{{"result":{},"id":{}}}"#, id, id / 2))
+        });
+        let client = TestClient::builder(&server.uri).verify_id(true).build();
+        assert_eq!(client.getblockcount().unwrap(), 0);
+        match client.getblockcount() {
+            Err(RpcError::IdMismatch { expected, got }) => {
+                assert_eq!(expected, 1);
+                assert_eq!(got, 0);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // Loose servers are accepted unless verification is enabled
+        let client = TestClient::builder(&server.uri).build();
+        client.getblockcount().unwrap();
+        assert_eq!(client.getblockcount().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_call_many() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let in_flight = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let peak = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = TestServer::start({
+            let (in_flight, peak) = (in_flight.clone(), peak.clone());
+            move |req| {
+                use std::sync::atomic::Ordering::SeqCst;
+                let now = in_flight.fetch_add(1, SeqCst) + 1;
+                peak.fetch_max(now, SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                in_flight.fetch_sub(1, SeqCst);
+                let req = req.json();
+                let result = match req["method"].as_str().unwrap() {
+                    "getblockhash" => serde_json::json!(format!("h{}", req["params"][0])),
+                    _ => serde_json::json!(7),
+                };
+                test_util::Reply::json(
+                    &serde_json::json!({"result": result, "id": req["id"]}).to_string(),
+                )
+            }
+        });
+        let client = TestClient::builder(&server.uri)
+            .max_concurrency(3)
+            .rps(100)
+            .build();
+
+        type Request = Box<dyn FnOnce(&TestClient) -> Result<String, RpcError> + Send>;
+        let requests: Vec<Request> = (0..9u64)
+            .map(|h| Box::new(move |c: &TestClient| c.getblockhash(h)) as Request)
+            .chain(std::iter::once(Box::new(|c: &TestClient| {
+                c.getblockcount().map(|n| n.to_string())
+            }) as Request))
+            .collect();
+        let mut completed = 0;
+        let start = Instant::now();
+        let results = client.call_many(requests, |_, result| {
+            assert!(result.is_ok());
+            completed += 1;
+        });
+        assert_eq!(completed, 10);
+        let results: Vec<String> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(results[0], "h0");
+        assert_eq!(results[8], "h8");
+        assert_eq!(results[9], "7");
+        assert!(peak.load(std::sync::atomic::Ordering::SeqCst) <= 3);
+        // 10 requests at 100 rps take at least 90ms
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn test_with_response() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+            enum:
+        });
+
+        #[derive(serde::Deserialize)]
+        struct Block<'a> {
+            #[serde(borrow)]
+            tx: Vec<&'a str>,
+            #[serde(borrow)]
+            comment: std::borrow::Cow<'a, str>,
+        }
+
+        let server =
+            TestServer::json(r#"{"result":{"tx":["aa","bb"],"comment":"line\nbreak"},"id":0}"#);
+        let client = TestClient::builder(&server.uri).build();
+        let txids = client
+            .with_response("getblock", ("hash", 2), |resp| {
+                let block: Block<'_> = resp.result()?;
+                let body = resp.text().as_bytes().as_ptr_range();
+                assert!(block.tx.iter().all(|tx| body.contains(&tx.as_ptr())));
+                assert!(matches!(block.comment, std::borrow::Cow::Owned(_)));
+                assert_eq!(block.comment, "line\nbreak");
+                Ok::<_, RpcError>(block.tx.len())
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(txids, 2);
+        assert_eq!(
+            server.requests()[0].json()["params"],
+            serde_json::json!(["hash", 2])
+        );
+
+        let server = TestServer::json(r#"{"result":null,"error":{"code":-5},"id":0}"#);
+        let client = TestClient::builder(&server.uri).build();
+        let result = client
+            .with_response("getblock", ("hash",), |resp| {
+                resp.result::<Block<'_>>().map(|_| ())
+            })
+            .unwrap();
+        assert!(matches!(result, Err(RpcError::RpcError { .. })));
+    }
+
+    #[test]
+    fn test_reset_throttle() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let client = TestClient::builder(&server.uri)
+            .rps(1)
+            .when_throttled(WhenThrottled::Reject)
+            .build();
+        client.reset_throttle();
+        client.getblockcount().unwrap();
+        assert!(matches!(
+            client.getblockcount(),
+            Err(RpcError::RateLimited { .. })
+        ));
+        client.reset_throttle();
+        client.getblockcount().unwrap();
+    }
+
+    #[test]
+    fn test_send_raw_batch() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let body = match req.json() {
+                serde_json::Value::Array(calls) => serde_json::Value::Array(
+                    calls
+                        .iter()
+                        .map(|c| serde_json::json!({"result": 5, "id": c["id"]}))
+                        .collect(),
+                ),
+                call => serde_json::json!({"result": 1, "id": call["id"]}),
+            };
+            test_util::Reply::json(&body.to_string())
+        });
+        let client = TestClient::builder(&server.uri).rps(20).build();
+        client.reset_throttle();
+        let batch = serde_json::json!([
+            {"jsonrpc": "2.0", "method": "getblockcount", "params": [], "id": "a"},
+            {"jsonrpc": "2.0", "method": "getblockcount", "params": [], "id": "b"},
+            {"jsonrpc": "2.0", "method": "getblockcount", "params": [], "id": "c"},
+            {"jsonrpc": "2.0", "method": "getblockcount", "params": [], "id": "d"},
+        ]);
+        let start = Instant::now();
+        let reply = client.send_raw_batch(batch.clone()).unwrap();
+        assert_eq!(reply[3], serde_json::json!({"result": 5, "id": "d"}));
+        assert_eq!(server.requests()[0].json(), batch);
+
+        // The batch took four slots at 50ms each
+        client.getblockcount().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        assert!(matches!(
+            client.send_raw_batch(serde_json::json!({"method": "getblockcount"})),
+            Err(RpcError::InvalidBatch)
+        ));
+    }
+
+    #[test]
+    fn test_saturation() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let client = TestClient::builder(&server.uri).rps(20).build();
+        for _ in 0..5 {
+            client.getblockcount().unwrap();
+        }
+        // Almost all of the run was spent waiting for the next slot
+        let saturation = client.saturation();
+        assert!(saturation > 0.5, "{}", saturation);
+
+        let client = TestClient::builder(&server.uri).build();
+        for _ in 0..5 {
+            client.getblockcount().unwrap();
+        }
+        let saturation = client.saturation();
+        assert!(saturation < 0.2, "{}", saturation);
+    }
+
+    #[test]
+    fn test_default_args() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblock(&self, hash: String, verbosity: u8 = 1) -> Result<u64>;
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+                pub fn gettx(&self, txid: String, verbose: bool = true, block: Option<String> = None) -> Result<A(String)|B(u64)>;
+        });
+
+        let server = TestServer::json(r#"{"result":3,"id":0}"#);
+        let client = TestClient::builder(&server.uri).build();
+        client.defaults().getblock("h".to_string()).unwrap();
+        client.getblock("h".to_string(), 2).unwrap();
+        assert!(matches!(
+            client.defaults().gettx("t".to_string()).unwrap(),
+            reply::gettx::B(3)
+        ));
+        client
+            .with_options(CallOptions::new().metadata("tenant", "a"))
+            .defaults()
+            .getblock("h".to_string())
+            .unwrap();
+        let params: Vec<_> = server
+            .requests()
+            .iter()
+            .map(|r| r.json()["params"].clone())
+            .collect();
+        assert_eq!(
+            params,
+            [
+                serde_json::json!(["h", 1]),
+                serde_json::json!(["h", 2]),
+                serde_json::json!(["t", true]),
+                serde_json::json!(["h", 1]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dry_run() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+                pub fn getblock(&self, hash: String, verbosity: Option<u8>) -> Result<String>;
+            enum:
+                pub fn gettx(&self, txid: String) -> Result<A(String)|B(u64)>;
+        });
+
+        let server = TestServer::json(r#"{"result":"h","id":0}"#);
+        let client = TestClient::builder(&server.uri).build();
+        assert_eq!(
+            client.dry_run().getblockhash(100).unwrap(),
+            serde_json::json!({"method": "getblockhash", "params": [100], "id": 0})
+        );
+        assert_eq!(
+            client.dry_run().getblock("h".to_string(), None).unwrap(),
+            serde_json::json!({"method": "getblock", "params": ["h"], "id": 1})
+        );
+        assert_eq!(
+            client
+                .with_options(CallOptions::new().id("fixed"))
+                .dry_run()
+                .gettx("t".to_string())
+                .unwrap(),
+            serde_json::json!({"method": "gettx", "params": ["t"], "id": "fixed"})
+        );
+
+        let mut batch = client.batcher::<String>();
+        batch.getblockhash(1).unwrap();
+        batch.getblockhash(2).unwrap();
+        assert_eq!(
+            batch.dry_run().unwrap(),
+            serde_json::json!([
+                {"method": "getblockhash", "params": [1], "id": 2},
+                {"method": "getblockhash", "params": [2], "id": 3},
+            ])
+        );
+
+        let named = TestClient::builder(&server.uri)
+            .param_style(ParamStyle::Named)
+            .build();
+        assert_eq!(
+            named.dry_run().getblock("h".to_string(), Some(2)).unwrap(),
+            serde_json::json!({"method": "getblock", "params": {"hash": "h", "verbosity": 2}, "id": 0})
+        );
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "native-tls")]
+    fn test_tls_error() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let client = TestClient::builder(test_util::untrusted_tls_server()).build();
+        let err = client.getblockcount().unwrap_err();
+        assert!(matches!(err, RpcError::Tls(_)), "{:?}", err);
+        assert!(!err.is_transient());
+
+        // A refused connection stays an HTTP error
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let client = TestClient::builder(format!("https://{}", addr)).build();
+        assert!(matches!(
+            client.getblockcount(),
+            Err(RpcError::HttpError(_))
+        ));
+    }
+
+    #[test]
+    fn test_http_get() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+                pub fn sendrawtransaction(&self, hex: String) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let reply = |id: &serde_json::Value| serde_json::json!({"result": "ok", "id": id});
+            let body = match req.method.as_str() {
+                "GET" => reply(&serde_json::Value::Null),
+                _ => match req.json() {
+                    serde_json::Value::Array(calls) => {
+                        calls.iter().map(|c| reply(&c["id"])).collect()
+                    }
+                    call => reply(&call["id"]),
+                },
+            };
+            test_util::Reply::json(&body.to_string())
+        });
+        let client = TestClient::builder(format!("{}/rpc?key=k", server.uri))
+            .http_get("getblockhash")
+            .batch_window(Duration::from_millis(5))
+            .build();
+        client.getblockhash(100).unwrap();
+        client.sendrawtransaction("00 ff".to_string()).unwrap();
+        client
+            .with_options(CallOptions::new().http_get())
+            .sendrawtransaction("a&b".to_string())
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "GET");
+        assert_eq!(
+            requests[0].path,
+            "/rpc?key=k&method=getblockhash&params=%5B100%5D&id=0"
+        );
+        assert!(requests[0].body.is_empty());
+        assert!(requests[0].header("content-type").is_none());
+        assert_eq!(requests[1].method, "POST");
+        assert_eq!(requests[1].json()[0]["method"], "sendrawtransaction");
+        assert_eq!(requests[2].method, "GET");
+        assert_eq!(
+            requests[2].path,
+            "/rpc?key=k&method=sendrawtransaction&params=%5B%22a%26b%22%5D&id=2"
+        );
+    }
+
+    #[test]
+    fn test_result_path() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+                pub fn getcount(&self) -> Result<Count(u64) | Text(String)>;
+        });
+
+        let server = TestServer::json(r#"{"jsonrpc":"2.0","result":{"data":42},"id":0}"#);
+        let client = TestClient::builder(&server.uri)
+            .result_path(&["data"])
+            .build();
+        assert_eq!(client.getblockcount().unwrap(), 42);
+        assert!(matches!(client.getcount(), Ok(reply::getcount::Count(42))));
+    }
+
+    #[test]
+    fn test_cannot_deserialize_variant_errors() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+            enum:
+                pub fn getpeer(&self) -> Result<Id(u64) | Peer { addr: String, port: u16 }>;
+        });
+
+        let server = TestServer::json(r#"{"result":{"addr":"10.0.0.1","port":"8333"},"id":0}"#);
+        let client = TestClient::builder(&server.uri).build();
+        let Err(RpcError::CannotDeserialize {
+            enum_name,
+            variant_errors,
+            ..
+        }) = client.getpeer()
+        else {
+            panic!("expected CannotDeserialize");
+        };
+        assert_eq!(enum_name, "getpeer");
+        let variants: Vec<_> = variant_errors.iter().map(|(v, _)| *v).collect();
+        assert_eq!(variants, ["Id", "Peer"]);
+        assert!(variant_errors[1]
+            .1
+            .to_string()
+            .contains("invalid type: string \"8333\""));
+
+        let message = client.getpeer().unwrap_err().to_string();
+        let lines: Vec<_> = message.lines().collect();
+        assert!(lines[1].starts_with("  Id: "), "{}", message);
+        assert!(lines[1].contains("invalid type: map"), "{}", message);
+        assert!(lines[2].starts_with("  Peer: "), "{}", message);
+        assert!(lines[2].contains("invalid type: string"), "{}", message);
+    }
+
+    #[test]
+    fn test_shared_id_counter() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let counter = Arc::new(std::sync::atomic::AtomicU64::new(10));
+        let a = TestClient::builder(&server.uri)
+            .shared_id_counter(counter.clone())
+            .build();
+        let b = TestClient::builder(&server.uri)
+            .shared_id_counter(counter.clone())
+            .build();
+        let own = TestClient::builder(&server.uri).build();
+        a.getblockcount().unwrap();
+        b.getblockcount().unwrap();
+        a.getblockcount().unwrap();
+        own.getblockcount().unwrap();
+
+        let ids: Vec<_> = server
+            .requests()
+            .iter()
+            .map(|r| r.json()["id"].clone())
+            .collect();
+        assert_eq!(ids, [10, 11, 12, 0]);
+        assert_eq!(counter.load(std::sync::atomic::Ordering::Relaxed), 13);
+    }
+
+    #[test]
+    fn test_chunked_response() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn listunspent(&self) -> Result<Vec<serde_json::Value>>;
+            enum:
+        });
+
+        let items: Vec<_> = (0..200)
+            .map(|i| format!(r#"{{"txid":"{:064x}","vout":{}}}"#, i, i))
+            .collect();
+        let body = format!(r#"{{"result":[{}],"error":null,"id":0}}"#, items.join(","));
+        let server = TestServer::start({
+            let body = body.clone();
+            move |_| test_util::Reply::json(&body).chunked(61)
+        });
+        let client = TestClient::builder(&server.uri).build();
+        let result = client.listunspent().unwrap();
+        assert_eq!(result.len(), 200);
+        assert_eq!(result[199]["vout"], 199);
+
+        let streamed = client
+            .stream_array::<serde_json::Value, _>("listunspent", ())
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(streamed, result);
+
+        let batch_body = format!("[{}]", body);
+        let server = TestServer::start(move |_| test_util::Reply::json(&batch_body).chunked(53));
+        let client = TestClient::builder(&server.uri).build();
+        let mut batch = client.batcher::<Vec<serde_json::Value>>();
+        batch.listunspent().unwrap();
+        assert_eq!(batch.send().unwrap()[0].len(), 200);
+
+        // A body cut off mid-transfer is an error, never a partial result
+        let server =
+            TestServer::start(move |_| test_util::Reply::json(&body).chunked_incomplete(61));
+        let client = TestClient::builder(&server.uri).build();
+        assert!(client.listunspent().is_err());
+        let mut stream = client
+            .stream_array::<serde_json::Value, _>("listunspent", ())
+            .unwrap();
+        assert!(stream.by_ref().take_while(|item| item.is_ok()).count() < 200);
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn test_method_concurrency() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblock(&self, hash: String, verbosity: u8) -> Result<u64>;
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let in_flight = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let peak = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = TestServer::start({
+            let (in_flight, peak) = (in_flight.clone(), peak.clone());
+            move |req| {
+                let heavy = req.json()["method"] == "getblock";
+                if heavy {
+                    let now = in_flight.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, std::sync::atomic::Ordering::SeqCst);
+                }
+                std::thread::sleep(Duration::from_millis(50));
+                if heavy {
+                    in_flight.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                }
+                test_util::Reply::json(r#"{"result":1,"id":0}"#)
+            }
+        });
+        let client = TestClient::builder(&server.uri)
+            .max_concurrency(20)
+            .method_concurrency("getblock", 2)
+            .build();
+        let started = Instant::now();
+        std::thread::scope(|s| {
+            for _ in 0..6 {
+                s.spawn(|| client.getblock("00".to_string(), 2).unwrap());
+                s.spawn(|| client.getblockcount().unwrap());
+            }
+        });
+        assert_eq!(peak.load(std::sync::atomic::Ordering::SeqCst), 2);
+        // Three rounds of two heavy calls, while the other calls ran alongside
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert!(started.elapsed() < Duration::from_millis(300));
+        assert_eq!(client.throttle().gate().in_flight(), 0);
+    }
+
+    #[test]
+    fn test_accept_error_code() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn sendrawtransaction(&self, hex: String) -> Result<Option<String>>;
+                pub fn getblockcount(&self) -> Result<u64>;
+                pub fn getrawmempool(&self) -> Result<Option<Vec<String>>>;
+            enum:
+        });
+
+        let server = TestServer::json(
+            r#"{"result":null,"error":{"code":-27,"message":"transaction already in block chain"},"id":0}"#,
+        );
+        let strict = TestClient::builder(&server.uri).build();
+        assert!(matches!(
+            strict.sendrawtransaction("00".to_string()),
+            Err(RpcError::RpcError { .. })
+        ));
+
+        let client = TestClient::builder(&server.uri)
+            .accept_method_error_code("sendrawtransaction", -27)
+            .build();
+        assert_eq!(client.sendrawtransaction("00".to_string()).unwrap(), None);
+        // Only for the configured method
+        assert!(client.getrawmempool().is_err());
+
+        let client = TestClient::builder(&server.uri)
+            .accept_error_code(-27)
+            .build();
+        assert_eq!(client.getrawmempool().unwrap(), None);
+        // A result type that cannot be null keeps the server's error
+        match client.getblockcount() {
+            Err(RpcError::RpcError { error }) => assert_eq!(error["code"], -27),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // Errors without a code are not matched by the code they are normalized to
+        for error in [
+            r#""already in mempool""#,
+            r#"{"message":"already in mempool"}"#,
+        ] {
+            let body = format!(r#"{{"result":null,"error":{},"id":0}}"#, error);
+            let server = TestServer::start(move |_| test_util::Reply::json(&body));
+            let client = TestClient::builder(&server.uri)
+                .accept_error_code(JsonRpcErrorObject::STRING_ERROR_CODE)
+                .build();
+            assert!(matches!(
+                client.sendrawtransaction("00".to_string()),
+                Err(RpcError::RpcError { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_raw_header() {
+        use reqwest::header::{HeaderName, HeaderValue};
+
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let mut token = HeaderValue::from_bytes(b"tok\xfe\xff").unwrap();
+        token.set_sensitive(true);
+        let client = TestClient::builder(&server.uri)
+            .raw_header(HeaderName::from_static("x-api-token"), token)
+            .build();
+        client.getblockcount().unwrap();
+        assert_eq!(
+            server.requests()[0].header("x-api-token"),
+            Some("tok\u{fffd}\u{fffd}")
+        );
+        assert!(format!("{:?}", client.config().primary()).contains("Sensitive"));
+    }
+
+    #[test]
+    fn test_batch_fallback() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let body = req.json();
+            if body.is_array() {
+                return test_util::Reply::json(
+                    r#"{"error":{"code":-32600,"message":"batch not supported"},"id":null}"#,
+                );
+            }
+            let height = body["params"][0].as_u64().unwrap();
+            test_util::Reply::json(
+                &serde_json::json!({"result": height * 10, "id": body["id"]}).to_string(),
+            )
+        });
+
+        let strict = TestClient::builder(&server.uri).build();
+        let mut batch = strict.batcher::<u64>();
+        batch.getblockhash(1).unwrap();
+        assert!(matches!(batch.send(), Err(RpcError::RpcError { .. })));
+
+        let client = TestClient::builder(&server.uri)
+            .batch_fallback(true)
+            .build();
+        let mut batch = client.batcher::<u64>();
+        batch.getblockhash(1).unwrap();
+        batch.getblockhash(2).unwrap();
+        assert_eq!(batch.send().unwrap(), vec![10, 20]);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[1].json().is_array());
+        assert_eq!(requests[3].json()["params"], serde_json::json!([2]));
+
+        let client = TestClient::builder(&server.uri)
+            .batch_window(Duration::from_millis(50))
+            .batch_fallback(true)
+            .build();
+        std::thread::scope(|s| {
+            let a = s.spawn(|| client.getblockhash(3));
+            let b = s.spawn(|| client.getblockhash(4));
+            assert_eq!(a.join().unwrap().unwrap(), 30);
+            assert_eq!(b.join().unwrap().unwrap(), 40);
+        });
+    }
+
+    #[test]
+    fn test_null_id_error() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+            enum:
+        });
+
+        const INVALID: &str =
+            r#"{"jsonrpc":"2.0","error":{"code":-32600,"message":"Invalid Request"},"id":null}"#;
+        let server = TestServer::start(|req| {
+            let body = req.json();
+            match body.as_array() {
+                // The second call of a batch could not be parsed
+                Some(_) => test_util::Reply::json(&format!(
+                    r#"[{{"result":"aa","id":{}}},{}]"#,
+                    body[0]["id"], INVALID
+                )),
+                None => test_util::Reply::json(INVALID),
+            }
+        });
+        for verify_id in [false, true] {
+            let client = TestClient::builder(&server.uri)
+                .verify_id(verify_id)
+                .build();
+            match client.getblockhash(1) {
+                Err(RpcError::RpcError { error }) => assert_eq!(error["code"], -32600),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+
+        let client = TestClient::builder(&server.uri).build();
+        let mut batch = client.batcher::<String>();
+        batch.getblockhash(1).unwrap();
+        batch.getblockhash(2).unwrap();
+        let results = batch.send_each().unwrap();
+        assert_eq!(results[0].as_ref().unwrap(), "aa");
+        assert!(
+            matches!(&results[1], Err(RpcError::RpcError { error }) if error["code"] == -32600)
+        );
+
+        let client = TestClient::builder(&server.uri)
+            .batch_window(Duration::from_millis(50))
+            .build();
+        std::thread::scope(|s| {
+            let a = s.spawn(|| client.getblockhash(1));
+            std::thread::sleep(Duration::from_millis(10));
+            let b = s.spawn(|| client.getblockhash(2));
+            assert_eq!(a.join().unwrap().unwrap(), "aa");
+            assert!(matches!(b.join().unwrap(), Err(RpcError::RpcError { .. })));
+        });
+    }
+
+    #[test]
+    fn test_set_header() {
+        use reqwest::header::{HeaderValue, AUTHORIZATION};
+
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let client = TestClient::builder(&server.uri)
+            .bearer_auth("first")
+            .header("X-Tenant", "a")
+            .build();
+        client.getblockcount().unwrap();
+        client.set_header(AUTHORIZATION, HeaderValue::from_static("Bearer second"));
+        client.set_header("x-trace", HeaderValue::from_static("1"));
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| client.getblockcount().unwrap());
+            }
+        });
+        client.remove_header(AUTHORIZATION);
+        client.remove_header("x-trace");
+        client.getblockcount().unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].header("authorization"), Some("Bearer first"));
+        for request in &requests[1..5] {
+            let auth: Vec<_> = request
+                .headers
+                .iter()
+                .filter(|(k, _)| k.eq_ignore_ascii_case("authorization"))
+                .collect();
+            assert_eq!(auth.len(), 1);
+            assert_eq!(auth[0].1, "Bearer second");
+            assert_eq!(request.header("x-trace"), Some("1"));
+            assert_eq!(request.header("x-tenant"), Some("a"));
+        }
+        assert_eq!(requests[5].header("authorization"), Some("Bearer first"));
+        assert_eq!(requests[5].header("x-trace"), None);
+    }
+
+    #[test]
+    fn test_on_unauthorized() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let valid = Arc::new(Mutex::new("Bearer t1".to_string()));
+        let server = TestServer::start({
+            let valid = valid.clone();
+            move |req| match req.header("authorization") == Some(valid.lock().unwrap().as_str()) {
+                true => test_util::Reply::json(r#"{"result":1,"id":0}"#),
+                false => test_util::Reply::json("").status(401),
+            }
+        });
+        let refreshes = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let client = TestClient::builder(&server.uri)
+            .bearer_auth("t0")
+            .on_unauthorized({
+                let refreshes = refreshes.clone();
+                move || {
+                    refreshes.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(50));
+                    Ok("t1".to_string())
+                }
+            })
+            .build();
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| assert_eq!(client.getblockcount().unwrap(), 1));
+            }
+        });
+        // Concurrent 401s share one refresh
+        assert_eq!(refreshes.load(std::sync::atomic::Ordering::SeqCst), 1);
+        client.getblockcount().unwrap();
+        assert_eq!(
+            server.requests().last().unwrap().header("authorization"),
+            Some("Bearer t1")
+        );
+
+        // The token expires again: refreshed once more, then the request is retried once
+        *valid.lock().unwrap() = "Bearer t2".to_string();
+        assert!(client.getblockcount().is_err());
+        assert_eq!(refreshes.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        let client = TestClient::builder(&server.uri)
+            .on_unauthorized(|| Err(RpcError::Cancelled))
+            .build();
+        assert!(matches!(client.getblockcount(), Err(RpcError::Cancelled)));
+    }
+
+    #[test]
+    fn test_unthrottled_scope() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let client = TestClient::builder(&server.uri)
+            .rps(1)
+            .max_concurrency(1)
+            .when_throttled(WhenThrottled::Reject)
+            .build();
+        client.reset_throttle();
+        client.getblockcount().unwrap();
+        {
+            let _burst = client.unthrottled_scope();
+            let nested = client.unthrottled_scope();
+            for _ in 0..5 {
+                client.getblockcount().unwrap();
+            }
+            drop(nested);
+            client.getblockcount().unwrap();
+            // The concurrency limit still applies
+            let _slot = client.throttle().gate().acquire(0);
+            assert!(client.throttle().try_acquire().is_err());
+        }
+        assert!(matches!(
+            client.getblockcount(),
+            Err(RpcError::RateLimited { .. })
+        ));
+
+        // A panic inside the scope turns the limit back on
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _burst = client.unthrottled_scope();
+            panic!("sync failed");
+        }));
+        assert!(result.is_err());
+        assert!(matches!(
+            client.getblockcount(),
+            Err(RpcError::RateLimited { .. })
+        ));
+    }
+
+    #[test]
+    fn test_gzip_response() {
+        use std::io::Write;
+
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblock(&self, hash: String) -> Result<serde_json::Value>;
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            if let Some(batch) = req.json().as_array() {
+                let id = &batch[0]["id"];
+                return test_util::Reply::json(&format!(r#"[{{"result":7,"id":{}}}]"#, id));
+            }
+            let body = match req.json()["method"].as_str() {
+                Some("getblock") => r#"{"result":{"tx":["aa","bb"]},"id":0}"#,
+                _ => r#"{"result":7,"id":0}"#,
+            };
+            if req.header("accept-encoding") != Some("gzip") {
+                return test_util::Reply::json(body);
+            }
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body.as_bytes()).unwrap();
+            test_util::Reply {
+                body: encoder.finish().unwrap(),
+                ..test_util::Reply::json("")
+            }
+            .header("Content-Encoding", "gzip")
+        });
+        let client = TestClient::builder(&server.uri)
+            .gzip_response("getblock")
+            .batch_window(Duration::from_millis(10))
+            .build();
+        assert_eq!(client.getblock("00".to_string()).unwrap()["tx"][1], "bb");
+        assert_eq!(client.getblockcount().unwrap(), 7);
+        assert_eq!(
+            client
+                .with_options(CallOptions::new().gzip_response())
+                .getblockcount()
+                .unwrap(),
+            7
+        );
+
+        let requests = server.requests();
+        assert_eq!(requests[0].header("accept-encoding"), Some("gzip"));
+        // Sent on its own rather than in an automatic batch
+        assert!(requests[0].json().is_object());
+        assert_eq!(requests[1].header("accept-encoding"), None);
+        assert_eq!(requests[2].header("accept-encoding"), Some("gzip"));
+    }
+
+    #[test]
+    fn test_batch_send_response() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let body = req.json();
+            test_util::Reply::json(&format!(
+                r#"[{{"result":"aa","id":{}}},{{"error":{{"code":-8,"message":"out of range","data":9}},"id":{}}}]"#,
+                body[0]["id"], body[1]["id"]
+            ))
+        });
+        let client = TestClient::builder(&server.uri).build();
+        let mut batch = client.batcher::<String>();
+        batch.getblockhash(1).unwrap();
+        batch.getblockhash(99).unwrap();
+        let response = batch.send_response().unwrap();
+        assert_eq!(
+            response.successes().collect::<Vec<_>>(),
+            [(0, &"aa".to_string())]
+        );
+        let errors: Vec<_> = response.errors().collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 1);
+        assert_eq!(
+            *errors[0].1,
+            JsonRpcErrorObject {
+                code: -8,
+                message: "out of range".to_string(),
+                data: Some(serde_json::json!(9)),
+                synthesized_code: false,
+            }
+        );
+        assert!(response.into_results()[1].is_err());
+    }
+
+    #[test]
+    fn test_batch_cost() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockhash(&self, height: u64) -> Result<String>;
+            enum:
+        });
+
+        let server = TestServer::start(|req| {
+            let body = match req.json() {
+                serde_json::Value::Array(calls) => serde_json::Value::Array(
+                    calls
+                        .iter()
+                        .map(|c| serde_json::json!({"result": "aa", "id": c["id"]}))
+                        .collect(),
+                ),
+                call => serde_json::json!({"result": "bb", "id": call["id"]}),
+            };
+            test_util::Reply::json(&body.to_string())
+        });
+        for (cost, held_back) in [(BatchCost::PerCall, true), (BatchCost::PerRequest, false)] {
+            let client = TestClient::builder(&server.uri)
+                .rps(10)
+                .batch_window(Duration::from_millis(30))
+                .batch_cost(cost)
+                .build();
+            client.reset_throttle();
+            let start = Instant::now();
+            let requests = server.requests().len();
+            // Calls joining the batch do not wait on the rate limiter, so all three
+            // make it into the same batch
+            std::thread::scope(|s| {
+                let client = &client;
+                let calls: Vec<_> = (0..3)
+                    .map(|height| s.spawn(move || client.getblockhash(height)))
+                    .collect();
+                for call in calls {
+                    assert_eq!(call.join().unwrap().unwrap(), "aa");
+                }
+            });
+            assert_eq!(server.requests().len(), requests + 1);
+            assert!(start.elapsed() < Duration::from_millis(100));
+
+            // Per call, the batch took three slots at 100ms each
+            client
+                .with_options(CallOptions::new().immediate())
+                .getblockhash(3)
+                .unwrap();
+            assert_eq!(
+                start.elapsed() >= Duration::from_millis(300),
+                held_back,
+                "{:?}",
+                cost
+            );
+        }
+    }
+
+    #[test]
+    fn test_with_independent_throttle() {
+        use reqwest::header::{HeaderMap, HeaderValue};
+
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"id":0}"#);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-pool", HeaderValue::from_static("shared"));
+        let http = reqwest::blocking::Client::builder()
+            .default_headers(headers)
+            .build()
+            .unwrap();
+        let bulk = TestClient::builder(&server.uri)
+            .http_client(http)
+            .rps(1)
+            .build();
+        let interactive = bulk.with_independent_throttle(ThrottleConfig {
+            rps: 100,
+            ..ThrottleConfig::default()
+        });
+        assert!(!Arc::ptr_eq(bulk.throttle(), interactive.throttle()));
+        assert_eq!(interactive.config().throttle.rps, 100);
+
+        // The bulk client's exhausted rate limit does not hold back the other one
+        bulk.reset_throttle();
+        bulk.getblockcount().unwrap();
+        let start = Instant::now();
+        interactive.getblockcount().unwrap();
+        interactive.getblockcount().unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests
+            .iter()
+            .all(|r| r.header("x-pool") == Some("shared")));
+    }
+
+    #[test]
+    fn test_per_method_options_take_wire_names() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getinfo(&self) -> Result<u64>;
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let server = TestServer::json(r#"{"result":1,"error":null,"id":0}"#);
+        let client = TestClient::builder(&server.uri)
+            .method_prefix("daemon.")
+            .cache_method("daemon.getinfo", Duration::from_secs(60))
+            .cache_method("getblockcount", Duration::from_secs(60))
+            .build();
+        client.getinfo().unwrap();
+        client.getinfo().unwrap();
+        assert!(client.last_was_cache_hit());
+        // The declared name matches nothing on the wire
+        client.getblockcount().unwrap();
+        client.getblockcount().unwrap();
+        assert!(!client.last_was_cache_hit());
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_error_status() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn test_method(&self) -> Result<u64>;
+            enum:
+        });
+
+        let status = |res: Result<u64, RpcError>| match res {
+            Err(RpcError::HttpError(e)) => e.status().map(|s| s.as_u16()),
+            other => panic!("expected HttpError, got {:?}", other),
+        };
+        for body in ["", "Bad Gateway", r#"{"message":"no such route"}"#] {
+            let server = TestServer::start(move |_| test_util::Reply::json(body).status(502));
+            let client = TestClient::builder(&server.uri).build();
+            assert_eq!(status(client.test_method()), Some(502));
+        }
+
+        // An envelope still carries the server's error
+        let server = TestServer::start(|_| {
+            test_util::Reply::json(
+                r#"{"result":null,"error":{"code":-32601,"message":"Method not found"},"id":0}"#,
+            )
+            .status(404)
+        });
+        let client = TestClient::builder(&server.uri).build();
+        assert_eq!(
+            client
+                .test_method()
+                .unwrap_err()
+                .error_object()
+                .unwrap()
+                .code,
+            -32601
+        );
+    }
+
+    #[test]
+    fn test_on_unauthorized_failover() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblockcount(&self) -> Result<u64>;
+            enum:
+        });
+
+        let down = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let primary = TestServer::start({
+            let down = down.clone();
+            move |req| {
+                if down.load(std::sync::atomic::Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_millis(500));
+                }
+                match req.header("authorization") == Some("Bearer fresh") {
+                    true => test_util::Reply::json(r#"{"result":1,"id":0}"#),
+                    false => test_util::Reply::json("").status(401),
+                }
+            }
+        });
+        let fallback = TestServer::start(|req| match req.header("authorization") {
+            Some("Bearer own") => test_util::Reply::json(r#"{"result":2,"id":0}"#),
+            _ => test_util::Reply::json("").status(401),
+        });
+        let client = TestClient::builder(&primary.uri)
+            .bearer_auth("stale")
+            .fallback(Endpoint::new(&fallback.uri).bearer_auth("own"))
+            .timeout(Duration::from_millis(200))
+            .on_unauthorized(|| Ok("fresh".to_string()))
+            .build();
+        assert_eq!(client.getblockcount().unwrap(), 1);
+        assert_eq!(
+            primary.requests()[1].header("authorization"),
+            Some("Bearer fresh")
+        );
+
+        // The primary's token is not sent to the fallback, which keeps its own
+        down.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(client.getblockcount().unwrap(), 2);
+        assert_eq!(client.transport.active_endpoint(), 1);
+        let requests = fallback.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].header("authorization"), Some("Bearer own"));
+    }
+
+    #[test]
+    fn test_slot_held_while_body_is_read() {
+        jsonrpc_client!(pub struct TestClient {
+            single:
+                pub fn getblock(&self) -> Result<String>;
+            enum:
+        });
+
+        // About 200ms to send each body, in 2ms chunks
+        let body = format!(r#"{{"result":"{}","id":0}}"#, "x".repeat(1000));
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let server = TestServer::start({
+            let arrivals = arrivals.clone();
+            move |_| {
+                arrivals.lock().unwrap().push(Instant::now());
+                test_util::Reply::json(&body).chunked(10)
+            }
+        });
+        let client = TestClient::builder(&server.uri).max_concurrency(1).build();
+        std::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| assert_eq!(client.getblock().unwrap().len(), 1000));
+            }
+        });
+        let arrivals = arrivals.lock().unwrap();
+        assert!(arrivals[1] - arrivals[0] >= Duration::from_millis(150));
+    }
 }
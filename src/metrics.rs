@@ -0,0 +1,183 @@
+//! Request metrics rendered in the Prometheus text exposition format, from
+//! `client.metrics_text()`. Only compiled with the `metrics` feature.
+
+use crate::RpcError;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the request latency histogram buckets
+pub const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters and latency histogram per wire method
+#[derive(Default)]
+pub(crate) struct Metrics {
+    methods: Mutex<BTreeMap<String, MethodMetrics>>,
+}
+
+#[derive(Default)]
+struct MethodMetrics {
+    requests: u64,
+    errors: BTreeMap<&'static str, u64>,
+    /// Non-cumulative counts per bucket of `LATENCY_BUCKETS`, plus one for `+Inf`
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    latency_sum: f64,
+}
+
+impl Metrics {
+    pub(crate) fn record(&self, method: &str, elapsed: Duration, error: Option<&RpcError>) {
+        let mut methods = self.methods.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = match methods.get_mut(method) {
+            Some(entry) => entry,
+            None => methods.entry(method.to_string()).or_default(),
+        };
+        entry.requests += 1;
+        if let Some(error) = error {
+            *entry.errors.entry(error_kind(error)).or_default() += 1;
+        }
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        entry.buckets[bucket] += 1;
+        entry.latency_sum += secs;
+    }
+
+    pub(crate) fn render(&self) -> String {
+        let methods = self.methods.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut out = String::new();
+        out.push_str("# HELP jsonrpc_requests_total JSON-RPC requests sent, by method.\n");
+        out.push_str("# TYPE jsonrpc_requests_total counter\n");
+        for (method, m) in methods.iter() {
+            let method = escape(method);
+            let _ = writeln!(
+                out,
+                "jsonrpc_requests_total{{method=\"{method}\"}} {}",
+                m.requests
+            );
+        }
+        out.push_str(
+            "# HELP jsonrpc_errors_total Failed JSON-RPC requests, by method and error kind.\n",
+        );
+        out.push_str("# TYPE jsonrpc_errors_total counter\n");
+        for (method, m) in methods.iter() {
+            let method = escape(method);
+            for (kind, count) in &m.errors {
+                let _ = writeln!(
+                    out,
+                    "jsonrpc_errors_total{{method=\"{method}\",kind=\"{kind}\"}} {count}"
+                );
+            }
+        }
+        out.push_str("# HELP jsonrpc_request_duration_seconds JSON-RPC request latency, including throttling.\n");
+        out.push_str("# TYPE jsonrpc_request_duration_seconds histogram\n");
+        for (method, m) in methods.iter() {
+            let method = escape(method);
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&m.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "jsonrpc_request_duration_seconds_bucket{{method=\"{method}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "jsonrpc_request_duration_seconds_bucket{{method=\"{method}\",le=\"+Inf\"}} {}",
+                m.requests
+            );
+            let _ = writeln!(
+                out,
+                "jsonrpc_request_duration_seconds_sum{{method=\"{method}\"}} {}",
+                m.latency_sum
+            );
+            let _ = writeln!(
+                out,
+                "jsonrpc_request_duration_seconds_count{{method=\"{method}\"}} {}",
+                m.requests
+            );
+        }
+        out
+    }
+}
+
+/// Label value for the kind of `error`
+fn error_kind(error: &RpcError) -> &'static str {
+    match error.untagged() {
+        RpcError::HttpError(_) => "http",
+        RpcError::Tls(_) => "tls",
+        RpcError::IoError(_) => "io",
+        RpcError::UnsupportedTransport(_) => "unsupported_transport",
+        RpcError::SerializeError(_) => "serialize",
+        RpcError::InvalidHeader(_) => "invalid_header",
+        RpcError::InvalidBatch => "invalid_batch",
+        RpcError::JsonError { .. } => "json",
+        RpcError::RpcError { .. } => "rpc",
+        RpcError::AmbiguousResponse { .. } => "ambiguous_response",
+        RpcError::MissingId => "missing_id",
+        RpcError::IdMismatch { .. } => "id_mismatch",
+        RpcError::MissingResponse => "missing_response",
+        RpcError::NullResponse => "null_response",
+        RpcError::WrongVariant { .. } => "wrong_variant",
+        RpcError::RateLimited { .. } => "rate_limited",
+        RpcError::Cancelled => "cancelled",
+        RpcError::EmptyResponse => "empty_response",
+        RpcError::NotJson { .. } => "not_json",
+        RpcError::BatchFailed(_) => "batch_failed",
+        RpcError::CannotDeserialize { .. } => "cannot_deserialize",
+        RpcError::Tagged { source, .. } => error_kind(source),
+    }
+}
+
+/// Escape a label value as required by the exposition format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.record("getblockcount", Duration::from_millis(3), None);
+        metrics.record(
+            "getblockcount",
+            Duration::from_millis(30),
+            Some(&RpcError::Cancelled),
+        );
+        metrics.record(
+            "say \"hi\"",
+            Duration::from_secs(60),
+            Some(&RpcError::MissingId),
+        );
+        let text = metrics.render();
+        assert!(text.contains("jsonrpc_requests_total{method=\"getblockcount\"} 2\n"));
+        assert!(
+            text.contains("jsonrpc_errors_total{method=\"getblockcount\",kind=\"cancelled\"} 1\n")
+        );
+        assert!(text.contains(
+            "jsonrpc_request_duration_seconds_bucket{method=\"getblockcount\",le=\"0.005\"} 1\n"
+        ));
+        assert!(text.contains(
+            "jsonrpc_request_duration_seconds_bucket{method=\"getblockcount\",le=\"0.05\"} 2\n"
+        ));
+        assert!(text.contains(
+            "jsonrpc_request_duration_seconds_bucket{method=\"say \\\"hi\\\"\",le=\"10\"} 0\n"
+        ));
+        assert!(text.contains(
+            "jsonrpc_request_duration_seconds_bucket{method=\"say \\\"hi\\\"\",le=\"+Inf\"} 1\n"
+        ));
+        assert!(
+            text.contains("jsonrpc_request_duration_seconds_count{method=\"getblockcount\"} 2\n")
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Scriptable local JSON-RPC server for integration tests, behind the `mock` feature.
+
+use crate::test_util::{Recorded, Reply, TestServer};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// JSON-RPC error code answered for methods without a mocked response
+pub const METHOD_NOT_FOUND: i64 = -32601;
+
+/// How a [`MockRpcServer`] answers calls to a method
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    outcome: Outcome,
+    delay: Duration,
+    times: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+enum Outcome {
+    Result(Value),
+    Error(Value),
+    Status(u16),
+}
+
+impl MockResponse {
+    /// Answer with `result`
+    pub fn result(result: impl Into<Value>) -> Self {
+        Self::new(Outcome::Result(result.into()))
+    }
+
+    /// Answer with a JSON-RPC error object
+    pub fn error(code: i64, message: impl Into<String>) -> Self {
+        Self::new(Outcome::Error(
+            json!({"code": code, "message": message.into()}),
+        ))
+    }
+
+    /// Answer with an HTTP `status` and an empty body, e.g. `503` to exercise retries
+    ///
+    /// Inside a batch, the call is answered with an error object instead.
+    pub fn status(status: u16) -> Self {
+        Self::new(Outcome::Status(status))
+    }
+
+    fn new(outcome: Outcome) -> Self {
+        MockResponse {
+            outcome,
+            delay: Duration::ZERO,
+            times: None,
+        }
+    }
+
+    /// Wait `delay` before answering, e.g. to exercise timeouts
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Answer only the next `times` calls this way; later calls get the next response
+    /// mocked for the method
+    pub fn times(mut self, times: usize) -> Self {
+        self.times = Some(times);
+        self
+    }
+}
+
+#[derive(Default)]
+struct State {
+    mocks: Vec<(String, MockResponse)>,
+    calls: Vec<Value>,
+}
+
+impl State {
+    /// Take the response for the next call to `method`
+    fn next(&mut self, method: &str) -> Option<MockResponse> {
+        let (_, mock) = self
+            .mocks
+            .iter_mut()
+            .find(|(m, mock)| m == method && mock.times != Some(0))?;
+        if let Some(times) = &mut mock.times {
+            *times -= 1;
+        }
+        Some(mock.clone())
+    }
+}
+
+/// Local HTTP server answering JSON-RPC calls with mocked responses per method
+///
+/// Single calls and batches are both answered; calls to methods without a mocked
+/// response get a "method not found" error ([`METHOD_NOT_FOUND`]). Responses to a
+/// method are used in the order they were mocked, so a few failures can be followed
+/// by a success:
+///
+/// ```
+/// use std::time::Duration;
+/// use throttled_json_rpc::{jsonrpc_client, MockResponse, MockRpcServer};
+///
+/// jsonrpc_client!(pub struct Client {
+///     single:
+///         pub fn getblockcount(&self) -> Result<u64>;
+///     enum:
+/// });
+///
+/// # fn main() {
+/// let server = MockRpcServer::start();
+/// server
+///     .mock("getblockcount", MockResponse::error(-28, "Loading block index").times(1))
+///     .mock("getblockcount", MockResponse::result(100).delay(Duration::from_millis(5)));
+///
+/// let client = Client::builder(server.uri())
+///     .max_retries(1)
+///     .retry_if(|e, _| matches!(e, RpcError::RpcError { .. }))
+///     .build();
+/// assert_eq!(client.getblockcount().unwrap(), 100);
+/// assert_eq!(server.call_count("getblockcount"), 2);
+/// # }
+/// ```
+pub struct MockRpcServer {
+    uri: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockRpcServer {
+    /// Start a server on a free local port; it runs until the process exits
+    pub fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let server = TestServer::start({
+            let state = state.clone();
+            move |req| answer(&state, req)
+        });
+        MockRpcServer {
+            uri: server.uri,
+            state,
+        }
+    }
+
+    /// URL to build clients with
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    /// Answer calls to `method` with `response`, after any responses mocked before
+    pub fn mock(&self, method: impl Into<String>, response: MockResponse) -> &Self {
+        self.lock().mocks.push((method.into(), response));
+        self
+    }
+
+    /// Every request object received so far, with batches split into their calls
+    pub fn calls(&self) -> Vec<Value> {
+        self.lock().calls.clone()
+    }
+
+    /// Number of calls to `method` received so far
+    pub fn call_count(&self, method: &str) -> usize {
+        self.lock()
+            .calls
+            .iter()
+            .filter(|call| call["method"] == method)
+            .count()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn answer(state: &Mutex<State>, req: &Recorded) -> Reply {
+    let Ok(body) = serde_json::from_slice::<Value>(&req.body) else {
+        let error = json!({"code": -32700, "message": "Parse error"});
+        return Reply::json(&json!({"error": error, "id": null}).to_string());
+    };
+    let batch = body.is_array();
+    let calls = match body {
+        Value::Array(calls) => calls,
+        call => vec![call],
+    };
+    let mut replies = Vec::with_capacity(calls.len());
+    let mut delay = Duration::ZERO;
+    {
+        let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+        for call in calls {
+            let mock = state.next(call["method"].as_str().unwrap_or_default());
+            delay = delay.max(mock.as_ref().map_or(Duration::ZERO, |m| m.delay));
+            let outcome = mock.map_or_else(
+                || Outcome::Error(json!({"code": METHOD_NOT_FOUND, "message": "Method not found"})),
+                |m| m.outcome,
+            );
+            replies.push((call["id"].clone(), outcome));
+            state.calls.push(call);
+        }
+    }
+    std::thread::sleep(delay);
+
+    if let (false, Some((_, Outcome::Status(status)))) = (batch, replies.first()) {
+        return Reply::json("").status(*status);
+    }
+    let mut envelopes = replies.into_iter().map(|(id, outcome)| match outcome {
+        Outcome::Result(result) => json!({"result": result, "error": null, "id": id}),
+        Outcome::Error(error) => json!({"result": null, "error": error, "id": id}),
+        Outcome::Status(status) => {
+            let error = json!({"code": -32603, "message": format!("HTTP {}", status)});
+            json!({"result": null, "error": error, "id": id})
+        }
+    });
+    let body = match batch {
+        true => Value::Array(envelopes.collect()),
+        false => envelopes.next().unwrap_or(Value::Null),
+    };
+    Reply::json(&body.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_answers() {
+        let server = MockRpcServer::start();
+        server
+            .mock(
+                "getblockcount",
+                MockResponse::error(-28, "Loading").times(1),
+            )
+            .mock("getblockcount", MockResponse::result(7));
+        let client = reqwest::blocking::Client::new();
+        let call = |body: Value| -> Value {
+            client
+                .post(server.uri())
+                .json(&body)
+                .send()
+                .unwrap()
+                .json()
+                .unwrap()
+        };
+
+        let reply = call(json!({"method": "getblockcount", "id": 1}));
+        assert_eq!(reply["error"]["code"], -28);
+        assert_eq!(reply["id"], 1);
+        let reply = call(json!([
+            {"method": "getblockcount", "id": 2},
+            {"method": "stop", "id": 3},
+        ]));
+        assert_eq!(reply[0]["result"], 7);
+        assert_eq!(reply[1]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(server.call_count("getblockcount"), 2);
+        assert_eq!(server.calls().len(), 3);
+    }
+}
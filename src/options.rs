@@ -0,0 +1,174 @@
+//! Per-call options accepted by generated clients.
+
+use crate::WhenThrottled;
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Shared flag used to abandon a blocking call.
+///
+/// Cancellation is checked while waiting on the rate limiter and the concurrency gate,
+/// and right before the request is sent. A request already on the wire is not
+/// interrupted.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel every call holding a clone of this token
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl From<Arc<AtomicBool>> for CancelToken {
+    fn from(flag: Arc<AtomicBool>) -> Self {
+        CancelToken(flag)
+    }
+}
+
+/// Options applied to a single RPC call via `client.with_options(..)`
+///
+/// The defaults reproduce the behavior of calling a method directly on the client.
+#[derive(Debug, Clone, Default)]
+pub struct CallOptions {
+    /// Priority used when waiting on the concurrency gate
+    pub priority: i32,
+    /// Token that aborts the call with `RpcError::Cancelled` while it is still waiting
+    pub cancel: Option<CancelToken>,
+    /// Key/value pairs passed to the `on_request` hook and attached to errors
+    pub metadata: Vec<(String, String)>,
+    /// Overrides the client's `ThrottleConfig::when_throttled`
+    pub when_throttled: Option<WhenThrottled>,
+    /// Send the call on its own instead of queueing it for an automatic batch
+    pub immediate: bool,
+    /// Request id used instead of the next one from the client's sequence
+    pub id: Option<Value>,
+    /// Path appended to the endpoint URL for this call
+    pub path: Option<String>,
+    /// Request slots the call takes from the rate limiter; `0` counts as one
+    pub cost: usize,
+    /// Send the call as an HTTP GET with the request in the query string
+    pub http_get: bool,
+    /// Ask for a gzip-compressed response with `Accept-Encoding: gzip`
+    pub gzip_response: bool,
+}
+
+impl CallOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Priority used when waiting on the concurrency gate (default: `0`)
+    ///
+    /// When the gate is saturated, waiters with a higher priority acquire the next
+    /// free slot first; waiters with equal priority are served in arrival order.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Abort the call with `RpcError::Cancelled` if `cancel` fires before it is sent
+    pub fn cancel_token(mut self, cancel: impl Into<CancelToken>) -> Self {
+        self.cancel = Some(cancel.into());
+        self
+    }
+
+    /// Tag the call with `key = value`, e.g. a tenant or correlation id
+    ///
+    /// Metadata is passed to the `on_request` hook, and any error returned by the call
+    /// is wrapped in `RpcError::Tagged` carrying it.
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.push((key.into(), value.into()));
+        self
+    }
+
+    /// Sleep or fail fast when the rate limit does not allow this call yet, instead of
+    /// the client's setting
+    ///
+    /// Calls queued for an automatic batch are sent by the batch and always wait.
+    pub fn when_throttled(mut self, mode: WhenThrottled) -> Self {
+        self.when_throttled = Some(mode);
+        self
+    }
+
+    /// Send the call right away, even when the client batches calls automatically
+    ///
+    /// The call skips the batch window but still waits for the rate limiter and
+    /// concurrency gate.
+    pub fn immediate(mut self) -> Self {
+        self.immediate = true;
+        self
+    }
+
+    /// Send the call with exactly this request id instead of the client's next one,
+    /// e.g. to replay a logged request or for idempotency keyed on the id
+    ///
+    /// The call is sent on its own, like an [`immediate`](Self::immediate) one, so the
+    /// id cannot collide with those of an automatic batch. Ids are not checked for
+    /// uniqueness against the client's own sequence.
+    pub fn id(mut self, id: impl Into<Value>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Send the call to `path` appended to the endpoint URL, e.g. `/eth` on a gateway
+    /// routing namespaces by path
+    ///
+    /// Overrides the path declared on the method with `at "/path"`. Like an
+    /// [`immediate`](Self::immediate) call, the call is sent on its own, since an
+    /// automatic batch goes to the endpoint URL itself.
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Charge the call as `cost` requests against the rate limiter (default: one),
+    /// e.g. for a batch whose calls the server counts one by one
+    ///
+    /// The call waits for a single slot as usual; the requests after it are held back
+    /// for the remaining `cost - 1` intervals.
+    pub fn cost(mut self, cost: usize) -> Self {
+        self.cost = cost;
+        self
+    }
+
+    /// Send the call as an HTTP GET instead of a POST, for servers that accept read-only
+    /// calls that way (see `ClientBuilder::http_get` for the encoding)
+    ///
+    /// Like an [`immediate`](Self::immediate) call, the call is sent on its own.
+    pub fn http_get(mut self) -> Self {
+        self.http_get = true;
+        self
+    }
+
+    /// Ask for a gzip-compressed response, e.g. for a call with a large result (see
+    /// `ClientBuilder::gzip_response`)
+    ///
+    /// Like an [`immediate`](Self::immediate) call, the call is sent on its own.
+    pub fn gzip_response(mut self) -> Self {
+        self.gzip_response = true;
+        self
+    }
+
+    /// Whether the call must be sent on its own rather than in an automatic batch
+    pub(crate) fn sent_alone(&self) -> bool {
+        self.immediate
+            || self.id.is_some()
+            || self.path.is_some()
+            || self.http_get
+            || self.gzip_response
+    }
+
+    /// Whether this call's cancel token has fired
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancelToken::is_cancelled)
+    }
+}
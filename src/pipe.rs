@@ -0,0 +1,102 @@
+//! JSON-RPC over Windows named pipes (`\\.\pipe\name`).
+//!
+//! Each request opens the pipe, writes the JSON request and reads back exactly one JSON
+//! value as the response. Ids are correlated as for HTTP.
+
+use crate::RpcError;
+use serde_json::value::RawValue;
+use std::io::{BufReader, Read, Write};
+
+/// Prefix of endpoint URIs naming a local pipe
+pub const NAMED_PIPE_PREFIX: &str = r"\\.\pipe\";
+
+/// Write `body` to `stream` and read one JSON value back as the response text, exactly
+/// as the server sent it
+#[cfg_attr(not(windows), allow(dead_code))]
+pub(crate) fn exchange<S: Read + Write>(mut stream: S, body: &[u8]) -> Result<String, RpcError> {
+    stream.write_all(body)?;
+    stream.flush()?;
+    // Unbuffered, the deserializer would issue one read per byte of the response
+    let reply = serde_json::Deserializer::from_reader(BufReader::new(stream))
+        .into_iter::<Box<RawValue>>()
+        .next()
+        .ok_or(RpcError::EmptyResponse)?
+        .map_err(|source| RpcError::JsonError {
+            source,
+            body: String::new(),
+        })?;
+    Ok(reply.get().to_owned())
+}
+
+/// Send `body` over the pipe at `path`
+#[cfg(windows)]
+pub(crate) fn request(path: &str, body: &[u8]) -> Result<String, RpcError> {
+    let pipe = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?;
+    exchange(pipe, body)
+}
+
+#[cfg(not(windows))]
+pub(crate) fn request(_path: &str, _body: &[u8]) -> Result<String, RpcError> {
+    Err(RpcError::UnsupportedTransport(
+        "named pipes are only available on Windows",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// In-memory duplex stream: reads from `input`, records writes in `output`
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_exchange_reads_one_value() {
+        let mut stream = Duplex {
+            input: Cursor::new(br#"{"result":1,"id":0} {"result":2,"id":1}"#.to_vec()),
+            output: Vec::new(),
+        };
+        let reply = exchange(&mut stream, br#"{"method":"getblockcount","id":0}"#).unwrap();
+        assert_eq!(reply, r#"{"result":1,"id":0}"#);
+        assert_eq!(stream.output, br#"{"method":"getblockcount","id":0}"#);
+
+        let stream = Duplex {
+            input: Cursor::new(Vec::new()),
+            output: Vec::new(),
+        };
+        assert!(matches!(
+            exchange(stream, b"{}"),
+            Err(RpcError::EmptyResponse)
+        ));
+
+        // Amounts beyond f64 precision and the key order survive
+        let reply = r#"{"result":{"b":123456789012345678901234567890,"a":0.1000000000000000055511},"id":0}"#;
+        let stream = Duplex {
+            input: Cursor::new(format!("{}\n", reply).into_bytes()),
+            output: Vec::new(),
+        };
+        assert_eq!(exchange(stream, b"{}").unwrap(), reply);
+    }
+}
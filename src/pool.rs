@@ -0,0 +1,83 @@
+//! Worker pool running many calls at once, from `client.call_many(..)`.
+
+use std::sync::{mpsc, Mutex, PoisonError};
+
+/// Worker threads used by `call_many` on clients without a concurrency cap
+pub const UNCAPPED_CALL_MANY_WORKERS: usize = 16;
+
+/// Run each of `requests` against `client` on `workers` threads, calling
+/// `on_result(index, &result)` on the calling thread as each one completes
+///
+/// Returns the results in request order. Workers take requests in order, so at most
+/// `workers` are in flight at once; a panicking request panics the caller once the
+/// others have finished.
+pub fn call_many<C, F, R>(
+    client: &C,
+    workers: usize,
+    requests: impl IntoIterator<Item = F>,
+    mut on_result: impl FnMut(usize, &R),
+) -> Vec<R>
+where
+    C: Sync + ?Sized,
+    F: FnOnce(&C) -> R + Send,
+    R: Send,
+{
+    let requests: Vec<F> = requests.into_iter().collect();
+    let mut results: Vec<Option<R>> = requests.iter().map(|_| None).collect();
+    let workers = workers.clamp(1, requests.len().max(1));
+    let queue = Mutex::new(requests.into_iter().enumerate());
+    std::thread::scope(|s| {
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..workers {
+            let (tx, queue) = (tx.clone(), &queue);
+            s.spawn(move || loop {
+                let next = queue.lock().unwrap_or_else(PoisonError::into_inner).next();
+                let Some((idx, request)) = next else { break };
+                if tx.send((idx, request(client))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(tx);
+        for (idx, result) in rx {
+            on_result(idx, &result);
+            results[idx] = Some(result);
+        }
+    });
+    results
+        .into_iter()
+        .map(|result| result.expect("every request produced a result"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_call_many_caps_workers() {
+        let active = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let peak = &peak;
+        let requests = (0..20).map(|i| {
+            move |active: &AtomicUsize| {
+                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(5));
+                active.fetch_sub(1, Ordering::SeqCst);
+                i * 2
+            }
+        });
+        let mut seen = Vec::new();
+        let results = call_many(&active, 3, requests, |idx, result| {
+            assert_eq!(*result, idx * 2);
+            seen.push(idx);
+        });
+        assert_eq!(results, (0..20).map(|i| i * 2).collect::<Vec<_>>());
+        seen.sort_unstable();
+        assert_eq!(seen, (0..20).collect::<Vec<_>>());
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+    }
+}
@@ -0,0 +1,508 @@
+//! Parsing of JSON-RPC response envelopes into typed results.
+
+use crate::{
+    hex, AmbiguousResponsePolicy, ClientConfig, IdBlock, JsonRpcErrorObject, RpcError, RpcResponse,
+};
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+fn json_error<'a>(
+    config: &'a ClientConfig,
+    body: &'a str,
+) -> impl FnOnce(serde_json::Error) -> RpcError + 'a {
+    move |source| RpcError::JsonError {
+        source,
+        body: config.error_body(body),
+    }
+}
+
+/// Deserialize `T` from `de`, whose input sits at `prefix` in the response.
+///
+/// With the `path-to-error` feature, data errors name the path of the offending field.
+fn deserialize<'de, T, D>(de: D, prefix: &str) -> Result<T, serde_json::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de, Error = serde_json::Error>,
+{
+    #[cfg(feature = "path-to-error")]
+    {
+        serde_path_to_error::deserialize(de).map_err(|e| {
+            let path = e.path().to_string();
+            let inner = e.into_inner();
+            match (inner.is_data(), path.as_str(), prefix) {
+                (false, _, _) | (true, ".", "") => inner,
+                (true, ".", prefix) => serde_json::Error::custom(format!("{}: {}", prefix, inner)),
+                (true, path, "") => serde_json::Error::custom(format!("{}: {}", path, inner)),
+                (true, path, prefix) => {
+                    let sep = if path.starts_with('[') { "" } else { "." };
+                    serde_json::Error::custom(format!("{}{}{}: {}", prefix, sep, path, inner))
+                }
+            }
+        })
+    }
+    #[cfg(not(feature = "path-to-error"))]
+    {
+        let _ = prefix;
+        T::deserialize(de)
+    }
+}
+
+/// Deserialize a `result` value, honoring the client's decoding options
+pub fn from_result_value<T: DeserializeOwned>(
+    config: &ClientConfig,
+    value: Value,
+) -> Result<T, serde_json::Error> {
+    if config.hex_numbers {
+        deserialize(hex::HexNumbers(value), "result")
+    } else {
+        deserialize(value, "result")
+    }
+}
+
+/// Pick the outcome of an envelope according to the client's ambiguity policy.
+///
+/// Returns `Ok(None)` when the envelope carries neither a result nor an error.
+fn resolve<T>(
+    config: &ClientConfig,
+    result: Option<T>,
+    error: Option<Value>,
+) -> Result<Option<T>, RpcError> {
+    match (result, error) {
+        (Some(result), Some(error)) => match config.ambiguous_response {
+            AmbiguousResponsePolicy::PreferError => Err(RpcError::RpcError { error }),
+            AmbiguousResponsePolicy::PreferResult => Ok(Some(result)),
+            AmbiguousResponsePolicy::Reject => Err(RpcError::AmbiguousResponse { error }),
+        },
+        (_, Some(error)) => Err(RpcError::RpcError { error }),
+        (result, None) => Ok(result),
+    }
+}
+
+/// Descend into the client's `result_path` and apply its `on_response_json` rewrite to
+/// a result taken out of its envelope
+fn transform(config: &ClientConfig, mut value: Value) -> Result<Value, serde_json::Error> {
+    for key in &config.result_path {
+        value = match value {
+            Value::Object(mut map) => map.remove(key),
+            _ => None,
+        }
+        .ok_or_else(|| serde_json::Error::custom(format!("result has no `{}` field", key)))?;
+    }
+    if let Some(transform) = &config.on_response_json {
+        transform.apply(&mut value);
+    }
+    Ok(value)
+}
+
+/// Apply [`transform`] and deserialize the result
+fn decode<T: DeserializeOwned>(
+    config: &ClientConfig,
+    value: Value,
+) -> Result<T, serde_json::Error> {
+    from_result_value(config, transform(config, value)?)
+}
+
+/// Parse the response to a single call
+pub fn parse_single<T: DeserializeOwned>(config: &ClientConfig, body: &str) -> Result<T, RpcError> {
+    if !config.hex_numbers && config.on_response_json.is_none() && config.result_path.is_empty() {
+        let envelope: RpcResponse<T> =
+            deserialize(&mut serde_json::Deserializer::from_str(body), "")
+                .map_err(json_error(config, body))?;
+        return resolve(config, envelope.result, envelope.error)?.ok_or(RpcError::NullResponse);
+    }
+    let envelope: RpcResponse<Value> =
+        serde_json::from_str(body).map_err(json_error(config, body))?;
+    let value = resolve(config, envelope.result, envelope.error)?.ok_or(RpcError::NullResponse)?;
+    decode(config, value).map_err(json_error(config, body))
+}
+
+/// The body of a single call's response, kept alive so that its result can be
+/// deserialized into types borrowing from it, from `client.with_response(..)`
+#[derive(Debug)]
+pub struct RawResponse<'a> {
+    config: &'a ClientConfig,
+    text: String,
+}
+
+impl<'a> RawResponse<'a> {
+    pub fn new(config: &'a ClientConfig, text: String) -> Self {
+        RawResponse { config, text }
+    }
+
+    /// The response body as received
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Deserialize the `result`, borrowing strings from the body where `T` allows
+    ///
+    /// Fields of type `&str` borrow only strings without escape sequences; use
+    /// `#[serde(borrow)] Cow<'_, str>` for fields that may contain some. Errors are
+    /// handled as for typed calls, but `hex_numbers`, `on_response_json` and
+    /// `result_path` do not apply, since they work on an owned copy of the result.
+    pub fn result<'de, T: Deserialize<'de>>(&'de self) -> Result<T, RpcError> {
+        let envelope: RpcResponse<T> =
+            deserialize(&mut serde_json::Deserializer::from_str(&self.text), "")
+                .map_err(json_error(self.config, &self.text))?;
+        resolve(self.config, envelope.result, envelope.error)?.ok_or(RpcError::NullResponse)
+    }
+}
+
+/// Parse the response to a single call of a method marked with
+/// `ClientBuilder::bare_result`: a body that is not a JSON-RPC envelope is the result
+/// itself.
+pub fn parse_bare<T: DeserializeOwned>(config: &ClientConfig, body: &str) -> Result<T, RpcError> {
+    let value: Value = serde_json::from_str(body).map_err(json_error(config, body))?;
+    let is_envelope = value
+        .as_object()
+        .is_some_and(|o| o.contains_key("result") || o.contains_key("error"));
+    if is_envelope {
+        return parse_single(config, body);
+    }
+    decode(config, value).map_err(json_error(config, body))
+}
+
+/// Check that the single response `body` answers the request with id `expected`
+pub fn check_id(config: &ClientConfig, expected: &Value, body: &str) -> Result<(), RpcError> {
+    let envelope: RpcResponse<serde::de::IgnoredAny> =
+        serde_json::from_str(body).map_err(json_error(config, body))?;
+    let got = envelope.id.unwrap_or_default();
+    let unattributed = got.is_null() && envelope.error.is_some_and(|e| !e.is_null());
+    if got == *expected || unattributed {
+        return Ok(());
+    }
+    Err(RpcError::IdMismatch {
+        expected: expected.clone(),
+        got,
+    })
+}
+
+/// One entry of a batch response: the position of the request it answers (if its id
+/// matches one of `ids`) and that entry's own outcome
+pub type BatchEntry<T> = (Option<usize>, Result<T, RpcError>);
+
+/// Split a batch response into its entries.
+///
+/// A server that rejects the batch as a whole (e.g. a parse error) answers with a
+/// single error object instead of an array; that error is returned as
+/// `RpcError::RpcError`.
+pub fn batch_replies<T: DeserializeOwned>(
+    config: &ClientConfig,
+    body: &str,
+) -> Result<Vec<T>, RpcError> {
+    let value: Value = serde_json::from_str(body).map_err(json_error(config, body))?;
+    if let Value::Object(mut reply) = value {
+        if let Some(error) = reply.remove("error").filter(|e| !e.is_null()) {
+            return Err(RpcError::RpcError { error });
+        }
+        return Err(json_error(config, body)(serde_json::Error::custom(
+            "expected an array of batch responses",
+        )));
+    }
+    serde_json::from_value(value).map_err(json_error(config, body))
+}
+
+/// Parse a batch response into each entry's request position and its own outcome
+pub fn parse_batch_results<T: DeserializeOwned>(
+    config: &ClientConfig,
+    ids: &IdBlock<'_>,
+    body: &str,
+) -> Result<Vec<BatchEntry<T>>, RpcError> {
+    let envelopes: Vec<RpcResponse<Value>> = batch_replies(config, body)?;
+    let mut entries: Vec<BatchEntry<T>> = envelopes
+        .into_iter()
+        .map(|reply| {
+            let result = match resolve(config, reply.result, reply.error) {
+                Ok(Some(value)) => decode(config, value).map_err(json_error(config, body)),
+                Ok(None) => Err(RpcError::RpcError { error: Value::Null }),
+                Err(e) => Err(e),
+            };
+            (reply.id.and_then(|id| ids.index_of(&id)), result)
+        })
+        .collect();
+    // A server answers a request it could not parse with an error and a null id; when
+    // exactly one request is left unanswered, that error is its answer
+    let unattributed: Vec<usize> = (0..entries.len())
+        .filter(|&i| entries[i].0.is_none() && entries[i].1.is_err())
+        .collect();
+    let unanswered: Vec<usize> = (0..ids.len())
+        .filter(|&idx| entries.iter().all(|(id, _)| *id != Some(idx)))
+        .collect();
+    if let ([entry], [idx]) = (&unattributed[..], &unanswered[..]) {
+        entries[*entry].0 = Some(*idx);
+    }
+    Ok(entries)
+}
+
+/// Parse a batch response into `(position, result)` pairs, failing on the first failed entry
+pub fn parse_batch<T: DeserializeOwned>(
+    config: &ClientConfig,
+    ids: &IdBlock<'_>,
+    body: &str,
+) -> Result<Vec<(usize, T)>, RpcError> {
+    parse_batch_results(config, ids, body)?
+        .into_iter()
+        .map(|(id, result)| {
+            let result = result?;
+            Ok((id.ok_or(RpcError::MissingId)?, result))
+        })
+        .collect()
+}
+
+/// The outcome of a batch, from `batcher().send_response()`: each call's result or
+/// the error object the server answered it with, in submission order
+///
+/// Only errors sent by the server are kept per entry. Transport failures, and entries
+/// that could not be decoded or were not answered at all, fail the batch as a whole.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchResponse<T> {
+    results: Vec<Result<T, JsonRpcErrorObject>>,
+}
+
+impl<T> BatchResponse<T> {
+    /// Split per-entry outcomes (as from `send_each`) into results and server errors
+    ///
+    /// Returns the first entry error that did not come from the server.
+    pub fn from_results(results: Vec<Result<T, RpcError>>) -> Result<Self, RpcError> {
+        let results = results
+            .into_iter()
+            .map(|result| match result {
+                Ok(value) => Ok(Ok(value)),
+                Err(e) => e.error_object().map(Err).ok_or(e),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(BatchResponse { results })
+    }
+
+    /// Number of calls in the batch
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    /// The successful results with their positions in the batch
+    pub fn successes(&self) -> impl Iterator<Item = (usize, &T)> + '_ {
+        self.results
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, result)| result.as_ref().ok().map(|value| (idx, value)))
+    }
+
+    /// The server's error objects with the positions of the calls they answer
+    pub fn errors(&self) -> impl Iterator<Item = (usize, &JsonRpcErrorObject)> + '_ {
+        self.results
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, result)| result.as_ref().err().map(|error| (idx, error)))
+    }
+
+    /// Each call's outcome in submission order
+    pub fn into_results(self) -> Vec<Result<T, JsonRpcErrorObject>> {
+        self.results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOTH: &str = r#"{"result":1,"error":{"code":-1,"message":"oops"},"id":0}"#;
+
+    fn config(policy: AmbiguousResponsePolicy) -> ClientConfig {
+        ClientConfig {
+            ambiguous_response: policy,
+            ..ClientConfig::new("http://localhost")
+        }
+    }
+
+    #[test]
+    fn test_ambiguous_prefer_error() {
+        let config = config(AmbiguousResponsePolicy::default());
+        assert!(matches!(
+            parse_single::<u64>(&config, BOTH),
+            Err(RpcError::RpcError { .. })
+        ));
+        let batch = format!("[{}]", BOTH);
+        assert!(matches!(
+            parse_batch::<u64>(&config, &IdBlock::new(0, 1, None), &batch),
+            Err(RpcError::RpcError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_ambiguous_prefer_result() {
+        let config = config(AmbiguousResponsePolicy::PreferResult);
+        assert_eq!(parse_single::<u64>(&config, BOTH).unwrap(), 1);
+        let batch = format!("[{}]", BOTH);
+        assert_eq!(
+            parse_batch::<u64>(&config, &IdBlock::new(0, 1, None), &batch).unwrap(),
+            vec![(0, 1)]
+        );
+    }
+
+    #[test]
+    fn test_ambiguous_reject() {
+        let config = config(AmbiguousResponsePolicy::Reject);
+        match parse_single::<u64>(&config, BOTH) {
+            Err(RpcError::AmbiguousResponse { error }) => assert_eq!(error["code"], -1),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // A null error next to a result is not ambiguous
+        let ok = r#"{"result":1,"error":null,"id":0}"#;
+        assert_eq!(parse_single::<u64>(&config, ok).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_parse_bare() {
+        let config = ClientConfig::new("http://localhost");
+        assert_eq!(parse_bare::<u64>(&config, "42").unwrap(), 42);
+        assert_eq!(
+            parse_bare::<Vec<u64>>(&config, "[1, 2]").unwrap(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            parse_bare::<Value>(&config, r#"{"height":5}"#).unwrap(),
+            serde_json::json!({"height": 5})
+        );
+        assert_eq!(
+            parse_bare::<u64>(&config, r#"{"result":7,"id":0}"#).unwrap(),
+            7
+        );
+        assert!(matches!(
+            parse_bare::<u64>(&config, r#"{"result":null,"error":"boom","id":0}"#),
+            Err(RpcError::RpcError { .. })
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "path-to-error")]
+    fn test_error_path() {
+        #[derive(serde::Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Vout {
+            value: f64,
+        }
+        #[derive(serde::Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Tx {
+            vout: Vec<Vout>,
+        }
+        #[derive(serde::Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Block {
+            tx: Vec<Tx>,
+        }
+
+        let body =
+            r#"{"result":{"tx":[{"vout":[{"value":1.0}]},{"vout":[{"value":"x"}]}]},"id":0}"#;
+        let mut config = ClientConfig::new("http://localhost");
+        let err = parse_single::<Block>(&config, body).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("result.tx[1].vout[0].value: invalid type"),
+            "{}",
+            err
+        );
+
+        config.hex_numbers = true;
+        let err = parse_single::<Block>(&config, body).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("result.tx[1].vout[0].value: invalid type"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_error_body_cap() {
+        let mut config = ClientConfig::new("http://localhost");
+        config.max_error_body = 16;
+        let body = format!(r#"{{"result":"{}","id":0}}"#, "é".repeat(100));
+        let Err(RpcError::JsonError { body: kept, .. }) = parse_single::<u64>(&config, &body)
+        else {
+            panic!("expected a JSON error");
+        };
+        assert_eq!(
+            kept,
+            format!(r#"{{"result":"{}... (220 bytes total)"#, "é".repeat(2))
+        );
+
+        config.max_error_body = usize::MAX;
+        let Err(RpcError::JsonError { body: kept, .. }) = parse_single::<u64>(&config, &body)
+        else {
+            panic!("expected a JSON error");
+        };
+        assert_eq!(kept, body);
+    }
+
+    #[test]
+    fn test_check_id() {
+        let config = ClientConfig::new("http://localhost");
+        let id = serde_json::json!(7);
+        assert!(check_id(&config, &id, r#"{"result":{"a":[1]},"id":7}"#).is_ok());
+        match check_id(&config, &id, r#"{"result":1,"id":6}"#) {
+            Err(RpcError::IdMismatch { expected, got }) => {
+                assert_eq!(expected, 7);
+                assert_eq!(got, 6);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(matches!(
+            check_id(&config, &id, r#"{"result":1}"#),
+            Err(RpcError::IdMismatch {
+                got: Value::Null,
+                ..
+            })
+        ));
+        let parse_error = r#"{"error":{"code":-32700,"message":"Parse error"},"id":null}"#;
+        assert!(check_id(&config, &id, parse_error).is_ok());
+    }
+
+    #[test]
+    fn test_result_path() {
+        let mut config = ClientConfig::new("http://localhost");
+        config.result_path = vec!["data".to_string(), "inner".to_string()];
+        let body = r#"{"result":{"data":{"inner":[1,2]}},"id":0}"#;
+        assert_eq!(parse_single::<Vec<u64>>(&config, body).unwrap(), vec![1, 2]);
+        let batch = format!("[{}]", body);
+        assert_eq!(
+            parse_batch::<Vec<u64>>(&config, &IdBlock::new(0, 1, None), &batch).unwrap(),
+            vec![(0, vec![1, 2])]
+        );
+
+        let Err(RpcError::JsonError { source, .. }) =
+            parse_single::<u64>(&config, r#"{"result":{"data":5},"id":0}"#)
+        else {
+            panic!("expected a JSON error");
+        };
+        assert!(source.to_string().contains("result has no `inner` field"));
+    }
+
+    #[test]
+    fn test_batch_response() {
+        let error = serde_json::json!({"code": -8, "message": "Block height out of range"});
+        let response =
+            BatchResponse::from_results(vec![Ok(1u64), Err(RpcError::RpcError { error }), Ok(3)])
+                .unwrap();
+        assert_eq!(response.len(), 3);
+        assert_eq!(response.successes().collect::<Vec<_>>(), [(0, &1), (2, &3)]);
+        let errors: Vec<_> = response.errors().collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].0, errors[0].1.code), (1, -8));
+        let results = response.into_results();
+        assert_eq!(
+            results[1].as_ref().unwrap_err().message,
+            "Block height out of range"
+        );
+
+        assert!(matches!(
+            BatchResponse::from_results(vec![Ok(1u64), Err(RpcError::MissingResponse)]),
+            Err(RpcError::MissingResponse)
+        ));
+    }
+}
@@ -0,0 +1,331 @@
+//! Incremental parsing of array results, yielding one element at a time instead of
+//! buffering the whole response body.
+//!
+//! The response is scanned byte by byte: only the envelope's `error` member and one
+//! array element at a time are ever held in memory.
+
+use crate::{hex, GateGuard, RpcError};
+use serde::de::{DeserializeOwned, Error as _};
+use serde_json::Value;
+use std::io::{BufReader, Bytes, Read};
+use std::marker::PhantomData;
+
+/// Iterator over the elements of an array `result`, read from the response as needed
+///
+/// Yields an error and then stops if the body turns out to be malformed or the
+/// connection fails mid-stream. A stream from a client holds the call's concurrency
+/// slot until it is dropped.
+pub struct ResultStream<'a, T, R = reqwest::blocking::Response> {
+    scanner: Scanner<R>,
+    hex_numbers: bool,
+    started: bool,
+    done: bool,
+    _slot: Option<GateGuard<'a>>,
+    element: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned, R: Read> ResultStream<'static, T, R> {
+    /// Read the response envelope from `reader` up to the start of its `result` array.
+    ///
+    /// Fails with the server's error, `RpcError::NullResponse` for a null result, or a
+    /// JSON error if the result is not an array.
+    pub fn new(reader: R, hex_numbers: bool) -> Result<Self, RpcError> {
+        ResultStream::with_slot(BufReader::new(reader), hex_numbers, None)
+    }
+}
+
+impl<'a, T: DeserializeOwned, R: Read> ResultStream<'a, T, R> {
+    /// [`new`](ResultStream::new) for an already buffered `reader`, holding `slot` until
+    /// the stream is dropped
+    pub(crate) fn with_slot(
+        reader: BufReader<R>,
+        hex_numbers: bool,
+        slot: Option<GateGuard<'a>>,
+    ) -> Result<Self, RpcError> {
+        let mut scanner = Scanner::new(reader);
+        scanner.open_result()?;
+        Ok(ResultStream {
+            scanner,
+            hex_numbers,
+            started: false,
+            done: false,
+            _slot: slot,
+            element: PhantomData,
+        })
+    }
+
+    fn next_element(&mut self) -> Result<Option<T>, RpcError> {
+        let mut first = self.scanner.non_ws()?;
+        if first == b']' {
+            return Ok(None);
+        }
+        if self.started {
+            if first != b',' {
+                return Err(malformed("expected `,` or `]` between array elements"));
+            }
+            first = self.scanner.non_ws()?;
+        }
+        self.started = true;
+        let mut raw = Vec::new();
+        self.scanner.value(first, &mut raw)?;
+        let parsed = if self.hex_numbers {
+            serde_json::from_slice::<Value>(&raw).and_then(|v| T::deserialize(hex::HexNumbers(v)))
+        } else {
+            serde_json::from_slice(&raw)
+        };
+        parsed.map(Some).map_err(|source| RpcError::JsonError {
+            source,
+            body: String::from_utf8_lossy(&raw).into_owned(),
+        })
+    }
+}
+
+impl<T: DeserializeOwned, R: Read> Iterator for ResultStream<'_, T, R> {
+    type Item = Result<T, RpcError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.next_element();
+        if !matches!(next, Ok(Some(_))) {
+            self.done = true;
+        }
+        next.transpose()
+    }
+}
+
+fn malformed(msg: &str) -> RpcError {
+    RpcError::JsonError {
+        source: serde_json::Error::custom(msg),
+        body: String::new(),
+    }
+}
+
+/// Byte-level reader of JSON values with one byte of lookahead
+struct Scanner<R> {
+    bytes: Bytes<BufReader<R>>,
+    peeked: Option<u8>,
+}
+
+impl<R: Read> Scanner<R> {
+    fn new(reader: BufReader<R>) -> Self {
+        Scanner {
+            bytes: reader.bytes(),
+            peeked: None,
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, RpcError> {
+        if let Some(b) = self.peeked.take() {
+            return Ok(b);
+        }
+        match self.bytes.next() {
+            Some(b) => Ok(b?),
+            None => Err(malformed("unexpected end of response")),
+        }
+    }
+
+    /// Next byte that is not whitespace
+    fn non_ws(&mut self) -> Result<u8, RpcError> {
+        loop {
+            match self.byte()? {
+                b' ' | b'\n' | b'\r' | b'\t' => continue,
+                b => return Ok(b),
+            }
+        }
+    }
+
+    /// Skip a UTF-8 byte order mark at the very start of the body, as `read_body` does
+    fn skip_bom(&mut self) -> Result<(), RpcError> {
+        let first = self.byte()?;
+        if first != 0xef {
+            self.peeked = Some(first);
+            return Ok(());
+        }
+        match (self.byte()?, self.byte()?) {
+            (0xbb, 0xbf) => Ok(()),
+            _ => Err(malformed("response is not a JSON object")),
+        }
+    }
+
+    /// Walk the envelope's members until the `result` array opens
+    fn open_result(&mut self) -> Result<(), RpcError> {
+        self.skip_bom()?;
+        if self.non_ws()? != b'{' {
+            return Err(malformed("response is not a JSON object"));
+        }
+        let mut error = Value::Null;
+        let mut result = Value::Null;
+        let mut raw = Vec::new();
+        loop {
+            match self.non_ws()? {
+                b'"' => {}
+                b'}' => break,
+                _ => return Err(malformed("expected a member name")),
+            }
+            raw.clear();
+            self.value(b'"', &mut raw)?;
+            let key: String =
+                serde_json::from_slice(&raw).map_err(|source| RpcError::JsonError {
+                    source,
+                    body: String::from_utf8_lossy(&raw).into_owned(),
+                })?;
+            if self.non_ws()? != b':' {
+                return Err(malformed("expected `:` after a member name"));
+            }
+            let first = self.non_ws()?;
+            if key == "result" && first == b'[' {
+                if !error.is_null() {
+                    return Err(RpcError::RpcError { error });
+                }
+                return Ok(());
+            }
+            raw.clear();
+            self.value(first, &mut raw)?;
+            let target = match key.as_str() {
+                "error" => &mut error,
+                "result" => &mut result,
+                _ => {
+                    raw.clear();
+                    match self.non_ws()? {
+                        b',' => continue,
+                        b'}' => break,
+                        _ => return Err(malformed("expected `,` or `}` after a member")),
+                    }
+                }
+            };
+            *target = serde_json::from_slice(&raw).map_err(|source| RpcError::JsonError {
+                source,
+                body: String::from_utf8_lossy(&raw).into_owned(),
+            })?;
+            match self.non_ws()? {
+                b',' => {}
+                b'}' => break,
+                _ => return Err(malformed("expected `,` or `}` after a member")),
+            }
+        }
+        if !error.is_null() {
+            return Err(RpcError::RpcError { error });
+        }
+        match result {
+            Value::Null => Err(RpcError::NullResponse),
+            _ => Err(malformed("result is not an array")),
+        }
+    }
+
+    /// Append the complete value starting with `first` to `out`
+    fn value(&mut self, first: u8, out: &mut Vec<u8>) -> Result<(), RpcError> {
+        out.push(first);
+        match first {
+            b'"' => self.string_rest(out),
+            b'{' | b'[' => {
+                let mut depth = 1;
+                while depth > 0 {
+                    let b = self.byte()?;
+                    out.push(b);
+                    match b {
+                        b'"' => self.string_rest(out)?,
+                        b'{' | b'[' => depth += 1,
+                        b'}' | b']' => depth -= 1,
+                        _ => {}
+                    }
+                }
+                Ok(())
+            }
+            // Numbers and literals end at the first delimiter, which is put back
+            _ => loop {
+                match self.bytes.next().transpose()? {
+                    Some(b @ (b',' | b']' | b'}' | b' ' | b'\n' | b'\r' | b'\t')) => {
+                        self.peeked = Some(b);
+                        return Ok(());
+                    }
+                    Some(b) => out.push(b),
+                    None => return Ok(()),
+                }
+            },
+        }
+    }
+
+    /// Append the rest of a string whose opening quote was already read
+    fn string_rest(&mut self, out: &mut Vec<u8>) -> Result<(), RpcError> {
+        loop {
+            let b = self.byte()?;
+            out.push(b);
+            match b {
+                b'\\' => out.push(self.byte()?),
+                b'"' => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn stream<T: DeserializeOwned>(body: &str) -> Result<Vec<Result<T, RpcError>>, RpcError> {
+        ResultStream::new(Cursor::new(body.as_bytes().to_vec()), false).map(Iterator::collect)
+    }
+
+    #[test]
+    fn test_stream_elements() {
+        let items = stream::<serde_json::Value>(
+            r#"{"error":null, "result":[ {"txid":"a]\"}","amount":1.5}, [1,[2]], -3e2 ,"x",true ],"id":0}"#,
+        )
+        .unwrap();
+        let items: Vec<_> = items.into_iter().map(Result::unwrap).collect();
+        assert_eq!(
+            items,
+            vec![
+                serde_json::json!({"txid": "a]\"}", "amount": 1.5}),
+                serde_json::json!([1, [2]]),
+                serde_json::json!(-300.0),
+                serde_json::json!("x"),
+                serde_json::json!(true),
+            ]
+        );
+
+        assert!(stream::<u64>(r#"{"result":[],"id":0}"#).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_stream_errors() {
+        assert!(matches!(
+            stream::<u64>(r#"{"result":null,"error":{"code":-1},"id":0}"#),
+            Err(RpcError::RpcError { .. })
+        ));
+        assert!(matches!(
+            stream::<u64>(r#"{"id":0,"result":null}"#),
+            Err(RpcError::NullResponse)
+        ));
+        assert!(matches!(
+            stream::<u64>(r#"{"result":5,"id":0}"#),
+            Err(RpcError::JsonError { .. })
+        ));
+
+        // A bad element is reported, then the stream ends
+        let items = stream::<u64>(r#"{"result":[1,"two",3]}"#).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].as_ref().unwrap(), &1);
+        assert!(matches!(items[1], Err(RpcError::JsonError { .. })));
+
+        // Truncated body
+        let items = stream::<u64>(r#"{"result":[1,2"#).unwrap();
+        assert!(items.last().unwrap().is_err());
+
+        // Only a leading byte order mark is skipped
+        let items = stream::<u64>("\u{feff}{\"result\":[1,2]}").unwrap();
+        assert_eq!(items.len(), 2);
+        assert!(matches!(
+            stream::<u64>(" \u{feff}{\"result\":[1]}"),
+            Err(RpcError::JsonError { .. })
+        ));
+        let items = stream::<u64>("{\"result\":[1,\u{feff}2]}").unwrap();
+        assert!(matches!(items[1], Err(RpcError::JsonError { .. })));
+        let items = stream::<String>("{\"result\":[\"\u{feff}\"]}").unwrap();
+        assert_eq!(items[0].as_ref().unwrap(), "\u{feff}");
+    }
+}
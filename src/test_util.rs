@@ -0,0 +1,221 @@
+//! Minimal HTTP server used by the crate's own tests and by `MockRpcServer`.
+
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+/// A request as seen by the test server
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Recorded {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Body parsed as JSON, decompressing gzipped bodies
+    pub fn json(&self) -> serde_json::Value {
+        if self.header("content-encoding") == Some("gzip") {
+            let decoder = flate2::read::GzDecoder::new(self.body.as_slice());
+            return serde_json::from_reader(decoder).unwrap();
+        }
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+/// A canned HTTP response
+#[derive(Default)]
+pub struct Reply {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Send the body with `Transfer-Encoding: chunked` in chunks of this many bytes
+    pub chunk_size: Option<usize>,
+    /// Close the connection halfway through a chunked body
+    pub incomplete: bool,
+}
+
+impl Reply {
+    pub fn json(body: &str) -> Self {
+        Reply {
+            status: 200,
+            headers: vec![("Content-Type".into(), "application/json".into())],
+            body: body.as_bytes().to_vec(),
+            chunk_size: None,
+            incomplete: false,
+        }
+    }
+
+    pub fn status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Send the body in chunks of `size` bytes, flushed separately, without a
+    /// `Content-Length`
+    pub fn chunked(mut self, size: usize) -> Self {
+        self.chunk_size = Some(size);
+        self
+    }
+
+    /// Like [`chunked`](Self::chunked), but close the connection after sending half of
+    /// the body
+    pub fn chunked_incomplete(mut self, size: usize) -> Self {
+        self.incomplete = true;
+        self.chunked(size)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+type Handler = dyn Fn(&Recorded) -> Reply + Send + Sync;
+
+/// Test server answering every request with the given handler
+pub struct TestServer {
+    pub uri: String,
+    pub requests: Arc<Mutex<Vec<Recorded>>>,
+}
+
+impl TestServer {
+    pub fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&Recorded) -> Reply + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { break };
+                let handler = handler.clone();
+                let recorded = recorded.clone();
+                std::thread::spawn(move || serve(stream, &*handler, &recorded));
+            }
+        });
+        TestServer { uri, requests }
+    }
+
+    /// Server answering every request with the same JSON body
+    pub fn json(body: &'static str) -> Self {
+        Self::start(move |_| Reply::json(body))
+    }
+
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// HTTPS server presenting a self-signed certificate for `localhost`, which clients
+/// reject during the TLS handshake
+#[cfg(feature = "native-tls")]
+pub fn untrusted_tls_server() -> String {
+    let identity = native_tls::Identity::from_pkcs8(
+        include_bytes!("../tests/fixtures/self_signed.crt"),
+        include_bytes!("../tests/fixtures/self_signed.key"),
+    )
+    .unwrap();
+    let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let uri = format!(
+        "https://localhost:{}",
+        listener.local_addr().unwrap().port()
+    );
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { break };
+            let _ = acceptor.accept(stream);
+        }
+    });
+    uri
+}
+
+fn serve(stream: TcpStream, handler: &Handler, recorded: &Mutex<Vec<Recorded>>) {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+        return;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+    let mut headers = Vec::new();
+    loop {
+        // Header values may hold bytes that are not UTF-8
+        let mut raw = Vec::new();
+        if reader.read_until(b'\n', &mut raw).unwrap_or(0) == 0 {
+            return;
+        }
+        let line = String::from_utf8_lossy(&raw);
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            headers.push((k.trim().to_string(), v.trim().to_string()));
+        }
+    }
+    let len = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; len];
+    if reader.read_exact(&mut body).is_err() {
+        return;
+    }
+    let req = Recorded {
+        method,
+        path,
+        headers,
+        body,
+    };
+    recorded.lock().unwrap().push(req.clone());
+    let reply = handler(&req);
+
+    let mut out = format!("HTTP/1.1 {} TEST\r\n", reply.status);
+    for (k, v) in &reply.headers {
+        out.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    let mut stream = reader.into_inner();
+    let Some(size) = reply.chunk_size else {
+        out.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            reply.body.len()
+        ));
+        let _ = stream.write_all(out.as_bytes());
+        let _ = stream.write_all(&reply.body);
+        return;
+    };
+    out.push_str("Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n");
+    let _ = stream.write_all(out.as_bytes());
+    let len = match reply.incomplete {
+        true => reply.body.len() / 2,
+        false => reply.body.len(),
+    };
+    for chunk in reply.body[..len].chunks(size.max(1)) {
+        let _ = stream.write_all(format!("{:x}\r\n", chunk.len()).as_bytes());
+        let _ = stream.write_all(chunk);
+        let _ = stream.write_all(b"\r\n");
+        let _ = stream.flush();
+        // Let the client see each chunk in a separate read
+        std::thread::sleep(std::time::Duration::from_millis(2));
+    }
+    if !reply.incomplete {
+        let _ = stream.write_all(b"0\r\n\r\n");
+    }
+}
@@ -0,0 +1,1322 @@
+//! Throttling primitives shared by generated clients.
+
+use crate::{CallOptions, CancelToken, RpcError, ThrottleConfig};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// How often blocked waiters re-check their cancellation token
+const CANCEL_POLL: Duration = Duration::from_millis(10);
+
+/// Timestamps of the threads using a [`ThrottleScope::PerThread`] limiter, each owned
+/// by its thread
+type ThreadTimestamps = Mutex<Vec<Weak<Mutex<Instant>>>>;
+
+/// A thread's own timestamp for the limiter whose [`ThreadTimestamps`] it is listed in
+type OwnTimestamp = (Weak<ThreadTimestamps>, Arc<Mutex<Instant>>);
+
+thread_local! {
+    /// This thread's last request timestamp for each live per-thread limiter, dropped
+    /// with the thread
+    static LAST_REQ: RefCell<Vec<OwnTimestamp>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Sleep for `duration`, returning [`RpcError::Cancelled`] as soon as `cancel` is
+/// triggered
+pub(crate) fn sleep_cancellable(
+    duration: Duration,
+    cancel: Option<&CancelToken>,
+) -> Result<(), RpcError> {
+    let Some(cancel) = cancel else {
+        std::thread::sleep(duration);
+        return Ok(());
+    };
+    let deadline = Instant::now() + duration;
+    loop {
+        if cancel.is_cancelled() {
+            return Err(RpcError::Cancelled);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(());
+        }
+        std::thread::sleep((deadline - now).min(CANCEL_POLL));
+    }
+}
+
+/// Span of the rolling window [`Throttle::saturation`] is computed over
+pub const SATURATION_WINDOW: Duration = Duration::from_secs(10);
+
+/// Number of slices `SATURATION_WINDOW` is kept in; the window advances a slice at a time
+const SATURATION_SLICES: u32 = 10;
+
+/// Source of time for the rate limiter, replaceable to test throttling without real
+/// sleeps
+///
+/// `sleep` must not return before `now()` has advanced by `duration`, or the rate
+/// limiter spins until it has.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+/// The system clock: [`Instant::now`] and [`std::thread::sleep`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InstantClock;
+
+impl Clock for InstantClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+/// The system clock, sleeping through all but the last `threshold` of each wait and
+/// spinning (yielding to other threads) for the rest
+///
+/// `std::thread::sleep` only wakes up on the operating system's timer ticks: up to
+/// about 15.6ms late on Windows at its default timer resolution, and typically a
+/// fraction of a millisecond to a few milliseconds elsewhere (more on virtual machines
+/// and busy hosts). At an `rps` whose interval is close to that granularity, every wait
+/// overshoots and the measured rate falls short of the configured one. Spinning
+/// through the tail of the wait trades CPU time for accuracy; a `threshold` slightly
+/// above the platform's sleep granularity is enough.
+///
+/// Applications on Windows can instead raise the system timer resolution with
+/// `timeBeginPeriod(1)`, which this crate does not do on its own since it affects the
+/// whole process.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpinClock {
+    threshold: Duration,
+}
+
+impl SpinClock {
+    pub fn new(threshold: Duration) -> Self {
+        SpinClock { threshold }
+    }
+}
+
+impl Clock for SpinClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        if duration > self.threshold {
+            std::thread::sleep(duration - self.threshold);
+        }
+        while Instant::now() < deadline {
+            std::thread::yield_now();
+        }
+    }
+}
+
+/// Which callers share a rate limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThrottleScope {
+    /// One limit shared by every thread using the client
+    #[default]
+    Global,
+    /// Each thread gets its own `rps` quota
+    ///
+    /// Each thread's timestamp lives in that thread and is dropped when it exits.
+    /// The concurrency limit stays global: a blocking thread has at most one request
+    /// in flight anyway.
+    PerThread,
+}
+
+/// What a request does when the rate limit does not allow it to be sent yet
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WhenThrottled {
+    /// Sleep until the request may be sent
+    #[default]
+    Block,
+    /// Fail immediately with `RpcError::RateLimited`
+    Reject,
+}
+
+/// How many requests a batch is charged as against the rate limit
+///
+/// Calls queued for a batch never wait on the rate limiter; a batch passes it once,
+/// when it is dispatched, and is charged according to this setting.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BatchCost {
+    /// One request per call in the batch: the batch waits for a single slot and the
+    /// requests after it are held back for the remaining intervals, so batching does
+    /// not raise the number of calls per second beyond `rps`
+    #[default]
+    PerCall,
+    /// One request per batch, for servers that count HTTP requests rather than calls
+    PerRequest,
+}
+
+/// Enforces a minimum interval of `1/rps` between consecutive requests.
+///
+/// An `rps` of `0` disables the limit, and so does one of a billion or more, whose
+/// interval would be shorter than a nanosecond.
+pub struct RateLimiter {
+    clock: Arc<dyn Clock>,
+    interval: Option<Duration>,
+    last_req: Mutex<Instant>,
+    per_thread: Option<Arc<ThreadTimestamps>>,
+    /// Number of live [`Unthrottled`] guards; the limit is off while non-zero
+    suspended: AtomicUsize,
+}
+
+/// Minimum spacing of requests at `rps`, or `None` if that is no limit at all
+fn interval(rps: usize) -> Option<Duration> {
+    const NANOS_PER_SEC: u64 = 1_000_000_000;
+    match u64::try_from(rps).unwrap_or(u64::MAX) {
+        0 => None,
+        rps if rps >= NANOS_PER_SEC => None,
+        rps => Some(Duration::from_nanos(NANOS_PER_SEC / rps)),
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rps: usize) -> Self {
+        Self::with_scope(rps, ThrottleScope::Global)
+    }
+
+    pub fn with_scope(rps: usize, scope: ThrottleScope) -> Self {
+        Self::with_clock(rps, scope, Arc::new(InstantClock))
+    }
+
+    /// Rate limiter reading and waiting out time on `clock`
+    pub fn with_clock(rps: usize, scope: ThrottleScope, clock: Arc<dyn Clock>) -> Self {
+        RateLimiter {
+            interval: interval(rps),
+            last_req: Mutex::new(clock.now()),
+            clock,
+            per_thread: (scope == ThrottleScope::PerThread).then(Default::default),
+            suspended: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sleep until the next request may be sent.
+    ///
+    /// Returns [`RpcError::Cancelled`] as soon as `cancel` is triggered while waiting.
+    pub fn wait(&self, cancel: Option<&CancelToken>) -> Result<(), RpcError> {
+        self.wait_cost(cancel, 1)
+    }
+
+    /// [`wait`](Self::wait) for a request taking `cost` request slots, which holds
+    /// back the next request by `cost` intervals
+    pub(crate) fn wait_cost(
+        &self,
+        cancel: Option<&CancelToken>,
+        cost: usize,
+    ) -> Result<(), RpcError> {
+        let Some(interval) = self.active_interval() else {
+            return Ok(());
+        };
+        self.with_last_req(|last_req| self.wait_on(last_req, interval, cancel, cost))
+    }
+
+    /// Claim the next request slot if it is already available.
+    ///
+    /// Returns [`RpcError::RateLimited`] with the remaining wait otherwise.
+    pub fn try_acquire(&self) -> Result<(), RpcError> {
+        self.try_acquire_cost(1)
+    }
+
+    /// [`try_acquire`](Self::try_acquire) for a request taking `cost` request slots
+    pub(crate) fn try_acquire_cost(&self, cost: usize) -> Result<(), RpcError> {
+        let Some(interval) = self.active_interval() else {
+            return Ok(());
+        };
+        self.with_last_req(|last_req| {
+            let mut lock = last_req.lock().unwrap_or_else(PoisonError::into_inner);
+            let now = self.clock.now();
+            let deadline = *lock + interval;
+            if now < deadline {
+                return Err(RpcError::RateLimited {
+                    retry_after: deadline - now,
+                });
+            }
+            *lock = charged(now, interval, cost);
+            Ok(())
+        })
+    }
+
+    /// Reserve the next slot for a request taking `cost` request slots without waiting,
+    /// returning how long to wait before sending it
+    pub(crate) fn reserve_cost(&self, cost: usize) -> Duration {
+        let Some(interval) = self.active_interval() else {
+            return Duration::ZERO;
+        };
+        self.with_last_req(|last_req| {
+            let (deadline, _, _) = self.reserve(last_req, interval, cost);
+            deadline.saturating_duration_since(self.clock.now())
+        })
+    }
+
+    /// Forget the timing of past requests, so that the next one in every scope is sent
+    /// without waiting
+    pub fn reset(&self) {
+        let now = self.clock.now();
+        let ready = self
+            .interval
+            .and_then(|interval| now.checked_sub(interval))
+            .unwrap_or(now);
+        *self.last_req.lock().unwrap_or_else(PoisonError::into_inner) = ready;
+        if let Some(per_thread) = &self.per_thread {
+            for last_req in per_thread
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .filter_map(Weak::upgrade)
+            {
+                *last_req.lock().unwrap_or_else(PoisonError::into_inner) = ready;
+            }
+        }
+    }
+
+    /// Turn the limit off until the returned guard is dropped
+    ///
+    /// Guards may overlap; the limit is back once the last one is dropped. Requests
+    /// already waiting when the guard is taken still wait out their turn.
+    pub fn suspend(&self) -> Unthrottled<'_> {
+        self.suspended.fetch_add(1, AtomicOrdering::Relaxed);
+        Unthrottled { limiter: self }
+    }
+
+    /// Interval to enforce now, `None` while there is no limit or it is suspended
+    fn active_interval(&self) -> Option<Duration> {
+        self.interval
+            .filter(|_| self.suspended.load(AtomicOrdering::Relaxed) == 0)
+    }
+
+    /// Run `f` on the timestamp of the last request in the caller's scope
+    fn with_last_req<R>(&self, f: impl FnOnce(&Mutex<Instant>) -> R) -> R {
+        let Some(per_thread) = &self.per_thread else {
+            return f(&self.last_req);
+        };
+        let last_req = LAST_REQ.with(|own| {
+            let mut own = own.borrow_mut();
+            own.retain(|(limiter, _)| limiter.strong_count() > 0);
+            if let Some((_, last_req)) = own
+                .iter()
+                .find(|(limiter, _)| limiter.as_ptr() == Arc::as_ptr(per_thread))
+            {
+                return last_req.clone();
+            }
+            let last_req = Arc::new(Mutex::new(self.clock.now()));
+            let mut threads = per_thread.lock().unwrap_or_else(PoisonError::into_inner);
+            threads.retain(|thread| thread.strong_count() > 0);
+            threads.push(Arc::downgrade(&last_req));
+            own.push((Arc::downgrade(per_thread), last_req.clone()));
+            last_req
+        });
+        f(&last_req)
+    }
+
+    /// Claim the first free slot in `last_req` for a request taking `cost` request slots:
+    /// returns when it may be sent, and the timestamp before and after the claim
+    fn reserve(
+        &self,
+        last_req: &Mutex<Instant>,
+        interval: Duration,
+        cost: usize,
+    ) -> (Instant, Instant, Instant) {
+        let mut lock = last_req.lock().unwrap_or_else(PoisonError::into_inner);
+        let deadline = (*lock + interval).max(self.clock.now());
+        let previous = std::mem::replace(&mut *lock, charged(deadline, interval, cost));
+        (deadline, previous, *lock)
+    }
+
+    fn wait_on(
+        &self,
+        last_req: &Mutex<Instant>,
+        interval: Duration,
+        cancel: Option<&CancelToken>,
+        cost: usize,
+    ) -> Result<(), RpcError> {
+        if cancel.is_some_and(CancelToken::is_cancelled) {
+            return Err(RpcError::Cancelled);
+        }
+        // Reserve the slot and sleep without the lock, so every waiter watches its own
+        // cancel token
+        let (deadline, previous, reserved) = self.reserve(last_req, interval, cost);
+        loop {
+            if cancel.is_some_and(CancelToken::is_cancelled) {
+                // Hand the slot back unless a later request has been queued behind it
+                let mut lock = last_req.lock().unwrap_or_else(PoisonError::into_inner);
+                if *lock == reserved {
+                    *lock = previous;
+                }
+                return Err(RpcError::Cancelled);
+            }
+            let now = self.clock.now();
+            if now >= deadline {
+                return Ok(());
+            }
+            let remaining = deadline - now;
+            self.clock.sleep(if cancel.is_some() {
+                remaining.min(CANCEL_POLL)
+            } else {
+                remaining
+            });
+        }
+    }
+}
+
+/// Guard that keeps a rate limiter off while it lives, from `client.unthrottled_scope()`
+/// or [`Throttle::unthrottled`]
+///
+/// Dropping it, including while unwinding from a panic, turns the limit back on.
+#[must_use = "the rate limit is back on as soon as the guard is dropped"]
+pub struct Unthrottled<'a> {
+    limiter: &'a RateLimiter,
+}
+
+impl Drop for Unthrottled<'_> {
+    fn drop(&mut self) {
+        self.limiter.suspended.fetch_sub(1, AtomicOrdering::Relaxed);
+    }
+}
+
+/// Timestamp to record for a request sent at `now` that takes `cost` request slots:
+/// the slots after the first are counted as sent in the intervals that follow
+fn charged(now: Instant, interval: Duration, cost: usize) -> Instant {
+    let extra = u32::try_from(cost.saturating_sub(1)).unwrap_or(u32::MAX);
+    now + interval.saturating_mul(extra)
+}
+
+/// Time spent waiting on a throttle, summed per slice of the recent
+/// [`SATURATION_WINDOW`]
+struct WaitWindow {
+    started: Instant,
+    /// Per slice: its index counted from `started`, and the waiting recorded in it
+    slices: Mutex<[(u64, Duration); SATURATION_SLICES as usize]>,
+}
+
+impl WaitWindow {
+    fn new(started: Instant) -> Self {
+        WaitWindow {
+            started,
+            slices: Mutex::new([(0, Duration::ZERO); SATURATION_SLICES as usize]),
+        }
+    }
+
+    /// Index of the slice `now` falls in
+    fn slice(&self, now: Instant) -> u64 {
+        let slice = SATURATION_WINDOW / SATURATION_SLICES;
+        (now.saturating_duration_since(self.started).as_nanos() / slice.as_nanos()) as u64
+    }
+
+    /// Record `waited`, ending at `now`
+    fn record(&self, now: Instant, waited: Duration) {
+        let idx = self.slice(now);
+        let mut slices = self.slices.lock().unwrap_or_else(PoisonError::into_inner);
+        let slot = &mut slices[(idx % u64::from(SATURATION_SLICES)) as usize];
+        if slot.0 != idx {
+            *slot = (idx, Duration::ZERO);
+        }
+        slot.1 += waited;
+    }
+
+    /// Waiting recorded over the window ending at `now`, divided by the wall time the
+    /// window spans
+    fn ratio(&self, now: Instant) -> f64 {
+        let idx = self.slice(now);
+        let oldest = idx.saturating_sub(u64::from(SATURATION_SLICES) - 1);
+        let waited: Duration = self
+            .slices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(slice, _)| (oldest..=idx).contains(slice))
+            .map(|(_, waited)| *waited)
+            .sum();
+        let wall = now
+            .saturating_duration_since(self.started)
+            .min(SATURATION_WINDOW);
+        match wall.as_secs_f64() {
+            secs if secs > 0.0 => waited.as_secs_f64() / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Rate limiter and concurrency gate that several clients can share
+///
+/// Build one from a [`ThrottleConfig`] and pass the same `Arc` to every client that
+/// should count against the same limits with `ClientBuilder::shared_throttle`. Code
+/// that sends requests by other means, e.g. from an async runtime, can take part
+/// through [`acquire_async`](Self::acquire_async) or
+/// [`try_acquire`](Self::try_acquire) without blocking its executor.
+pub struct Throttle {
+    rate_limiter: RateLimiter,
+    gate: ConcurrencyGate,
+    waits: WaitWindow,
+}
+
+impl Throttle {
+    /// Limits from `config`'s `rps`, `max_concurrency` and `scope`, waiting on a
+    /// [`SpinClock`] if it sets a `spin_threshold`
+    pub fn new(config: &ThrottleConfig) -> Self {
+        if config.spin_threshold.is_zero() {
+            Self::with_clock(config, Arc::new(InstantClock))
+        } else {
+            Self::with_clock(config, Arc::new(SpinClock::new(config.spin_threshold)))
+        }
+    }
+
+    /// Like [`new`](Self::new), with the rate limiter running on `clock`
+    ///
+    /// Pass the throttle to `ClientBuilder::shared_throttle` to use the clock in a
+    /// client.
+    pub fn with_clock(config: &ThrottleConfig, clock: Arc<dyn Clock>) -> Self {
+        Throttle {
+            waits: WaitWindow::new(clock.now()),
+            rate_limiter: RateLimiter::with_clock(config.rps, config.scope, clock),
+            gate: ConcurrencyGate::new(config.max_concurrency),
+        }
+    }
+
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    pub fn gate(&self) -> &ConcurrencyGate {
+        &self.gate
+    }
+
+    /// Clear the rate limiter's timing state, e.g. after a long pause, so the next
+    /// request is not held back by requests sent before it
+    ///
+    /// Affects every client sharing this throttle. Requests in flight and the
+    /// concurrency gate are left alone.
+    pub fn reset(&self) {
+        self.rate_limiter.reset();
+    }
+
+    /// Send requests without rate limiting until the returned guard is dropped, e.g.
+    /// for a controlled burst during an initial sync
+    ///
+    /// Only the rate limit is lifted: the concurrency limit still applies within the
+    /// scope. Affects every client sharing this throttle.
+    pub fn unthrottled(&self) -> Unthrottled<'_> {
+        self.rate_limiter.suspend()
+    }
+
+    /// Wait for the rate limiter, or fail fast under [`WhenThrottled::Reject`], then
+    /// for a concurrency slot, highest priority first
+    pub fn acquire(
+        &self,
+        options: &CallOptions,
+        mode: WhenThrottled,
+    ) -> Result<Option<GateGuard<'_>>, RpcError> {
+        let clock = &self.rate_limiter.clock;
+        let started = clock.now();
+        let result = self.acquire_untimed(options, mode);
+        let now = clock.now();
+        self.waits
+            .record(now, now.saturating_duration_since(started));
+        result
+    }
+
+    fn acquire_untimed(
+        &self,
+        options: &CallOptions,
+        mode: WhenThrottled,
+    ) -> Result<Option<GateGuard<'_>>, RpcError> {
+        match mode {
+            WhenThrottled::Block => self
+                .rate_limiter
+                .wait_cost(options.cancel.as_ref(), options.cost)?,
+            WhenThrottled::Reject => self.rate_limiter.try_acquire_cost(options.cost)?,
+        }
+        let guard = self
+            .gate
+            .acquire_cancellable(options.priority, options.cancel.as_ref())?;
+        if options.is_cancelled() {
+            return Err(RpcError::Cancelled);
+        }
+        Ok(guard)
+    }
+
+    /// Estimate of how much callers are held back by this throttle: the time spent in
+    /// [`acquire`](Self::acquire) over the last [`SATURATION_WINDOW`], divided by the
+    /// wall time of that window
+    ///
+    /// Waiting on the rate limiter and on the concurrency gate both count, and the
+    /// window advances in tenths of its span. Near `0.0`, raising `rps` or
+    /// `max_concurrency` would not help; concurrent waiters each add their own wait,
+    /// so values above `1.0` mean several callers were blocked at once on average.
+    pub fn saturation(&self) -> f64 {
+        self.waits.ratio(self.rate_limiter.clock.now())
+    }
+
+    /// Wait for a concurrency slot without blocking the executor, then reserve
+    /// `options.cost` request slots from the rate limiter
+    ///
+    /// The future queues for the slot alongside blocking callers, by
+    /// `options.priority` and then in arrival order, and its wait counts towards
+    /// [`saturation`](Self::saturation). It resolves to the slot's guard and the delay
+    /// the rate limit still asks for: sleep for it (e.g. with `tokio::time::sleep`),
+    /// send the request and hold the guard until it completes.
+    ///
+    /// Dropping the future (by `tokio::time::timeout`, `select!` or task abort) gives up
+    /// its place in the queue. `options.cancel_token` and `when_throttled` do not apply.
+    pub fn acquire_async(&self, options: &CallOptions) -> AcquireAsync<'_> {
+        AcquireAsync {
+            throttle: self,
+            priority: options.priority,
+            cost: options.cost,
+            seq: None,
+            started: None,
+        }
+    }
+
+    /// Take a concurrency slot and the next request slot without blocking.
+    ///
+    /// On success the request may be sent; hold the returned guard (if any) until it
+    /// completes. Otherwise nothing is taken and the error is how long to wait before
+    /// trying again, e.g. with `tokio::time::sleep`. This fails while others are queued
+    /// for the gate, so callers polling it can be starved by blocking ones; prefer
+    /// [`acquire_async`](Self::acquire_async), which queues fairly.
+    ///
+    /// An async loop around this is cancellation safe: no waiter is queued between
+    /// attempts, so dropping the future at any `.await` (by `tokio::time::timeout`,
+    /// `select!` or task abort) leaves the limits untouched, and a guard it already
+    /// holds is released as the future is dropped.
+    pub fn try_acquire(&self) -> Result<Option<GateGuard<'_>>, Duration> {
+        let guard = self.gate.try_acquire().ok_or(CANCEL_POLL)?;
+        match self.rate_limiter.try_acquire() {
+            Ok(()) => Ok(guard),
+            Err(RpcError::RateLimited { retry_after }) => Err(retry_after),
+            Err(_) => Err(CANCEL_POLL),
+        }
+    }
+}
+
+/// Future returned by [`Throttle::acquire_async`]
+#[must_use = "futures do nothing unless polled"]
+pub struct AcquireAsync<'a> {
+    throttle: &'a Throttle,
+    priority: i32,
+    cost: usize,
+    /// Place in the gate's queue once first polled
+    seq: Option<u64>,
+    started: Option<Instant>,
+}
+
+/// A concurrency slot and rate limiter turn taken with [`Throttle::acquire_async`]
+pub struct AsyncPermit<'a> {
+    /// Slot to hold until the request completes; `None` without a concurrency limit
+    pub guard: Option<GateGuard<'a>>,
+    /// How long to wait before sending the request, for the rate limit
+    pub delay: Duration,
+}
+
+impl<'a> Future for AcquireAsync<'a> {
+    type Output = AsyncPermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let throttle = this.throttle;
+        let clock = &throttle.rate_limiter.clock;
+        let started = *this.started.get_or_insert_with(|| clock.now());
+        let guard = match throttle
+            .gate
+            .poll_turn(&mut this.seq, this.priority, cx.waker())
+        {
+            Poll::Ready(guard) => guard,
+            Poll::Pending => return Poll::Pending,
+        };
+        let delay = throttle.rate_limiter.reserve_cost(this.cost);
+        let now = clock.now();
+        throttle
+            .waits
+            .record(now, now.saturating_duration_since(started) + delay);
+        Poll::Ready(AsyncPermit { guard, delay })
+    }
+}
+
+impl Drop for AcquireAsync<'_> {
+    fn drop(&mut self) {
+        if let Some(seq) = self.seq.take() {
+            self.throttle.gate.leave(seq);
+        }
+    }
+}
+
+impl fmt::Debug for Throttle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Throttle")
+            .field("max_concurrency", &self.gate.max_concurrency)
+            .field("in_flight", &self.gate.in_flight())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Priority-aware limiter for the number of simultaneous in-flight requests.
+///
+/// Waiters are served highest priority first; waiters with equal priority are
+/// served in arrival order. A `max_concurrency` of `0` disables the limit.
+pub struct ConcurrencyGate {
+    max_concurrency: usize,
+    state: Mutex<GateState>,
+    cvar: Condvar,
+}
+
+struct GateState {
+    in_flight: usize,
+    waiting: BinaryHeap<Waiter>,
+    next_seq: u64,
+    /// Wakers of queued async waiters, by sequence number
+    wakers: HashMap<u64, Waker>,
+}
+
+impl GateState {
+    /// Join the queue at `priority`, returning the new waiter's sequence number
+    fn enqueue(&mut self, priority: i32) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.waiting.push(Waiter { priority, seq });
+        seq
+    }
+}
+
+#[derive(PartialEq, Eq)]
+struct Waiter {
+    priority: i32,
+    seq: u64,
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl ConcurrencyGate {
+    pub fn new(max_concurrency: usize) -> Self {
+        ConcurrencyGate {
+            max_concurrency,
+            state: Mutex::new(GateState {
+                in_flight: 0,
+                waiting: BinaryHeap::new(),
+                next_seq: 0,
+                wakers: HashMap::new(),
+            }),
+            cvar: Condvar::new(),
+        }
+    }
+
+    /// Maximum number of simultaneous requests (`0` = unlimited)
+    pub fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+    }
+
+    /// Number of requests currently holding a slot
+    pub fn in_flight(&self) -> usize {
+        self.lock().in_flight
+    }
+
+    /// Block until a slot is available, then hold it until the guard is dropped.
+    ///
+    /// Returns `None` when concurrency limiting is disabled.
+    pub fn acquire(&self, priority: i32) -> Option<GateGuard<'_>> {
+        self.acquire_cancellable(priority, None)
+            .expect("acquire without a cancel token cannot be cancelled")
+    }
+
+    /// Like [`acquire`](Self::acquire), but gives up with [`RpcError::Cancelled`]
+    /// once `cancel` is triggered, leaving the queue as if it had never waited.
+    pub fn acquire_cancellable(
+        &self,
+        priority: i32,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Option<GateGuard<'_>>, RpcError> {
+        if self.max_concurrency == 0 {
+            return Ok(None);
+        }
+        let mut state = self.lock();
+        let seq = state.enqueue(priority);
+        while !self.take_turn(&mut state, seq) {
+            if cancel.is_some_and(CancelToken::is_cancelled) {
+                state.waiting.retain(|w| w.seq != seq);
+                self.notify(state);
+                return Err(RpcError::Cancelled);
+            }
+            state = match cancel {
+                Some(_) => {
+                    self.cvar
+                        .wait_timeout(state, CANCEL_POLL)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                None => self
+                    .cvar
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+        self.wake_next(state);
+        Ok(Some(GateGuard { gate: self }))
+    }
+
+    /// Take a slot for the async waiter queued as `seq`, queueing it at `priority`
+    /// first if it has no place yet, or register `waker` to be woken when it may be
+    /// its turn
+    fn poll_turn(
+        &self,
+        seq: &mut Option<u64>,
+        priority: i32,
+        waker: &Waker,
+    ) -> Poll<Option<GateGuard<'_>>> {
+        if self.max_concurrency == 0 {
+            return Poll::Ready(None);
+        }
+        let mut state = self.lock();
+        let queued = *seq.get_or_insert_with(|| state.enqueue(priority));
+        if self.take_turn(&mut state, queued) {
+            *seq = None;
+            self.wake_next(state);
+            return Poll::Ready(Some(GateGuard { gate: self }));
+        }
+        state.wakers.insert(queued, waker.clone());
+        Poll::Pending
+    }
+
+    /// Drop the waiter queued as `seq` without giving it a slot
+    fn leave(&self, seq: u64) {
+        let mut state = self.lock();
+        state.waiting.retain(|w| w.seq != seq);
+        state.wakers.remove(&seq);
+        self.notify(state);
+    }
+
+    /// Give the waiter queued as `seq` a slot if one is free and it is first in line
+    fn take_turn(&self, state: &mut GateState, seq: u64) -> bool {
+        if state.in_flight >= self.max_concurrency
+            || state.waiting.peek().map(|w| w.seq) != Some(seq)
+        {
+            return false;
+        }
+        state.waiting.pop();
+        state.wakers.remove(&seq);
+        state.in_flight += 1;
+        true
+    }
+
+    /// After a slot was taken, let the next waiter check for another free one
+    fn wake_next(&self, state: MutexGuard<'_, GateState>) {
+        if state.in_flight < self.max_concurrency && !state.waiting.is_empty() {
+            self.notify(state);
+        }
+    }
+
+    /// Wake every waiter, blocked or async, to check whether its turn has come
+    fn notify(&self, state: MutexGuard<'_, GateState>) {
+        let wakers: Vec<Waker> = state.wakers.values().cloned().collect();
+        drop(state);
+        self.cvar.notify_all();
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    /// Take a free slot without waiting; `None` if the gate is saturated or others are
+    /// already queued for it
+    pub(crate) fn try_acquire(&self) -> Option<Option<GateGuard<'_>>> {
+        if self.max_concurrency == 0 {
+            return Some(None);
+        }
+        let mut state = self.lock();
+        if state.in_flight >= self.max_concurrency || !state.waiting.is_empty() {
+            return None;
+        }
+        state.in_flight += 1;
+        Some(Some(GateGuard { gate: self }))
+    }
+
+    // The state is only ever mutated in small non-panicking steps, so a poisoned lock
+    // still holds consistent counts; recovering keeps `release` from panicking while a
+    // panicking request unwinds.
+    fn lock(&self) -> MutexGuard<'_, GateState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn release(&self) {
+        let mut state = self.lock();
+        state.in_flight -= 1;
+        self.notify(state);
+    }
+}
+
+/// RAII guard that releases a concurrency slot on drop, including during unwinding
+pub struct GateGuard<'a> {
+    gate: &'a ConcurrencyGate,
+}
+
+impl<'a> Drop for GateGuard<'a> {
+    fn drop(&mut self) {
+        self.gate.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_wait_window() {
+        let start = Instant::now();
+        let window = WaitWindow::new(start);
+        assert_eq!(window.ratio(start), 0.0);
+        window.record(start + Duration::from_secs(1), Duration::from_millis(500));
+        assert_eq!(window.ratio(start + Duration::from_secs(2)), 0.25);
+        window.record(start + Duration::from_secs(5), Duration::from_secs(3));
+        assert_eq!(window.ratio(start + Duration::from_secs(10)), 0.35);
+        // The first wait has left the window, the second has not
+        assert_eq!(window.ratio(start + Duration::from_secs(12)), 0.3);
+        assert_eq!(window.ratio(start + Duration::from_secs(16)), 0.0);
+    }
+
+    #[test]
+    fn test_reset_rate_limiter() {
+        for scope in [ThrottleScope::Global, ThrottleScope::PerThread] {
+            let limiter = RateLimiter::with_scope(1, scope);
+            assert!(matches!(
+                limiter.try_acquire(),
+                Err(RpcError::RateLimited { .. })
+            ));
+            limiter.reset();
+            limiter.try_acquire().unwrap();
+            assert!(matches!(
+                limiter.try_acquire(),
+                Err(RpcError::RateLimited { .. })
+            ));
+            limiter.reset();
+            limiter.try_acquire().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_unlimited_gate_never_blocks() {
+        let gate = ConcurrencyGate::new(0);
+        assert!(gate.acquire(0).is_none());
+        assert_eq!(gate.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_higher_priority_acquires_first() {
+        let gate = Arc::new(ConcurrencyGate::new(1));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let held = gate.acquire(0);
+
+        let mut handles = Vec::new();
+        for priority in [1, 5, 3] {
+            let waiter_gate = gate.clone();
+            let order = order.clone();
+            handles.push(std::thread::spawn(move || {
+                let _guard = waiter_gate.acquire(priority);
+                order.lock().unwrap().push(priority);
+            }));
+            // Make sure each waiter is queued before the next one
+            while gate.state.lock().unwrap().waiting.len() < handles.len() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        drop(held);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), vec![5, 3, 1]);
+        assert_eq!(gate.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_cancelled_waiter_leaves_queue() {
+        let gate = Arc::new(ConcurrencyGate::new(1));
+        let held = gate.acquire(0);
+        let token = CancelToken::new();
+
+        let waiter_gate = gate.clone();
+        let waiter_token = token.clone();
+        let handle = std::thread::spawn(move || {
+            waiter_gate
+                .acquire_cancellable(0, Some(&waiter_token))
+                .map(|guard| guard.is_some())
+        });
+        while gate.state.lock().unwrap().waiting.is_empty() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        token.cancel();
+        assert!(matches!(handle.join().unwrap(), Err(RpcError::Cancelled)));
+        assert!(gate.state.lock().unwrap().waiting.is_empty());
+
+        drop(held);
+        assert!(gate.acquire(0).is_some());
+    }
+
+    #[test]
+    fn test_panic_releases_slot() {
+        let gate = Arc::new(ConcurrencyGate::new(1));
+        let panicking = gate.clone();
+        let res = std::thread::spawn(move || {
+            let _guard = panicking.acquire(0);
+            panic!("request failed");
+        })
+        .join();
+        assert!(res.is_err());
+        assert_eq!(gate.in_flight(), 0);
+        assert!(gate.acquire(0).is_some());
+    }
+
+    #[test]
+    fn test_rate_limiter_cancel() {
+        let limiter = RateLimiter::new(1);
+        let token = CancelToken::new();
+        token.cancel();
+        let start = Instant::now();
+        assert!(matches!(
+            limiter.wait(Some(&token)),
+            Err(RpcError::Cancelled)
+        ));
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_rate_limiter_cancel_while_queued() {
+        let limiter = RateLimiter::new(4);
+        limiter.wait(None).unwrap();
+        let token = CancelToken::new();
+        std::thread::scope(|s| {
+            // Four waiters queue up a second of slots ahead of the cancelled call
+            for _ in 0..4 {
+                s.spawn(|| limiter.wait(None).unwrap());
+            }
+            std::thread::sleep(Duration::from_millis(20));
+            let cancelled = s.spawn(|| {
+                let start = Instant::now();
+                let result = limiter.wait(Some(&token));
+                (result, start.elapsed())
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            token.cancel();
+            let (result, waited) = cancelled.join().unwrap();
+            assert!(matches!(result, Err(RpcError::Cancelled)));
+            assert!(waited < Duration::from_millis(300), "{:?}", waited);
+        });
+    }
+
+    #[test]
+    fn test_per_thread_scope() {
+        fn run(scope: ThrottleScope) -> Duration {
+            let limiter = RateLimiter::with_scope(5, scope);
+            let start = Instant::now();
+            std::thread::scope(|s| {
+                for _ in 0..3 {
+                    s.spawn(|| limiter.wait(None).unwrap());
+                }
+            });
+            start.elapsed()
+        }
+
+        // Three threads share one 200ms interval, or each wait out their own
+        assert!(run(ThrottleScope::Global) >= Duration::from_millis(600));
+        assert!(run(ThrottleScope::PerThread) < Duration::from_millis(500));
+
+        // Exited threads take their timestamps with them
+        let limiter = Arc::new(RateLimiter::with_scope(1000, ThrottleScope::PerThread));
+        for _ in 0..3 {
+            let limiter = limiter.clone();
+            std::thread::spawn(move || limiter.wait(None).unwrap())
+                .join()
+                .unwrap();
+        }
+        let threads = limiter.per_thread.as_ref().unwrap();
+        assert_eq!(threads.lock().unwrap().len(), 1);
+        limiter.wait(None).unwrap();
+        assert_eq!(threads.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_try_acquire() {
+        let limiter = RateLimiter::new(10);
+        std::thread::sleep(Duration::from_millis(110));
+        limiter.try_acquire().unwrap();
+        match limiter.try_acquire() {
+            Err(RpcError::RateLimited { retry_after }) => {
+                assert!(retry_after <= Duration::from_millis(100))
+            }
+            other => panic!("expected RateLimited, got {:?}", other),
+        }
+        std::thread::sleep(Duration::from_millis(110));
+        limiter.try_acquire().unwrap();
+
+        RateLimiter::new(0).try_acquire().unwrap();
+    }
+
+    #[test]
+    fn test_throttle_try_acquire() {
+        let throttle = Throttle::new(&ThrottleConfig {
+            max_concurrency: 1,
+            rps: 10,
+            ..ThrottleConfig::default()
+        });
+        std::thread::sleep(Duration::from_millis(110));
+        let guard = throttle.try_acquire().unwrap();
+        assert_eq!(throttle.gate().in_flight(), 1);
+
+        // Saturated gate: nothing is taken from the rate limiter
+        assert!(throttle.try_acquire().is_err());
+        drop(guard);
+        let retry_after = throttle.try_acquire().err().unwrap();
+        assert!(retry_after <= Duration::from_millis(100));
+        assert_eq!(throttle.gate().in_flight(), 0);
+
+        std::thread::sleep(retry_after);
+        assert!(throttle.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn test_try_acquire_cancelled_async() {
+        async fn acquire(throttle: &Throttle) -> Option<GateGuard<'_>> {
+            loop {
+                match throttle.try_acquire() {
+                    Ok(guard) => return guard,
+                    Err(wait) => tokio::time::sleep(wait).await,
+                }
+            }
+        }
+
+        let throttle = Throttle::new(&ThrottleConfig {
+            max_concurrency: 1,
+            ..ThrottleConfig::default()
+        });
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let held = acquire(&throttle).await;
+            // A waiter cancelled before it gets a slot leaves nothing behind
+            let waiter = tokio::time::timeout(Duration::from_secs(1), acquire(&throttle));
+            assert!(waiter.await.is_err());
+            assert_eq!(throttle.gate().in_flight(), 1);
+            drop(held);
+
+            // A call cancelled while holding its slot releases it
+            let call = async {
+                let _guard = acquire(&throttle).await;
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            };
+            assert!(tokio::time::timeout(Duration::from_secs(1), call)
+                .await
+                .is_err());
+            assert_eq!(throttle.gate().in_flight(), 0);
+            assert!(acquire(&throttle).await.is_some());
+        });
+    }
+
+    #[test]
+    fn test_acquire_async_queues_with_blocking_callers() {
+        let throttle = Throttle::new(&ThrottleConfig {
+            max_concurrency: 1,
+            rps: 0,
+            ..ThrottleConfig::default()
+        });
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let order = Mutex::new(Vec::new());
+        let held = throttle.gate().acquire(0);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _guard = throttle.gate().acquire(0);
+                order.lock().unwrap().push("blocking");
+            });
+            while throttle.gate.lock().waiting.is_empty() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            runtime.block_on(async {
+                // A dropped future leaves the queue
+                let dropped = throttle.acquire_async(&CallOptions::new().priority(9));
+                assert!(tokio::time::timeout(Duration::from_millis(20), dropped)
+                    .await
+                    .is_err());
+                assert_eq!(throttle.gate.lock().waiting.len(), 1);
+
+                // Queued behind the held slot, ahead of the lower-priority blocking waiter
+                let mut acquire = throttle.acquire_async(&CallOptions::new().priority(5));
+                assert!(
+                    tokio::time::timeout(Duration::from_millis(20), &mut acquire)
+                        .await
+                        .is_err()
+                );
+                assert_eq!(throttle.gate.lock().waiting.len(), 2);
+                drop(held);
+                let permit = acquire.await;
+                assert!(permit.guard.is_some());
+                assert_eq!(permit.delay, Duration::ZERO);
+                order.lock().unwrap().push("async");
+            });
+        });
+        assert_eq!(*order.lock().unwrap(), ["async", "blocking"]);
+        assert!(throttle.saturation() > 0.0);
+    }
+
+    #[test]
+    fn test_acquire_async_cost() {
+        let throttle = Throttle::new(&ThrottleConfig {
+            rps: 10,
+            ..ThrottleConfig::default()
+        });
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let first = throttle.acquire_async(&CallOptions::new().cost(3)).await;
+            assert!(first.delay <= Duration::from_millis(100));
+            // The three slots of the first request hold the next one back
+            let second = throttle.acquire_async(&CallOptions::new()).await;
+            assert!(
+                second.delay >= Duration::from_millis(350),
+                "{:?}",
+                second.delay
+            );
+        });
+    }
+
+    #[test]
+    fn test_extreme_limits() {
+        assert_eq!(interval(0), None);
+        assert_eq!(interval(1), Some(Duration::from_secs(1)));
+        assert_eq!(interval(3), Some(Duration::from_nanos(333_333_333)));
+        assert_eq!(interval(999_999_999), Some(Duration::from_nanos(1)));
+        assert_eq!(interval(1_000_000_000), None);
+        // Would truncate to 0 as a u32 and divide by zero
+        assert_eq!(interval(1 << 32), None);
+        assert_eq!(interval(usize::MAX), None);
+
+        let throttle = Throttle::new(&ThrottleConfig {
+            max_concurrency: usize::MAX,
+            rps: usize::MAX,
+            ..ThrottleConfig::default()
+        });
+        let start = Instant::now();
+        let guards: Vec<_> = (0..1000)
+            .map(|_| {
+                throttle
+                    .acquire(&CallOptions::default(), WhenThrottled::Reject)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(guards.len(), 1000);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    /// Clock whose time only moves when slept on
+    struct MockClock {
+        start: Instant,
+        elapsed: Mutex<Duration>,
+        sleeps: Mutex<Vec<Duration>>,
+    }
+
+    impl MockClock {
+        fn new() -> Arc<Self> {
+            Arc::new(MockClock {
+                start: Instant::now(),
+                elapsed: Mutex::new(Duration::ZERO),
+                sleeps: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.elapsed.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.start + *self.elapsed.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.sleeps.lock().unwrap().push(duration);
+            self.advance(duration);
+        }
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::with_clock(4, ThrottleScope::Global, clock.clone());
+
+        limiter.wait(None).unwrap();
+        clock.advance(Duration::from_millis(100));
+        limiter.wait(None).unwrap();
+        clock.advance(Duration::from_millis(300));
+        limiter.wait(None).unwrap();
+        assert_eq!(
+            *clock.sleeps.lock().unwrap(),
+            vec![Duration::from_millis(250), Duration::from_millis(150)]
+        );
+
+        assert!(matches!(
+            limiter.try_acquire(),
+            Err(RpcError::RateLimited { retry_after }) if retry_after == Duration::from_millis(250)
+        ));
+        clock.advance(Duration::from_millis(250));
+        assert!(limiter.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn test_spin_clock() {
+        for threshold in [
+            Duration::ZERO,
+            Duration::from_millis(2),
+            Duration::from_millis(20),
+        ] {
+            let clock = SpinClock::new(threshold);
+            let start = Instant::now();
+            clock.sleep(Duration::from_millis(10));
+            assert!(start.elapsed() >= Duration::from_millis(10));
+        }
+
+        let throttle = Throttle::new(&ThrottleConfig {
+            rps: 200,
+            spin_threshold: Duration::from_millis(5),
+            ..ThrottleConfig::default()
+        });
+        throttle.reset();
+        let start = Instant::now();
+        for _ in 0..10 {
+            drop(
+                throttle
+                    .acquire(&CallOptions::default(), WhenThrottled::Block)
+                    .unwrap(),
+            );
+        }
+        // Nine waits of 5ms, each spun through rather than rounded up to a timer tick
+        assert!(start.elapsed() >= Duration::from_millis(45));
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+}
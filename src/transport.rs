@@ -0,0 +1,1161 @@
+//! HTTP transport shared by generated clients: throttling, auth and failover.
+
+use crate::batching::AutoBatcher;
+use crate::cache::{Lookup, ResponseCache};
+use crate::response::{self, BatchEntry};
+use crate::stream::ResultStream;
+use crate::throttle::{sleep_cancellable, GateGuard, Throttle};
+use crate::{
+    pipe, BatchCost, CallOptions, ClientConfig, ConcurrencyGate, HttpVersion, IdBlock,
+    RequestEvent, RequestParts, RpcError,
+};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{
+    HeaderMap, HeaderValue, IntoHeaderName, ACCEPT, ACCEPT_ENCODING, AUTHORIZATION,
+    CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_NONE_MATCH,
+};
+use reqwest::{Method, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+/// Sends serialized requests on behalf of a generated client
+pub struct HttpTransport {
+    config: ClientConfig,
+    client: Client,
+    /// URL actually requested for each endpoint, after TLS server name overrides
+    targets: Vec<String>,
+    throttle: Arc<Throttle>,
+    /// Per-method concurrency limits, taken before `throttle`
+    method_gates: Vec<(String, ConcurrencyGate)>,
+    /// Headers set at runtime with `set_header`, replacing endpoint headers of the same name
+    live_headers: RwLock<HeaderMap>,
+    /// Bearer tokens fetched by `on_unauthorized`, by endpoint index
+    tokens: Vec<EndpointToken>,
+    /// Held while `on_unauthorized` fetches a token
+    token_refresh: Mutex<()>,
+    active_endpoint: AtomicUsize,
+    next_id: Arc<AtomicU64>,
+    auto_batch: Option<AutoBatcher>,
+    cache: Option<ResponseCache>,
+    #[cfg(feature = "metrics")]
+    metrics: crate::metrics::Metrics,
+}
+
+impl HttpTransport {
+    pub fn new(config: ClientConfig) -> Self {
+        let mut builder = Client::builder();
+        if let Some(timeout) = config.throttle.timeout {
+            builder = builder.timeout(timeout);
+        }
+        builder = builder
+            .tcp_nodelay(config.tcp_nodelay)
+            .tcp_keepalive(config.tcp_keepalive);
+        builder = match config.http_version {
+            HttpVersion::Negotiate => builder,
+            HttpVersion::Http1Only => builder.http1_only(),
+            #[cfg(feature = "http2")]
+            HttpVersion::Http2PriorKnowledge => builder.http2_prior_knowledge(),
+        };
+        for (host, addr) in &config.resolve {
+            builder = builder.resolve(host, *addr);
+        }
+        let mut targets = Vec::with_capacity(config.endpoints.len());
+        for endpoint in &config.endpoints {
+            let (target, resolve) = endpoint.connect_target();
+            if let Some((name, addr)) = resolve {
+                builder = builder.resolve(&name, addr);
+            }
+            targets.push(target);
+        }
+        HttpTransport {
+            client: match &config.http_client {
+                Some(client) => client.clone(),
+                None => builder.build().expect("failed to build HTTP client"),
+            },
+            targets,
+            throttle: config
+                .shared_throttle
+                .clone()
+                .unwrap_or_else(|| Arc::new(Throttle::new(&config.throttle))),
+            method_gates: config
+                .method_concurrency
+                .iter()
+                .map(|(method, max)| (method.clone(), ConcurrencyGate::new(*max)))
+                .collect(),
+            live_headers: RwLock::new(HeaderMap::new()),
+            tokens: config
+                .endpoints
+                .iter()
+                .map(|_| EndpointToken::default())
+                .collect(),
+            token_refresh: Mutex::new(()),
+            active_endpoint: AtomicUsize::new(0),
+            next_id: config
+                .shared_id_counter
+                .clone()
+                .unwrap_or_else(|| Arc::new(AtomicU64::new(config.id_offset))),
+            auto_batch: config
+                .throttle
+                .batch_window
+                .map(|window| AutoBatcher::new(window, config.throttle.max_batch_size)),
+            cache: (!config.cached_methods.is_empty())
+                .then(|| ResponseCache::new(config.cached_methods.iter().cloned().collect())),
+            #[cfg(feature = "metrics")]
+            metrics: Default::default(),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    pub fn gate(&self) -> &ConcurrencyGate {
+        self.throttle.gate()
+    }
+
+    /// Rate limiter and concurrency gate of this client, possibly shared with others
+    pub fn throttle(&self) -> &Arc<Throttle> {
+        &self.throttle
+    }
+
+    /// HTTP client sending this client's requests, possibly shared with others
+    pub fn http_client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Index into `config().endpoints` of the endpoint currently in use
+    pub fn active_endpoint(&self) -> usize {
+        self.active_endpoint.load(Ordering::Relaxed)
+    }
+
+    /// Send `name: value` with every later request to any endpoint, replacing a header
+    /// of the same name set before or configured on the endpoint
+    pub fn set_header(&self, name: impl IntoHeaderName, value: HeaderValue) {
+        self.live_headers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name, value);
+    }
+
+    /// Stop sending a header set with [`set_header`](Self::set_header)
+    pub fn remove_header(&self, name: impl IntoHeaderName) {
+        let mut headers = self
+            .live_headers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let reqwest::header::Entry::Occupied(entry) = headers.entry(name) {
+            entry.remove();
+        }
+    }
+
+    /// Name sent on the wire for `method`
+    pub fn wire_method(&self, method: &'static str) -> std::borrow::Cow<'static, str> {
+        self.config.wire_method(method)
+    }
+
+    /// Parse the response to a single call of `method` (its wire name) into its result,
+    /// taking errors with an accepted code as a `null` result
+    pub fn parse_response<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        body: &str,
+    ) -> Result<T, RpcError> {
+        let result = match self.config.bare_result_methods.iter().any(|m| m == method) {
+            true => response::parse_bare(&self.config, body),
+            false => response::parse_single(&self.config, body),
+        };
+        match result {
+            Err(RpcError::RpcError { error }) if self.config.accepts_error(method, &error) => {
+                T::deserialize(serde_json::Value::Null).map_err(|_| RpcError::RpcError { error })
+            }
+            result => result,
+        }
+    }
+
+    /// With `verify_id`, check that the single response `body` answers request `id`
+    pub fn verify_id(&self, id: &serde_json::Value, body: &str) -> Result<(), RpcError> {
+        if !self.config.verify_id {
+            return Ok(());
+        }
+        response::check_id(&self.config, id, body)
+    }
+
+    /// Request counts, error counts by kind and latency histograms per method, in the
+    /// Prometheus text exposition format
+    #[cfg(feature = "metrics")]
+    pub fn metrics_text(&self) -> String {
+        self.metrics.render()
+    }
+
+    /// Reserve `len` consecutive request ids from this client's sequence
+    pub fn reserve_ids(&self, len: usize) -> IdBlock<'_> {
+        let start = self.next_id.fetch_add(len as u64, Ordering::Relaxed);
+        IdBlock::new(start, len, self.config.id_prefix.as_deref())
+    }
+
+    /// Parse a batch response into `(position, result)` pairs
+    pub fn parse_batch_response<T: DeserializeOwned>(
+        &self,
+        ids: &IdBlock<'_>,
+        body: &str,
+    ) -> Result<Vec<(usize, T)>, RpcError> {
+        response::parse_batch(&self.config, ids, body)
+    }
+
+    /// Parse a batch response into each entry's request position and its own outcome
+    pub fn parse_batch_results<T: DeserializeOwned>(
+        &self,
+        ids: &IdBlock<'_>,
+        body: &str,
+    ) -> Result<Vec<BatchEntry<T>>, RpcError> {
+        response::parse_batch_results(&self.config, ids, body)
+    }
+
+    /// Deserialize an already extracted `result` value
+    pub fn decode_result<T: DeserializeOwned>(
+        &self,
+        value: serde_json::Value,
+    ) -> Result<T, serde_json::Error> {
+        response::from_result_value(&self.config, value)
+    }
+
+    /// Pass a deserialization failure to the `on_deserialize_error` hook; other
+    /// errors are ignored
+    pub fn report_deserialize_error(&self, method: &str, body: &str, error: &RpcError) {
+        if let Some(hook) = &self.config.on_deserialize_error {
+            if matches!(
+                error.untagged(),
+                RpcError::JsonError { .. } | RpcError::CannotDeserialize { .. }
+            ) {
+                hook.call(method, body, error);
+            }
+        }
+    }
+
+    /// Check that the endpoint is reachable and accepts our credentials.
+    ///
+    /// Calls the configured health check method. Any JSON answer counts as healthy,
+    /// including a JSON-RPC error such as "method not found"; transport failures,
+    /// `401`/`403` responses and non-JSON bodies are returned as errors.
+    pub fn health_check(&self, options: &CallOptions) -> Result<(), RpcError> {
+        let body = serde_json::json!({
+            "method": self.config.wire_method(&self.config.health_check_method),
+            "params": [],
+            "id": self.reserve_ids(1).id(0),
+        });
+        if self.config.primary().named_pipe().is_some() {
+            return self.request(options, &body).map(drop);
+        }
+        let (res, _slot) = self.send_in_slot(options, &body)?;
+        if matches!(
+            res.status(),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        ) {
+            return Err(RpcError::HttpError(
+                res.error_for_status()
+                    .expect_err("status is a client error"),
+            ));
+        }
+        read_body(res).map(drop)
+    }
+
+    /// Open up to `connections` pooled connections ahead of real traffic by sending that
+    /// many health checks, as many at once as the concurrency cap allows.
+    ///
+    /// The pings run on at most `max_concurrency` (or [`MAX_WARM_UP_THREADS`] if that
+    /// is lower or unlimited) threads, each sending its share in turn, so no more
+    /// connections than that are opened. Each ping goes through the rate limiter and
+    /// concurrency gate. Returns the first error if any ping failed.
+    pub fn warm_up(&self, connections: usize) -> Result<(), RpcError> {
+        let threads = match self.gate().max_concurrency() {
+            0 => MAX_WARM_UP_THREADS,
+            max => max.min(MAX_WARM_UP_THREADS),
+        };
+        let remaining = AtomicUsize::new(connections);
+        let claim = || {
+            remaining
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok()
+        };
+        std::thread::scope(|s| {
+            let pings: Vec<_> = (0..threads.min(connections))
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut result = Ok(());
+                        while claim() {
+                            result = result.and(self.health_check(&CallOptions::default()));
+                        }
+                        result
+                    })
+                })
+                .collect();
+            pings
+                .into_iter()
+                .map(|ping| ping.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+                .fold(Ok(()), Result::and)
+        })
+    }
+
+    /// Send a single call, queueing it for the next automatic batch when a batch window
+    /// is configured and the call is neither `immediate` nor given its own `id`
+    ///
+    /// `body` must be a complete request object including its `id`. Returns the text of
+    /// the call's own response envelope, which may come from the response cache.
+    pub fn submit<T: Serialize + ?Sized>(
+        &self,
+        options: &CallOptions,
+        method: &str,
+        body: &T,
+    ) -> Result<String, RpcError> {
+        let _method_slot = self.method_slot(options, method)?;
+        let http_get = self.config.http_get_methods.iter().any(|m| m == method);
+        let gzip = self
+            .config
+            .gzip_response_methods
+            .iter()
+            .any(|m| m == method);
+        let options = match (http_get, gzip) {
+            (false, false) => options,
+            _ => &CallOptions {
+                http_get: options.http_get || http_get,
+                gzip_response: options.gzip_response || gzip,
+                ..options.clone()
+            },
+        };
+        let Some(cache) = &self.cache else {
+            return self.submit_uncached(options, method, body);
+        };
+        let request = serde_json::to_value(body).map_err(RpcError::SerializeError)?;
+        let Some(key) = cache.key(method, &request) else {
+            cache.record_hit(false);
+            return self.submit_uncached(options, method, &request);
+        };
+        let id = request.get("id").cloned().unwrap_or_default();
+        let etag = match cache.get(method, &key, &id) {
+            Lookup::Fresh(text) => {
+                cache.record_hit(true);
+                return Ok(text);
+            }
+            Lookup::Stale { etag } => Some(etag),
+            Lookup::Miss => None,
+        };
+        let batched = self.auto_batch.is_some() && !options.sent_alone();
+        if batched && etag.is_none() {
+            cache.record_hit(false);
+            let text = self.submit_uncached(options, method, &request)?;
+            cache.insert(method, key, &text, None);
+            return Ok(text);
+        }
+        // Sent on its own so that the ETag can be sent and read at the HTTP level
+        let mut revalidation = Revalidation {
+            if_none_match: etag,
+            ..Revalidation::default()
+        };
+        let text = self.call_with(options, method, &request, Some(&mut revalidation))?;
+        if revalidation.not_modified {
+            if let Some(text) = cache.revalidated(&key, &id) {
+                cache.record_hit(true);
+                return Ok(text);
+            }
+        }
+        cache.record_hit(false);
+        let text = match revalidation.not_modified {
+            // The entry vanished meanwhile; fetch the response unconditionally
+            true => self.call(options, method, &request)?,
+            false => text,
+        };
+        cache.insert(method, key, &text, revalidation.etag);
+        Ok(text)
+    }
+
+    /// Wait for a slot of `method`'s own concurrency limit, if it has one
+    fn method_slot(
+        &self,
+        options: &CallOptions,
+        method: &str,
+    ) -> Result<Option<GateGuard<'_>>, RpcError> {
+        match self.method_gates.iter().find(|(m, _)| m == method) {
+            Some((_, gate)) => gate.acquire_cancellable(options.priority, options.cancel.as_ref()),
+            None => Ok(None),
+        }
+    }
+
+    fn submit_uncached<T: Serialize + ?Sized>(
+        &self,
+        options: &CallOptions,
+        method: &str,
+        body: &T,
+    ) -> Result<String, RpcError> {
+        let Some(batcher) = self.auto_batch.as_ref().filter(|_| !options.sent_alone()) else {
+            return self.call(options, method, body);
+        };
+        if options.is_cancelled() {
+            return Err(RpcError::Cancelled);
+        }
+        let request = serde_json::to_value(body).map_err(RpcError::SerializeError)?;
+        let id = request.get("id").cloned().unwrap_or_default();
+        batcher.submit(self, id, request)
+    }
+
+    /// Whether the calling thread's last single call was answered from the response
+    /// cache
+    pub fn last_was_cache_hit(&self) -> bool {
+        self.cache.as_ref().is_some_and(ResponseCache::last_was_hit)
+    }
+
+    /// Number of calls queued for the next automatic batch
+    pub fn pending_batch_len(&self) -> usize {
+        self.auto_batch.as_ref().map_or(0, AutoBatcher::pending_len)
+    }
+
+    /// Wire names of the calls queued for the next automatic batch, in queue order
+    pub fn pending_batch_methods(&self) -> Vec<String> {
+        self.auto_batch
+            .as_ref()
+            .map_or_else(Vec::new, AutoBatcher::pending_methods)
+    }
+
+    /// Send the pending automatic batch now, returning once its calls have their
+    /// responses
+    pub fn flush_batch(&self) -> Result<(), RpcError> {
+        match &self.auto_batch {
+            Some(batcher) => batcher.flush(self),
+            None => Ok(()),
+        }
+    }
+
+    /// Send `request` unchanged and parse the response as arbitrary JSON
+    ///
+    /// Reported to the `on_request` hook under the request's `method` member, or
+    /// `"raw"` if it has none.
+    pub fn send_raw(
+        &self,
+        options: &CallOptions,
+        request: &serde_json::Value,
+    ) -> Result<serde_json::Value, RpcError> {
+        let method = match request.get("method") {
+            Some(serde_json::Value::String(method)) => method.as_str(),
+            _ => "raw",
+        };
+        let text = self.call(options, method, request)?;
+        serde_json::from_str(&text).map_err(|source| RpcError::JsonError {
+            source,
+            body: self.config.error_body(&text),
+        })
+    }
+
+    /// POST the batch `request`, an array of request objects, exactly as given and return
+    /// the server's whole response
+    ///
+    /// The batch is charged against the rate limiter as set by
+    /// [`ClientBuilder::batch_cost`](crate::ClientBuilder::batch_cost).
+    pub fn send_raw_batch(
+        &self,
+        options: &CallOptions,
+        request: &serde_json::Value,
+    ) -> Result<serde_json::Value, RpcError> {
+        let Some(calls) = request.as_array() else {
+            return Err(RpcError::InvalidBatch);
+        };
+        let text = self.call(&self.batch_options(options, calls.len()), "batch", request)?;
+        serde_json::from_str(&text).map_err(|source| RpcError::JsonError {
+            source,
+            body: self.config.error_body(&text),
+        })
+    }
+
+    /// Send a single call and stream the elements of its array result as they arrive
+    ///
+    /// Goes straight to the server: no automatic batching, caching, retries or
+    /// compressed responses. The response is checked as by [`read_body`] before
+    /// streaming starts, and the stream holds the call's concurrency slot until dropped.
+    pub fn stream_results<T: DeserializeOwned, B: Serialize + ?Sized>(
+        &self,
+        options: &CallOptions,
+        body: &B,
+    ) -> Result<ResultStream<'_, T>, RpcError> {
+        // The stream reads the body as it arrives, which leaves no room to decompress it
+        let options = CallOptions {
+            gzip_response: false,
+            ..options.clone()
+        };
+        let (res, slot) = self.send_in_slot(&options, body)?;
+        ResultStream::with_slot(streamable(res)?, self.config.hex_numbers, slot)
+    }
+
+    /// Like [`request`](Self::request), retrying failed attempts as configured and
+    /// reporting the request to the `on_request` hook as `method`
+    pub fn call<T: Serialize + ?Sized>(
+        &self,
+        options: &CallOptions,
+        method: &str,
+        body: &T,
+    ) -> Result<String, RpcError> {
+        self.call_with(options, method, body, None)
+    }
+
+    /// [`call`](Self::call) for a batch of request objects, returning the text of the
+    /// batch response
+    ///
+    /// The batch is charged against the rate limiter as set by `ThrottleConfig::batch_cost`.
+    /// With `batch_fallback`, a batch answered with a single object instead of an array
+    /// is resent call by call, and the array of their responses is returned instead.
+    pub fn call_batch<T: Serialize>(
+        &self,
+        options: &CallOptions,
+        requests: &[T],
+    ) -> Result<String, RpcError> {
+        let text = self.call(
+            &self.batch_options(options, requests.len()),
+            "batch",
+            requests,
+        )?;
+        let rejected = self.config.batch_fallback
+            && !requests.is_empty()
+            && serde_json::from_str::<serde_json::Value>(&text).is_ok_and(|v| v.is_object());
+        if !rejected {
+            return Ok(text);
+        }
+        let replies = requests
+            .iter()
+            .map(|request| {
+                let request = serde_json::to_value(request).map_err(RpcError::SerializeError)?;
+                let method = match request.get("method") {
+                    Some(serde_json::Value::String(method)) => method.as_str(),
+                    _ => "batch",
+                };
+                self.call(options, method, &request)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(format!("[{}]", replies.join(",")))
+    }
+
+    /// `options` for a batch of `len` calls, charged against the rate limiter as set by
+    /// `ThrottleConfig::batch_cost`
+    pub fn batch_options(&self, options: &CallOptions, len: usize) -> CallOptions {
+        match self.config.throttle.batch_cost {
+            BatchCost::PerCall => CallOptions {
+                cost: len,
+                ..options.clone()
+            },
+            BatchCost::PerRequest => options.clone(),
+        }
+    }
+
+    /// [`call`](Self::call), making the request conditional on `revalidation`'s
+    /// `If-None-Match` if given
+    fn call_with<T: Serialize + ?Sized>(
+        &self,
+        options: &CallOptions,
+        method: &str,
+        body: &T,
+        mut revalidation: Option<&mut Revalidation>,
+    ) -> Result<String, RpcError> {
+        let started = Instant::now();
+        let mut throttle_wait = Duration::ZERO;
+        let mut attempt = 1;
+        let result = loop {
+            let result = self.request_timed(
+                options,
+                body,
+                &mut throttle_wait,
+                revalidation.as_deref_mut(),
+            );
+            if attempt > self.config.max_retries || !self.should_retry(&result, attempt) {
+                break result;
+            }
+            let doublings = (attempt - 1).min(16);
+            let backoff = self.config.retry_backoff.saturating_mul(1 << doublings);
+            if let Err(e) = sleep_cancellable(backoff, options.cancel.as_ref()) {
+                break Err(e);
+            }
+            attempt += 1;
+        };
+        #[cfg(feature = "metrics")]
+        match &result {
+            Ok(text) => {
+                // Only the error's kind is recorded, which needs no copy of the error
+                let error = has_reply_error(text).then_some(RpcError::RpcError {
+                    error: serde_json::Value::Null,
+                });
+                self.metrics
+                    .record(method, started.elapsed(), error.as_ref())
+            }
+            Err(e) => self.metrics.record(method, started.elapsed(), Some(e)),
+        }
+        if let Some(hook) = &self.config.on_request {
+            let elapsed = started.elapsed();
+            hook.call(&RequestEvent {
+                method,
+                metadata: &options.metadata,
+                elapsed,
+                throttle_wait,
+                network: elapsed.saturating_sub(throttle_wait),
+                error: result.as_ref().err(),
+            });
+        }
+        result
+    }
+
+    /// Whether the outcome of attempt number `attempt` calls for another try.
+    ///
+    /// An error object in a single call's response is judged as `RpcError::RpcError`,
+    /// which only `retry_if` can accept, so the body is only parsed if one is set;
+    /// batch responses are never retried once received.
+    fn should_retry(&self, result: &Result<String, RpcError>, attempt: u32) -> bool {
+        match (result, &self.config.retry_if) {
+            (Err(RpcError::Cancelled), _) => false,
+            (Err(e), predicate) => {
+                e.is_transient() || predicate.as_ref().is_some_and(|p| p.call(e, attempt))
+            }
+            (Ok(_), None) => false,
+            (Ok(text), Some(predicate)) => {
+                reply_error(text).is_some_and(|e| predicate.call(&e, attempt))
+            }
+        }
+    }
+
+    /// Send `body` and return the response text, ready to be parsed as JSON
+    ///
+    /// Goes over the local pipe instead of HTTP when the primary endpoint is a
+    /// named pipe.
+    pub fn request<T: Serialize + ?Sized>(
+        &self,
+        options: &CallOptions,
+        body: &T,
+    ) -> Result<String, RpcError> {
+        self.request_timed(options, body, &mut Duration::default(), None)
+    }
+
+    /// [`request`](Self::request), adding the time spent waiting for the throttle to
+    /// `throttle_wait`, and sending and recording the conditional request state of
+    /// `revalidation` if given.
+    ///
+    /// A `304 Not Modified` answer is returned as an empty text.
+    fn request_timed<T: Serialize + ?Sized>(
+        &self,
+        options: &CallOptions,
+        body: &T,
+        throttle_wait: &mut Duration,
+        revalidation: Option<&mut Revalidation>,
+    ) -> Result<String, RpcError> {
+        if let Some(path) = self.config.primary().named_pipe() {
+            let _guard = self.wait_turn(options, throttle_wait)?;
+            let payload = serde_json::to_vec(body).map_err(RpcError::SerializeError)?;
+            return pipe::request(path, &payload);
+        }
+        // The body is read before the concurrency slot is released
+        let Some(revalidation) = revalidation else {
+            let (res, _slot) = self.send_timed(options, body, throttle_wait, None)?;
+            return read_body(res);
+        };
+        let (res, _slot) = self.send_timed(
+            options,
+            body,
+            throttle_wait,
+            revalidation.if_none_match.as_deref(),
+        )?;
+        revalidation.not_modified = res.status() == StatusCode::NOT_MODIFIED;
+        revalidation.etag = res
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        if revalidation.not_modified {
+            return Ok(String::new());
+        }
+        read_body(res)
+    }
+
+    /// Wait for the rate limiter and a concurrency slot, highest priority first, adding
+    /// the time waited to `waited`
+    fn wait_turn(
+        &self,
+        options: &CallOptions,
+        waited: &mut Duration,
+    ) -> Result<Option<GateGuard<'_>>, RpcError> {
+        let mode = options
+            .when_throttled
+            .unwrap_or(self.config.throttle.when_throttled);
+        let started = Instant::now();
+        let guard = self.throttle.acquire(options, mode);
+        *waited += started.elapsed();
+        guard
+    }
+
+    /// POST `body` once the rate limiter and concurrency gate allow it.
+    ///
+    /// Starts with the endpoint that last succeeded. If it cannot be reached, each
+    /// other endpoint is tried once in order, using that endpoint's own credentials.
+    /// Connections the server closes (with `Connection: close` or while idle) are not
+    /// reused, and a request the connection pool fails to write to a reused connection
+    /// is sent again on a new one; a request that reached the server is never resent
+    /// here, since it may have been executed. Fails with `RpcError::UnsupportedTransport`
+    /// for named pipe endpoints, which have no HTTP response.
+    ///
+    /// The concurrency slot is released once the response headers have arrived; the
+    /// body is read outside it.
+    pub fn send<T: Serialize + ?Sized>(
+        &self,
+        options: &CallOptions,
+        body: &T,
+    ) -> Result<Response, RpcError> {
+        self.send_in_slot(options, body).map(|(res, _)| res)
+    }
+
+    /// [`send`](Self::send), also returning the concurrency slot the request took, to
+    /// be held until the body has been read
+    pub(crate) fn send_in_slot<T: Serialize + ?Sized>(
+        &self,
+        options: &CallOptions,
+        body: &T,
+    ) -> Result<(Response, Option<GateGuard<'_>>), RpcError> {
+        self.send_timed(options, body, &mut Duration::default(), None)
+    }
+
+    /// [`send_in_slot`](Self::send_in_slot), adding the time spent waiting for the
+    /// throttle to `throttle_wait` and sending `If-None-Match: if_none_match` if given
+    fn send_timed<T: Serialize + ?Sized>(
+        &self,
+        options: &CallOptions,
+        body: &T,
+        throttle_wait: &mut Duration,
+        if_none_match: Option<&str>,
+    ) -> Result<(Response, Option<GateGuard<'_>>), RpcError> {
+        if self.config.primary().named_pipe().is_some() {
+            return Err(RpcError::UnsupportedTransport(
+                "HTTP responses are not available over a named pipe",
+            ));
+        }
+        let slot = self.wait_turn(options, throttle_wait)?;
+
+        let (method, query, (payload, compressed)) = match options.http_get {
+            true => (Method::GET, get_query(body)?, (Vec::new(), false)),
+            false => (Method::POST, Vec::new(), self.encode_body(body)?),
+        };
+        let mut request_headers = HeaderMap::new();
+        if let Some(etag) = if_none_match.and_then(|etag| HeaderValue::from_str(etag).ok()) {
+            request_headers.insert(IF_NONE_MATCH, etag);
+        }
+        if options.gzip_response {
+            request_headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        }
+        let endpoints = &self.config.endpoints;
+        let start = self.active_endpoint();
+        let mut last_err = None;
+        for offset in 0..endpoints.len() {
+            let idx = (start + offset) % endpoints.len();
+            let url = with_query(self.target(idx, options.path.as_deref()), &query);
+            let send =
+                || self.send_http(idx, &method, &url, &payload, compressed, &request_headers);
+            let token_generation = self.tokens[idx].generation.load(Ordering::Acquire);
+            let mut result = send();
+            let unauthorized =
+                matches!(&result, Ok(res) if res.status() == StatusCode::UNAUTHORIZED);
+            if unauthorized && self.config.on_unauthorized.is_some() {
+                self.refresh_token(idx, token_generation)?;
+                result = send();
+            }
+            match result {
+                Ok(res) => {
+                    self.active_endpoint.store(idx, Ordering::Relaxed);
+                    return Ok((res, slot));
+                }
+                Err(e) if e.is_connect() || e.is_timeout() => last_err = Some(e),
+                Err(e) => return Err(http_error(e)),
+            }
+        }
+        Err(http_error(
+            last_err.expect("client has at least one endpoint"),
+        ))
+    }
+
+    /// Fetch a new bearer token for endpoint `idx` with `on_unauthorized`, unless another
+    /// request already did since its token number `seen` was current
+    fn refresh_token(&self, idx: usize, seen: u64) -> Result<(), RpcError> {
+        let Some(refresh) = &self.config.on_unauthorized else {
+            return Ok(());
+        };
+        let _refreshing = self
+            .token_refresh
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let token = &self.tokens[idx];
+        if token.generation.load(Ordering::Acquire) != seen {
+            return Ok(());
+        }
+        let mut value = HeaderValue::try_from(format!("Bearer {}", refresh.call()?))
+            .map_err(RpcError::InvalidHeader)?;
+        value.set_sensitive(true);
+        *token.value.write().unwrap_or_else(PoisonError::into_inner) = Some(value);
+        token.generation.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+
+    /// URL of endpoint `idx`, with `path` appended to its path if given
+    fn target(&self, idx: usize, path: Option<&str>) -> Cow<'_, str> {
+        let base = &self.targets[idx];
+        let Some(path) = path else {
+            return Cow::Borrowed(base);
+        };
+        let Ok(mut url) = Url::parse(base) else {
+            return Cow::Owned(format!("{}{}", base, path));
+        };
+        let joined = format!(
+            "{}/{}",
+            url.path().trim_end_matches('/'),
+            path.trim_start_matches('/')
+        );
+        url.set_path(&joined);
+        Cow::Owned(url.into())
+    }
+
+    /// Send an encoded payload to `url` of endpoint `idx`, as the body of a POST or,
+    /// for a GET, not at all, adding `request_headers` specific to this request
+    fn send_http(
+        &self,
+        idx: usize,
+        method: &Method,
+        url: &str,
+        payload: &[u8],
+        compressed: bool,
+        request_headers: &HeaderMap,
+    ) -> reqwest::Result<Response> {
+        let mut builder = self.config.endpoints[idx]
+            .apply(self.client.request(method.clone(), url))
+            .header(ACCEPT, self.config.accept.as_str());
+        let live_headers = self
+            .live_headers
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        builder = builder.headers(live_headers);
+        let token = self.tokens[idx]
+            .value
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        if let Some(token) = token {
+            builder = builder.headers(HeaderMap::from_iter([(AUTHORIZATION, token)]));
+        }
+        if *method == Method::POST {
+            builder = builder.header(CONTENT_TYPE, self.config.content_type.as_str());
+        }
+        if compressed {
+            builder = builder.header(CONTENT_ENCODING, "gzip");
+        }
+        builder = builder.headers(request_headers.clone());
+        if let Some(signer) = &self.config.auth_signer {
+            let mut parts = RequestParts {
+                url,
+                body: payload,
+                headers: HeaderMap::new(),
+            };
+            signer.sign(&mut parts);
+            builder = builder.headers(parts.headers);
+        }
+        builder.body(payload.to_vec()).send()
+    }
+
+    /// Serialize `body`, gzipping it when compression is enabled and it is large enough.
+    /// Returns the payload and whether it was compressed.
+    fn encode_body<T: Serialize + ?Sized>(&self, body: &T) -> Result<(Vec<u8>, bool), RpcError> {
+        let json = serde_json::to_vec(body).map_err(RpcError::SerializeError)?;
+        if !self.config.compress_requests || json.len() < self.config.compression_threshold {
+            return Ok((json, false));
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(&json)
+            .expect("writing to a Vec cannot fail");
+        let gzipped = encoder.finish().expect("writing to a Vec cannot fail");
+        Ok((gzipped, true))
+    }
+}
+
+/// Query parameters carrying the request object `body` in a GET request: `jsonrpc` and
+/// `method` as plain strings, `params` and `id` as JSON text
+fn get_query<T: Serialize + ?Sized>(body: &T) -> Result<Vec<(&'static str, String)>, RpcError> {
+    let serde_json::Value::Object(mut request) =
+        serde_json::to_value(body).map_err(RpcError::SerializeError)?
+    else {
+        return Err(RpcError::UnsupportedTransport(
+            "only single calls can be sent with GET",
+        ));
+    };
+    let mut query = Vec::new();
+    for (name, as_json) in [
+        ("jsonrpc", false),
+        ("method", false),
+        ("params", true),
+        ("id", true),
+    ] {
+        let value = match request.remove(name) {
+            None => continue,
+            Some(serde_json::Value::String(text)) if !as_json => text,
+            Some(value) => value.to_string(),
+        };
+        query.push((name, value));
+    }
+    Ok(query)
+}
+
+/// `url` with `query` appended to its query string
+fn with_query<'a>(url: Cow<'a, str>, query: &[(&str, String)]) -> Cow<'a, str> {
+    if query.is_empty() {
+        return url;
+    }
+    let Ok(mut parsed) = Url::parse(&url) else {
+        return url;
+    };
+    parsed.query_pairs_mut().extend_pairs(query);
+    Cow::Owned(parsed.into())
+}
+
+/// Bearer token fetched by `on_unauthorized` for one endpoint, replacing its own
+/// `Authorization`
+#[derive(Default)]
+struct EndpointToken {
+    /// Number of tokens fetched so far
+    generation: AtomicU64,
+    value: RwLock<Option<HeaderValue>>,
+}
+
+/// Conditional request state of a call to a cached method
+#[derive(Default)]
+struct Revalidation {
+    /// `ETag` of the expired cache entry, sent as `If-None-Match`
+    if_none_match: Option<String>,
+    /// `ETag` of the response
+    etag: Option<String>,
+    /// Whether the server answered `304 Not Modified`
+    not_modified: bool,
+}
+
+/// The `error` member of a single call's response; the rest of the body is skipped
+/// over without being built into a `Value`
+#[derive(Deserialize)]
+struct ErrorMember<E> {
+    #[serde(default = "none")]
+    error: Option<E>,
+}
+
+fn none<E>() -> Option<E> {
+    None
+}
+
+/// The server's error object in a single call's response text, if it has one
+fn reply_error(text: &str) -> Option<RpcError> {
+    let error = serde_json::from_str::<ErrorMember<serde_json::Value>>(text)
+        .ok()?
+        .error?;
+    Some(RpcError::RpcError { error })
+}
+
+/// Whether a single call's response text carries an error object, without keeping it
+#[cfg(feature = "metrics")]
+fn has_reply_error(text: &str) -> bool {
+    serde_json::from_str::<ErrorMember<serde::de::IgnoredAny>>(text)
+        .is_ok_and(|reply| reply.error.is_some())
+}
+
+/// `e` as an `RpcError`, told apart as `RpcError::Tls` if the TLS handshake failed
+fn http_error(e: reqwest::Error) -> RpcError {
+    match is_tls_failure(&e) {
+        true => RpcError::Tls(e),
+        false => RpcError::HttpError(e),
+    }
+}
+
+/// Whether an error of the enabled TLS backend is in `e`'s source chain
+fn is_tls_failure(e: &reqwest::Error) -> bool {
+    fn is_tls(err: &(dyn std::error::Error + 'static)) -> bool {
+        #[cfg(any(feature = "native-tls", feature = "native-tls-vendored"))]
+        if err.is::<native_tls::Error>() {
+            return true;
+        }
+        #[cfg(any(feature = "rustls-tls", feature = "rustls-tls-native-roots"))]
+        if err.is::<rustls::Error>() {
+            return true;
+        }
+        // `io::Error::source` skips the error it wraps, which may itself be an
+        // `io::Error` wrapping the TLS error
+        if let Some(inner) = err
+            .downcast_ref::<std::io::Error>()
+            .and_then(std::io::Error::get_ref)
+        {
+            if is_tls(inner) {
+                return true;
+            }
+        }
+        err.source().is_some_and(is_tls)
+    }
+
+    std::error::Error::source(e).is_some_and(is_tls)
+}
+
+/// Most threads `warm_up` sends its health checks from, and so the most connections
+/// it opens
+pub const MAX_WARM_UP_THREADS: usize = 32;
+
+/// Maximum number of characters of a non-JSON body kept in [`RpcError::NotJson`]
+pub const NOT_JSON_SNIPPET_LEN: usize = 256;
+
+/// Read the response body, rejecting empty bodies and bodies that are clearly not JSON.
+///
+/// A body is rejected when it starts with `<` (an HTML or XML page), or when the
+/// server declares a non-JSON `Content-Type` and the body does not start like a JSON
+/// object or array. Servers that label JSON as `text/plain` keep working.
+///
+/// A response with an error status fails with `RpcError::HttpError` carrying the status
+/// unless its body is a JSON-RPC envelope (or a batch of them), as servers such as
+/// `bitcoind` send their errors with `500 Internal Server Error`.
+pub fn read_body(res: Response) -> Result<String, RpcError> {
+    let status_error = res.error_for_status_ref().err();
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let gzipped = res
+        .headers()
+        .get(CONTENT_ENCODING)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"gzip"));
+    let text = match gzipped {
+        // Requested with `gzip_response`; reqwest only decodes it with its own `gzip`
+        // feature
+        true => {
+            let mut text = String::new();
+            flate2::read::GzDecoder::new(res).read_to_string(&mut text)?;
+            text
+        }
+        false => res.text().map_err(RpcError::HttpError)?,
+    };
+    // Some servers prefix UTF-8 bodies with a byte order mark, which serde_json rejects
+    let text = match text.strip_prefix('\u{feff}') {
+        Some(stripped) => stripped.to_string(),
+        None => text,
+    };
+    let trimmed = text.trim_start();
+    if let Some(e) = status_error {
+        if !is_envelope(trimmed) {
+            return Err(RpcError::HttpError(e));
+        }
+    }
+    if trimmed.is_empty() {
+        return Err(RpcError::EmptyResponse);
+    }
+    if !may_be_json(content_type.as_deref(), trimmed.as_bytes()) {
+        return Err(RpcError::NotJson {
+            content_type,
+            snippet: snippet(trimmed, NOT_JSON_SNIPPET_LEN),
+        });
+    }
+    Ok(text)
+}
+
+/// Check `res` as [`read_body`] would before its body is streamed, reading no more of
+/// a good body than its first buffer
+///
+/// A response with an error status is read in full and fails with the server's error
+/// if it sent an envelope, or with `RpcError::HttpError` otherwise.
+fn streamable(res: Response) -> Result<BufReader<Response>, RpcError> {
+    if let Some(status_error) = res.error_for_status_ref().err() {
+        let text = read_body(res)?;
+        return Err(match serde_json::from_str(&text) {
+            Ok(ErrorMember { error: Some(error) }) => RpcError::RpcError { error },
+            _ => RpcError::HttpError(status_error),
+        });
+    }
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let mut reader = BufReader::new(res);
+    let head = reader.fill_buf()?;
+    if head.is_empty() {
+        return Err(RpcError::EmptyResponse);
+    }
+    let head = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+    let start = match head.iter().position(|b| !b.is_ascii_whitespace()) {
+        Some(pos) => &head[pos..],
+        None => return Ok(reader),
+    };
+    if may_be_json(content_type.as_deref(), start) {
+        return Ok(reader);
+    }
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body)?;
+    let text = String::from_utf8_lossy(&body);
+    let text = text.strip_prefix('\u{feff}').unwrap_or(&text);
+    Err(RpcError::NotJson {
+        content_type,
+        snippet: snippet(text.trim_start(), NOT_JSON_SNIPPET_LEN),
+    })
+}
+
+/// Whether a body starting with `start` (after any whitespace) may be JSON: it is not
+/// an HTML or XML page, and either `content_type` does not rule JSON out or the body
+/// starts like a JSON object or array
+fn may_be_json(content_type: Option<&str>, start: &[u8]) -> bool {
+    let declared_json = content_type.map_or(true, |ct| ct.to_ascii_lowercase().contains("json"));
+    let looks_like_json = start.starts_with(b"{") || start.starts_with(b"[");
+    !start.starts_with(b"<") && (declared_json || looks_like_json)
+}
+
+/// Whether `text` is a response envelope, or an array as batches are answered with
+fn is_envelope(text: &str) -> bool {
+    match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(serde_json::Value::Object(members)) => {
+            members.contains_key("result") || members.contains_key("error")
+        }
+        Ok(serde_json::Value::Array(_)) => true,
+        _ => false,
+    }
+}
+
+fn snippet(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((idx, _)) => format!("{}...", &text[..idx]),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct PanicOnSerialize;
+
+    impl Serialize for PanicOnSerialize {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            panic!("serializer panicked");
+        }
+    }
+
+    #[test]
+    fn test_panic_inside_request_releases_slot() {
+        let mut config = ClientConfig::new("http://127.0.0.1:1");
+        config.throttle.max_concurrency = 1;
+        let transport = HttpTransport::new(config);
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            transport.send(&CallOptions::default(), &PanicOnSerialize)
+        }));
+        assert!(res.is_err());
+        assert_eq!(transport.gate().in_flight(), 0);
+        // Capacity is back: the next request gets a slot and fails normally
+        assert!(matches!(
+            transport.send(&CallOptions::default(), &()),
+            Err(RpcError::HttpError(_))
+        ));
+    }
+}